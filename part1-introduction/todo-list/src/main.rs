@@ -1,15 +1,17 @@
 use std::io;
 
 struct Todo {
-    id: i16,
+    id: u64,
     title: String,
     completed: bool,
     deleted: bool,
 }
 
-fn add_todo(todos: &mut Vec<Todo>, title: &str) {
-    // The size of the vector + 1 makes a decent enough id
-    let new_id = todos.len() as i16 + 1;
+fn add_todo(todos: &mut Vec<Todo>, next_id: &mut u64, title: &str) {
+    // Take the next id and move the counter forward, so an id is
+    // never handed out twice
+    let new_id = *next_id;
+    *next_id += 1;
     todos.push(Todo {
         id: new_id,
         title: title.to_string(),
@@ -18,13 +20,13 @@ fn add_todo(todos: &mut Vec<Todo>, title: &str) {
     });
 }
 
-fn remove_todo(todos: &mut Vec<Todo>, todo_id: i16) {
+fn remove_todo(todos: &mut Vec<Todo>, todo_id: u64) {
     if let Some(todo) = todos.iter_mut().find(|todo| todo.id == todo_id) {
         todo.deleted = true;
     }
 }
 
-fn mark_done(todos: &mut Vec<Todo>, todo_id: i16) {
+fn mark_done(todos: &mut Vec<Todo>, todo_id: u64) {
     if let Some(todo) = todos.iter_mut().find(|todo| todo.id == todo_id) {
         todo.completed = true;
     }
@@ -46,6 +48,7 @@ fn invalid_command(command: &str) {
 
 fn main() {
     let mut todos: Vec<Todo> = Vec::new();
+    let mut next_id: u64 = 1;
     print_todos(&todos);
 
     loop {
@@ -66,11 +69,11 @@ fn main() {
             // If the length is bigger than 1 we look for `add x x x x`, `remove x` or `done x`
             _ => {
                 match command_parts[0] {
-                    "add" => add_todo(&mut todos, &command_parts[1..].join(" ")),
-                    "remove" => if let Ok(num) = command_parts[1].parse::<i16>() {
+                    "add" => add_todo(&mut todos, &mut next_id, &command_parts[1..].join(" ")),
+                    "remove" => if let Ok(num) = command_parts[1].parse::<u64>() {
                         remove_todo(&mut todos, num)
                     },
-                    "done" => if let Ok(num) = command_parts[1].parse::<i16>() {
+                    "done" => if let Ok(num) = command_parts[1].parse::<u64>() {
                         mark_done(&mut todos, num)
                     },
                     _ => invalid_command(&command),
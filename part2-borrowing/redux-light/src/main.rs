@@ -1,9 +1,20 @@
+mod query;
+mod transfer;
+
+use std::fs;
 use std::io;
+use std::mem;
+use std::cell::RefCell;
+use std::collections::{ BTreeMap, BTreeSet, VecDeque };
+use std::rc::{ Rc, Weak };
+use std::time::{ SystemTime, UNIX_EPOCH };
+use query::{ Query, SortKey, Term };
+use transfer::Format;
 
 // Lets us type `Add("Todo item".to_string())` instead of `TodoAction::Add("Todo item".to_string())`
-use TodoAction::{ Add, Remove, Toggle };
+use TodoAction::{ Add, AddTag, ClearCompleted, Edit, Move, Remove, RemoveTag, SetDue, SetPriority, Toggle, ToggleAll };
 // Same with the Action enum and VisibilityFilter, Action::*; would work too, but this way we list what we use
-use Action::{ Todos, Visibility };
+use Action::{ Batch, Sort, TagFilter, Todos, Visibility };
 use VisibilityFilter:: { ShowActive, ShowAll, ShowCompleted };
 
 // Ripping off the canonical Redux todo example we'll add a
@@ -11,7 +22,12 @@ use VisibilityFilter:: { ShowActive, ShowAll, ShowCompleted };
 #[derive(Clone, Debug)]
 struct State {
     todos: Vec<Todo>,
-    visibility_filter: VisibilityFilter
+    visibility_filter: VisibilityFilter,
+    // Only show todos with this tag, if it's set
+    tag_filter: Option<String>,
+    sort_by: SortBy,
+    // The id the next added todo will get, it only ever goes up
+    next_id: TodoId,
 }
 
 // By implementing a struct we are creating something very much like
@@ -22,27 +38,73 @@ impl State {
         State {
             todos: Vec::new(),
             visibility_filter: VisibilityFilter::ShowAll,
+            tag_filter: None,
+            sort_by: SortBy::Created,
+            next_id: 1,
         }
     }
 }
 
-// Same Todo as last time..
+// Ids are never reused, so u64 gives us more than enough of them
+type TodoId = u64;
+
+// Same Todo as last time, with a few optional extras for planning
 #[derive(Clone, Debug)]
 struct Todo {
-    id: i16,
+    id: TodoId,
     title: String,
     completed: bool,
     deleted: bool,
+    // A date like 2016-06-17, written that way they sort in date order as strings
+    due: Option<String>,
+    priority: Option<Priority>,
+    // A set keeps the tags sorted and without duplicates
+    tags: BTreeSet<String>,
+}
+
+// Deriving PartialOrd and Ord orders the variants the way they're listed, Low < High
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Priority {
+    Low,
+    Medium,
+    High,
+}
+
+impl Priority {
+    fn parse(name: &str) -> Option<Priority> {
+        match name {
+            "low" => Some(Priority::Low),
+            "medium" => Some(Priority::Medium),
+            "high" => Some(Priority::High),
+            _ => None,
+        }
+    }
+}
+
+// The orders we can list todos in
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SortBy {
+    // The order of the list, which is the order they were added in until
+    // they are moved around
+    Created,
+    // Soonest first, todos without a due date last
+    Due,
+    // Highest first, todos without a priority last
+    Priority,
+    Title,
 }
 
 // Create a convenient Todo::new(id, title) method
 impl Todo {
-    pub fn new(id: i16, title: String) -> Todo {
+    pub fn new(id: TodoId, title: String) -> Todo {
         Todo {
             id: id,
             title: title,
             completed: false,
             deleted: false,
+            due: None,
+            priority: None,
+            tags: BTreeSet::new(),
         }
     }
 }
@@ -55,6 +117,12 @@ impl Todo {
 enum Action {
     Todos(TodoAction),
     Visibility(VisibilityFilter),
+    Sort(SortBy),
+    // Some(tag) to only show todos with that tag, None to show them all again
+    TagFilter(Option<String>),
+    // Several actions that happen as one, like an import. The store sees one
+    // new state, so it's one step to undo and listeners only hear about it once
+    Batch(Vec<Action>),
 }
 
 // mark_done from the previous example becomes Toggle to align with the Redux example
@@ -62,12 +130,27 @@ enum Action {
 #[derive(Clone, Debug)]
 enum TodoAction {
     Add(String),
-    Toggle(i16),
-    Remove(i16),
+    Toggle(TodoId),
+    Remove(TodoId),
+    // Changes the title but keeps the id and whether it's done
+    Edit(TodoId, String),
+    // Marks every todo as done, or every todo as not done
+    ToggleAll(bool),
+    // Removes every todo that's done
+    ClearCompleted,
+    // A date like 2016-06-17, or None to clear it
+    SetDue(TodoId, Option<String>),
+    SetPriority(TodoId, Option<Priority>),
+    AddTag(TodoId, String),
+    RemoveTag(TodoId, String),
+    // Moves a todo to a new position in the list. Positions count the todos
+    // that aren't deleted, after the moved one has been taken out, so 0 is the
+    // top and anything past the end is the bottom
+    Move(TodoId, usize),
 }
 
 // Our 3 visibility states
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 enum VisibilityFilter {
     ShowActive,
     ShowAll,
@@ -75,24 +158,67 @@ enum VisibilityFilter {
 }
 
 // Helper function for getting a mutable todo from a vector by todo_id
-fn get_mut_todo(todos: &mut Vec<Todo>, todo_id: i16) -> Option<&mut Todo> {
+fn get_mut_todo(todos: &mut Vec<Todo>, todo_id: TodoId) -> Option<&mut Todo> {
     todos.iter_mut().find(|todo|todo.id == todo_id)
 }
 
-// Our main reducer, returns a new State with the results of the child-reducers
-// No combineReducers is implemented here, so it calls the child reducers
-// by function name
-fn reducer(state: &State, action: Action) -> State {
-    // Always return a new state
-    State {
-        todos: todo_reducer(&state.todos, &action),
-        visibility_filter: visibility_reducer(&state.visibility_filter, &action),
-    }
+// A reducer takes the current state and an action and returns the next state.
+// Boxing a closure instead of using a plain fn pointer means it can capture things
+type Reducer<S, A> = Box<dyn FnMut(&S, A) -> S>;
+
+// Listeners are called with the new state after every change
+type Listener<S> = Box<dyn FnMut(&S)>;
+
+// Calls a field reducer from combine_reducers!. Going through a generic function
+// lets Rust work out the types of the closure arguments for us
+fn field_reducer<S, A, T, F: Fn(&S, &A) -> T>(reducer: F, state: &S, action: &A) -> T {
+    reducer(state, action)
+}
+
+// Our take on combineReducers from Redux: builds the reducer for the whole state
+// out of one reducer per field. Each field reducer gets the previous state and
+// the action and returns the new value for its field
+macro_rules! combine_reducers {
+    ($state:ident, $action:ty { $($field:ident: $reducer:expr),+ $(,)* }) => {
+        Box::new(|state: &$state, action: $action| {
+            // Always return a new state
+            $state {
+                $( $field: field_reducer($reducer, state, &action), )+
+            }
+        })
+    };
+}
+
+// Our main reducer, returns a new State with the results of the child-reducers.
+// A Batch goes through them one action at a time, each one starting from the
+// state the one before it left
+fn reducer() -> Reducer<State, Action> {
+    let mut combined = combined_reducer();
+    Box::new(move |state: &State, action: Action| match action {
+        Batch(actions) => actions.into_iter().fold(state.clone(), |state, action| combined(&state, action)),
+        action => combined(state, action),
+    })
+}
+
+fn combined_reducer() -> Reducer<State, Action> {
+    combine_reducers!(State, Action {
+        todos: |state, action| todo_reducer(&state.todos, state.next_id, action),
+        visibility_filter: |state, action| visibility_reducer(&state.visibility_filter, action),
+        tag_filter: |state, action| match *action {
+            TagFilter(ref tag) => tag.clone(),
+            _ => state.tag_filter.clone(),
+        },
+        sort_by: |state, action| match *action {
+            Sort(sort_by) => sort_by,
+            _ => state.sort_by,
+        },
+        next_id: |state, action| next_id_reducer(state.next_id, action),
+    })
 }
 
 // Our todo reducer, takes in state (todo list) and returns a new/cloned version
 // after applying the action (is applicable)
-fn todo_reducer(state: &Vec<Todo>, action: &Action) -> Vec<Todo> {
+fn todo_reducer(state: &Vec<Todo>, next_id: TodoId, action: &Action) -> Vec<Todo> {
     let mut new_state: Vec<Todo> = state.clone();
 
     // First we make sure it's a `Todos` action, otherwise return clone of incoming state
@@ -102,8 +228,7 @@ fn todo_reducer(state: &Vec<Todo>, action: &Action) -> Vec<Todo> {
             // If Add push a new item, and if `Toggle` or `Remove` use our get_mut_todo
             // helper function and then change a property on the todo
             Add(ref title) => {
-                let new_id = new_state.len() as i16 + 1;
-                new_state.push(Todo::new(new_id, title.to_string()))
+                new_state.push(Todo::new(next_id, title.to_string()))
             },
             Toggle(todo_id) => {
                 if let Some(todo) = get_mut_todo(&mut new_state, todo_id) {
@@ -115,6 +240,55 @@ fn todo_reducer(state: &Vec<Todo>, action: &Action) -> Vec<Todo> {
                     todo.deleted = true;
                 }
             },
+            Edit(todo_id, ref title) => {
+                if let Some(todo) = get_mut_todo(&mut new_state, todo_id) {
+                    todo.title = title.to_string();
+                }
+            },
+            ToggleAll(completed) => {
+                for todo in new_state.iter_mut().filter(|todo| !todo.deleted) {
+                    todo.completed = completed;
+                }
+            },
+            ClearCompleted => {
+                for todo in new_state.iter_mut().filter(|todo| todo.completed) {
+                    todo.deleted = true;
+                }
+            },
+            SetDue(todo_id, ref due) => {
+                if let Some(todo) = get_mut_todo(&mut new_state, todo_id) {
+                    todo.due = due.clone();
+                }
+            },
+            SetPriority(todo_id, priority) => {
+                if let Some(todo) = get_mut_todo(&mut new_state, todo_id) {
+                    todo.priority = priority;
+                }
+            },
+            AddTag(todo_id, ref tag) => {
+                if let Some(todo) = get_mut_todo(&mut new_state, todo_id) {
+                    todo.tags.insert(tag.to_string());
+                }
+            },
+            RemoveTag(todo_id, ref tag) => {
+                if let Some(todo) = get_mut_todo(&mut new_state, todo_id) {
+                    todo.tags.remove(tag);
+                }
+            },
+            Move(todo_id, position) => {
+                if let Some(from) = new_state.iter().position(|todo| todo.id == todo_id && !todo.deleted) {
+                    let todo = new_state.remove(from);
+                    // Deleted todos keep their place, so we look for where the
+                    // todo at `position` is in the whole list
+                    let to = new_state.iter()
+                        .enumerate()
+                        .filter(|&(_, todo)| !todo.deleted)
+                        .map(|(index, _)| index)
+                        .nth(position)
+                        .unwrap_or(new_state.len());
+                    new_state.insert(to, todo);
+                }
+            },
         },
         // If it's not a Todos action change nothing
         _ => (),
@@ -122,6 +296,15 @@ fn todo_reducer(state: &Vec<Todo>, action: &Action) -> Vec<Todo> {
     return new_state;
 }
 
+// Hands out the id for the next todo, it only moves forward so an id
+// is never reused, even if todos are removed from the list
+fn next_id_reducer(next_id: TodoId, action: &Action) -> TodoId {
+    match *action {
+        Todos(Add(_)) => next_id + 1,
+        _ => next_id,
+    }
+}
+
 // Very simple reducer since the action will either be a VisibilityFilter, in which
 // case we will return that, otherwise just return the incoming state
 fn visibility_reducer(state: &VisibilityFilter, action: &Action) -> VisibilityFilter {
@@ -131,82 +314,472 @@ fn visibility_reducer(state: &VisibilityFilter, action: &Action) -> VisibilityFi
     }
 }
 
-// Redux store implementation
-struct Store {
-    state: State,
-    listeners: Vec<fn(&State)>,
-    reducer: fn(&State, Action) -> State,
+// How many past states the store keeps for undo unless told otherwise
+const DEFAULT_HISTORY_LIMIT: usize = 50;
+
+// Time travel for the store. The present state lives in the store itself, History
+// only keeps what came before it (`past`, oldest first) and what we've undone
+// (`future`, the next state to redo is last)
+struct History<S> {
+    past: VecDeque<S>,
+    future: Vec<S>,
+    limit: usize,
+}
+
+impl<S> History<S> {
+    fn new(limit: usize) -> History<S> {
+        History {
+            past: VecDeque::new(),
+            future: Vec::new(),
+            limit: limit,
+        }
+    }
+
+    // Changes how many past states we keep, dropping the oldest ones if needed
+    fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        while self.past.len() > self.limit {
+            self.past.pop_front();
+        }
+        self.future.truncate(self.limit);
+    }
+
+    // Called with the state we're leaving behind when a new action comes in.
+    // A new action starts a new timeline so anything we could redo is gone
+    fn record(&mut self, previous: S) {
+        self.future.clear();
+        if self.limit == 0 {
+            return;
+        }
+        self.past.push_back(previous);
+        if self.past.len() > self.limit {
+            self.past.pop_front();
+        }
+    }
+
+    // Swaps the present for the previous state, returns false if there is none
+    fn undo(&mut self, present: &mut S) -> bool {
+        match self.past.pop_back() {
+            Some(previous) => {
+                self.future.push(mem::replace(present, previous));
+                true
+            },
+            None => false,
+        }
+    }
+
+    // The opposite of undo
+    fn redo(&mut self, present: &mut S) -> bool {
+        match self.future.pop() {
+            Some(next) => {
+                self.past.push_back(mem::replace(present, next));
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Jumps to a position in `states()`, undoing or redoing as many times as needed
+    fn jump_to(&mut self, present: &mut S, index: usize) -> bool {
+        if index >= self.past.len() + 1 + self.future.len() {
+            return false;
+        }
+        while self.past.len() > index {
+            self.undo(present);
+        }
+        while self.past.len() < index {
+            self.redo(present);
+        }
+        true
+    }
+
+    // Every state we can travel to, oldest first, with the present at `past.len()`
+    fn states<'a>(&'a self, present: &'a S) -> Vec<&'a S> {
+        let mut states: Vec<&S> = self.past.iter().collect();
+        states.push(present);
+        states.extend(self.future.iter().rev());
+        states
+    }
+}
+
+// The store's listeners, each with an id so its Subscription can find it again
+struct Listeners<S> {
+    next_id: usize,
+    entries: Vec<(usize, Listener<S>)>,
 }
 
-impl Store {
-    // Takes a reducer function, we skip the initial_state and optional arguments
-    // TO keep it simple, State::default() from earlier is our initial_state implementation
-    fn create_store(reducer: fn(&State, Action) -> State) -> Store {
+// Returned by subscribe, the listener stays subscribed for as long as this lives.
+// Call unsubscribe() or just drop it to remove the listener from the store
+#[must_use = "dropping a Subscription unsubscribes the listener right away"]
+struct Subscription {
+    remove: Option<Box<dyn FnMut()>>,
+}
+
+impl Subscription {
+    #[allow(dead_code)]
+    fn unsubscribe(mut self) {
+        self.remove_listener();
+    }
+
+    fn remove_listener(&mut self) {
+        if let Some(mut remove) = self.remove.take() {
+            remove();
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.remove_listener();
+    }
+}
+
+// Redux store implementation, generic over the state and the actions so
+// it isn't tied to our todo list
+struct Store<S, A> {
+    state: S,
+    // Shared with the Subscriptions so they can remove their listener. A listener
+    // must not drop its own Subscription while it's being called
+    listeners: Rc<RefCell<Listeners<S>>>,
+    reducer: Reducer<S, A>,
+    history: History<S>,
+}
+
+impl<S, A> Store<S, A> {
+    // Takes a reducer and the initial state, we skip the optional enhancer argument
+    fn create_store(reducer: Reducer<S, A>, initial_state: S) -> Store<S, A> {
         Store {
-            state: State::default(),
-            listeners: Vec::new(),
+            state: initial_state,
+            listeners: Rc::new(RefCell::new(Listeners { next_id: 0, entries: Vec::new() })),
             reducer: reducer,
+            history: History::new(DEFAULT_HISTORY_LIMIT),
         }
     }
 
     // Pushes a listener that will be called for any state change
-    fn subscribe(&mut self, listener: fn(&State)) {
-        self.listeners.push(listener);
+    fn subscribe(&mut self, listener: Listener<S>) -> Subscription where S: 'static {
+        let mut listeners = self.listeners.borrow_mut();
+        let id = listeners.next_id;
+        listeners.next_id += 1;
+        listeners.entries.push((id, listener));
+
+        // The Subscription only holds a weak reference, so it doesn't keep
+        // the listeners alive if the store goes away first
+        let weak: Weak<RefCell<Listeners<S>>> = Rc::downgrade(&self.listeners);
+        Subscription {
+            remove: Some(Box::new(move || {
+                if let Some(listeners) = weak.upgrade() {
+                    listeners.borrow_mut().entries.retain(|&(entry_id, _)| entry_id != id);
+                }
+            })),
+        }
+    }
+
+    // Like subscribe, but the listener only gets the part of the state picked out
+    // by the selector, and is only called when that part actually changes
+    fn subscribe_with_selector<T>(&mut self, selector: Box<dyn Fn(&S) -> T>, listener: Box<dyn FnMut(&T)>) -> Subscription
+        where S: 'static, T: PartialEq + 'static {
+        let mut last = selector(&self.state);
+        let mut listener = listener;
+        self.subscribe(Box::new(move |state: &S| {
+            let selected = selector(state);
+            if selected != last {
+                listener(&selected);
+                last = selected;
+            }
+        }))
     }
 
     // Simply returns the state
     #[allow(dead_code)]
-    fn get_state(&self) -> &State {
+    fn get_state(&self) -> &S {
         &self.state
     }
 
     // Called for every new action, calls the reducer to update the state
     // and then calls every listener
-    fn dispatch(&mut self, action: Action) {
-        self.state = (self.reducer)(&self.state, action);
-        for listener in &self.listeners {
+    fn dispatch(&mut self, action: A) {
+        let new_state = (self.reducer)(&self.state, action);
+        let previous = mem::replace(&mut self.state, new_state);
+        self.history.record(previous);
+        self.notify();
+    }
+
+    // How many past states to keep for undo, the default is DEFAULT_HISTORY_LIMIT
+    #[allow(dead_code)]
+    fn set_history_limit(&mut self, limit: usize) {
+        self.history.set_limit(limit);
+    }
+
+    // Goes back to the state before the last action, returns false if there is none
+    fn undo(&mut self) -> bool {
+        let moved = self.history.undo(&mut self.state);
+        if moved { self.notify(); }
+        moved
+    }
+
+    // Reapplies the last undone state, returns false if there is none
+    fn redo(&mut self) -> bool {
+        let moved = self.history.redo(&mut self.state);
+        if moved { self.notify(); }
+        moved
+    }
+
+    // Jumps straight to a position in history(), returns false if it's out of range
+    fn jump_to(&mut self, index: usize) -> bool {
+        let moved = self.history.jump_to(&mut self.state, index);
+        if moved { self.notify(); }
+        moved
+    }
+
+    // Every state we can undo or redo to, oldest first. The current state
+    // is at history_position()
+    fn history(&self) -> Vec<&S> {
+        self.history.states(&self.state)
+    }
+
+    fn history_position(&self) -> usize {
+        self.history.past.len()
+    }
+
+    fn notify(&mut self) {
+        for &mut (_, ref mut listener) in &mut self.listeners.borrow_mut().entries {
             listener(&self.state)
         }
     }
 }
 
-// Very simple function to print a todo
-fn print_todo(todo: &Todo) {
+fn days_in_month(year: u32, month: u32) -> u32 {
+    match month {
+        // Every fourth year is a leap year, except every hundredth, except every four hundredth
+        2 => match (year % 400, year % 100, year % 4) {
+            (0, _, _) => 29,
+            (_, 0, _) => 28,
+            (_, _, 0) => 29,
+            _ => 28,
+        },
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Checks a date like 2016-6-17 and returns it zero padded as 2016-06-17,
+// so comparing two dates as strings compares them as dates
+fn parse_date(date: &str) -> Option<String> {
+    let parts: Vec<u32> = date.split('-').filter_map(|part| part.parse().ok()).collect();
+    if parts.len() != 3 || date.split('-').count() != 3 {
+        return None;
+    }
+    let (year, month, day) = (parts[0], parts[1], parts[2]);
+    if !(1000..=9999).contains(&year) || !(1..=12).contains(&month) || !(1..=days_in_month(year, month)).contains(&day) {
+        return None;
+    }
+    Some(format!("{:04}-{:02}-{:02}", year, month, day))
+}
+
+// Today's date in UTC, worked out from the days since 1970 with
+// Howard Hinnant's civil_from_days algorithm
+fn today() -> String {
+    let days = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs() / (24 * 60 * 60))
+        .unwrap_or(0);
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+fn is_overdue(todo: &Todo, today: &str) -> bool {
+    !todo.completed && todo.due.iter().any(|due| due.as_str() < today)
+}
+
+// Very simple function to print a todo, with whatever extras it has
+fn print_todo(todo: &Todo, today: &str) {
     let done = if todo.completed { "✔" } else { " " };
-    println!("[{}] {} {}", done, todo.id, todo.title);
+    let mut extras = String::new();
+    if let Some(ref due) = todo.due {
+        extras.push_str(&format!("  due {}", due));
+        if is_overdue(todo, today) { extras.push_str(" (overdue)"); }
+    }
+    if let Some(priority) = todo.priority {
+        extras.push_str(&format!("  !{:?}", priority).to_lowercase());
+    }
+    for tag in &todo.tags {
+        extras.push_str(&format!("  #{}", tag));
+    }
+    println!("[{}] {} {}{}", done, todo.id, todo.title, extras);
 }
 
-// Our print_todos function from last time, a bit altered to take State
-// as input instead of a todos list directly
-fn print_todos(state: &State) {
-    let visibility = &state.visibility_filter;
-    println!("\n\nTodo List:\n-------------------");
-    for todo in &state.todos {
-        if !todo.deleted {
-            match *visibility {
-                ShowAll => print_todo(&todo),
-                ShowCompleted => if todo.completed { print_todo(&todo) },
-                ShowActive => if !todo.completed { print_todo(&todo) },
+// The show, tagged and sort commands as one Query, so the list and the find
+// command pick and order todos the same way
+fn state_query(state: &State) -> Query {
+    let mut query = Query::default();
+    query.terms.push(Term::Status(state.visibility_filter.clone()));
+    if let Some(ref tag) = state.tag_filter {
+        query.terms.push(Term::Tag(tag.clone()));
+    }
+    match state.sort_by {
+        SortBy::Created => (),
+        // sort priority lists the highest first
+        SortBy::Priority => query.sort.push(SortKey { by: SortBy::Priority, descending: true }),
+        by => query.sort.push(SortKey { by, descending: false }),
+    }
+    query
+}
+
+// Runs the text after find as a query, see query.rs, and prints what it finds.
+// If it doesn't parse we point at where it went wrong
+fn find(state: &State, text: &str) {
+    match query::parse(text) {
+        Ok(query) => {
+            let today = today();
+            let found = query.select(&state.todos, &today);
+            println!("\nFound {} todos:\n-------------------", found.len());
+            for todo in found {
+                print_todo(todo, &today);
             }
-        }
+            println!("-------------------");
+        },
+        Err(e) => {
+            println!("  {}", text);
+            println!("  {}^ {}", " ".repeat(e.column - 1), e);
+        },
+    }
+}
+
+// Our print_todos function from last time, a bit altered to take State
+// as input instead of a todos list directly, and the name of the list it's in
+fn print_todos(name: &str, state: &State) {
+    let today = today();
+    println!("\n\nTodo List: {}\n-------------------", name);
+    for todo in state_query(state).select(&state.todos, &today) {
+        print_todo(todo, &today);
+    }
+    print!("-------------------\nVisibility filter:  {:?}, sorted by {:?}", state.visibility_filter, state.sort_by);
+    match state.tag_filter {
+        Some(ref tag) => println!(", tagged #{}", tag),
+        None => println!(),
     }
-    println!("-------------------\nVisibility filter:  {:?}", visibility);
     print_instructions();
 }
 
 
+// Lists every state in the store's history, marking the one we're at
+fn print_history(store: &Store<State, Action>) {
+    println!("\nHistory:\n-------------------");
+    for (index, state) in store.history().iter().enumerate() {
+        let marker = if index == store.history_position() { ">" } else { " " };
+        let count = state.todos.iter().filter(|todo| !todo.deleted).count();
+        println!("{} {} {} todos, {:?}", marker, index, count, state.visibility_filter);
+    }
+    println!("-------------------");
+}
+
 fn print_instructions() {
-    println!("\nAvailable commands: \nadd [text] - toggle [id] - remove [id]\nshow [all|active|completed]");
+    println!("\nAvailable commands: \nuse [list] - use\nadd [text] - toggle [id] - remove [id] - edit [id] [text]\ntoggle all - clear\ndue [id] [yyyy-mm-dd|none] - priority [id] [high|medium|low|none]\ntag [id] [tag] - untag [id] [tag] - move [id] [position]\nshow [all|active|completed] - tagged [tag|all]\nsort [created|due|priority|title]\nfind [query], like find status:active tag:ops due:<2026-11-01 sort:-priority \"report\"\nexport [json|csv|markdown|todotxt] [file] - import [format] [file] [dry-run]\nundo - redo - history - jump [n]");
+}
+
+// Prints the todos in `format`, or writes them to `file` if there is one
+fn export(state: &State, format: Format, file: Option<&&str>) {
+    let output = transfer::export(&state.todos, format);
+    match file {
+        Some(file) => match fs::write(file, output) {
+            Ok(()) => println!("Exported {} todos to {}", state.todos.iter().filter(|todo| !todo.deleted).count(), file),
+            Err(e) => println!("Couldn't write {}: {}", file, e),
+        },
+        None => print!("{}", output),
+    }
+}
+
+// Reads todos from `file` and adds the ones the list doesn't have. All of it
+// is dispatched as one Batch, so one undo takes the whole import back. A dry
+// run prints what would happen and leaves the list alone
+fn import(store: &mut Store<State, Action>, format: Format, file: &str, dry_run: bool) {
+    let text = match fs::read_to_string(file) {
+        Ok(text) => text,
+        Err(e) => return println!("Couldn't read {}: {}", file, e),
+    };
+    let entries = match transfer::parse(&text, format) {
+        Ok(entries) => entries,
+        Err(e) => return println!("Couldn't import {}, {}", file, e),
+    };
+    let plan = transfer::plan(store.get_state(), entries);
+    if dry_run {
+        return plan.print();
+    }
+    let actions = plan.actions(store.get_state().next_id);
+    if actions.is_empty() {
+        println!("Nothing to import, the list already has everything in {}", file);
+    } else {
+        store.dispatch( Batch(actions) );
+    }
 }
 
 fn invalid_command(command: &str) {
     println!("Invalid command: {}", command);
 }
 
+// The list we start out in
+const DEFAULT_LIST: &str = "todos";
+
+// Every list has a store of its own, so each has its own todos, filters and
+// undo history. The subscriptions live as long as the list, dropping them
+// would unsubscribe
+struct TodoList {
+    store: Store<State, Action>,
+    _print_subscription: Subscription,
+    _filter_subscription: Subscription,
+}
+
+impl TodoList {
+    // Creates the store and subscribes with print_todos so every update is printed
+    fn new(name: &str) -> TodoList {
+        let mut store = Store::create_store(reducer(), State::default());
+        let list_name = name.to_string();
+        let print_subscription = store.subscribe(Box::new(move |state: &State| print_todos(&list_name, state)));
+        let filter_subscription = store.subscribe_with_selector(
+            Box::new(|state: &State| state.visibility_filter.clone()),
+            Box::new(|filter: &VisibilityFilter| println!("\nNow showing {:?}", filter)),
+        );
+        TodoList {
+            store,
+            _print_subscription: print_subscription,
+            _filter_subscription: filter_subscription,
+        }
+    }
+}
+
+// use on its own lists the lists, use [list] switches to that list and
+// creates it if there's no list with that name yet
+fn use_list(lists: &mut BTreeMap<String, TodoList>, current: &mut String, name: &str) {
+    if name.is_empty() {
+        println!("\nLists:\n-------------------");
+        for (list_name, list) in lists.iter() {
+            let marker = if list_name == current { ">" } else { " " };
+            let count = list.store.get_state().todos.iter().filter(|todo| !todo.deleted).count();
+            println!("{} {} ({} todos)", marker, list_name, count);
+        }
+        println!("-------------------");
+        return;
+    }
+    if !lists.contains_key(name) {
+        lists.insert(name.to_string(), TodoList::new(name));
+        println!("\nCreated list {}", name);
+    }
+    *current = name.to_string();
+    print_todos(name, lists[name].store.get_state());
+}
+
 fn main() {
-    // Let's create our store and subscribe with print_todos so every update is printed
-    let mut store = Store::create_store(reducer);
-    store.subscribe(print_todos);
+    let mut lists = BTreeMap::new();
+    lists.insert(DEFAULT_LIST.to_string(), TodoList::new(DEFAULT_LIST));
+    let mut current = DEFAULT_LIST.to_string();
 
     print_instructions();
 
@@ -218,6 +791,13 @@ fn main() {
             .expect("failed to read line");
         let command_parts: Vec<&str> = command.split_whitespace().collect();
 
+        // Switching lists is done before we pick the current list's store
+        if command_parts.first() == Some(&"use") {
+            use_list(&mut lists, &mut current, &command_parts[1..].join(" "));
+            continue;
+        }
+        let store = &mut lists.get_mut(&current).expect("the current list always exists").store;
+
         match command_parts.len() {
             0 => invalid_command(&command),
             _ => {
@@ -225,12 +805,90 @@ fn main() {
                     // Since we prepared so well we just need to call dispatch on our store
                     // With the right action
                     "add" => store.dispatch( Todos(Add( command_parts[1..].join(" ").to_string() ))),
-                    "remove" => if let Ok(num) = command_parts[1].parse::<i16>() {
+                    "remove" => if let Ok(num) = command_parts[1].parse::<TodoId>() {
                         store.dispatch( Todos(Remove(num)));
                     },
-                    "toggle" => if let Ok(num) = command_parts[1].parse::<i16>() {
+                    "toggle" => if command_parts.get(1) == Some(&"all") {
+                        // Like the TodoMVC checkbox, everything gets done unless it already is
+                        let all_done = store.get_state().todos.iter()
+                            .filter(|todo| !todo.deleted)
+                            .all(|todo| todo.completed);
+                        store.dispatch( Todos(ToggleAll(!all_done)));
+                    } else if let Ok(num) = command_parts[1].parse::<TodoId>() {
                         store.dispatch( Todos(Toggle(num)));
                     },
+                    "clear" => store.dispatch( Todos(ClearCompleted) ),
+                    "due" => match (command_parts.get(1).and_then(|n| n.parse::<TodoId>().ok()), command_parts.get(2)) {
+                        (Some(num), Some(&"none")) => store.dispatch( Todos(SetDue(num, None))),
+                        (Some(num), Some(date)) => match parse_date(date) {
+                            Some(date) => store.dispatch( Todos(SetDue(num, Some(date)))),
+                            None => println!("Dates look like 2016-06-17"),
+                        },
+                        _ => invalid_command(&command),
+                    },
+                    "priority" => match (command_parts.get(1).and_then(|n| n.parse::<TodoId>().ok()), command_parts.get(2)) {
+                        (Some(num), Some(&"none")) => store.dispatch( Todos(SetPriority(num, None))),
+                        (Some(num), Some(name)) => match Priority::parse(name) {
+                            Some(priority) => store.dispatch( Todos(SetPriority(num, Some(priority)))),
+                            None => invalid_command(&command),
+                        },
+                        _ => invalid_command(&command),
+                    },
+                    "tag" | "untag" => match (command_parts.get(1).and_then(|n| n.parse::<TodoId>().ok()), command_parts.get(2)) {
+                        (Some(num), Some(tag)) => {
+                            // Tags are single words, the # in front is optional
+                            let tag = tag.trim_matches('#').to_lowercase();
+                            if command_parts[0] == "tag" {
+                                store.dispatch( Todos(AddTag(num, tag)));
+                            } else {
+                                store.dispatch( Todos(RemoveTag(num, tag)));
+                            }
+                        },
+                        _ => invalid_command(&command),
+                    },
+                    // Positions start at 1 for the top of the list
+                    "move" => match (command_parts.get(1).and_then(|n| n.parse::<TodoId>().ok()),
+                                     command_parts.get(2).and_then(|n| n.parse::<usize>().ok())) {
+                        (Some(num), Some(position)) if position > 0 => store.dispatch( Todos(Move(num, position - 1))),
+                        _ => invalid_command(&command),
+                    },
+                    "tagged" => match command_parts.get(1) {
+                        Some(&"all") => store.dispatch( TagFilter(None) ),
+                        Some(tag) => store.dispatch( TagFilter(Some(tag.trim_matches('#').to_lowercase())) ),
+                        None => invalid_command(&command),
+                    },
+                    "find" => {
+                        let text = command.trim()["find".len()..].trim();
+                        find(store.get_state(), text);
+                    },
+                    "export" => match command_parts.get(1).and_then(|name| Format::parse(name)) {
+                        Some(format) => export(store.get_state(), format, command_parts.get(2)),
+                        None => invalid_command(&command),
+                    },
+                    "import" => match (command_parts.get(1).and_then(|name| Format::parse(name)), command_parts.get(2)) {
+                        (Some(format), Some(file)) => import(store, format, file, command_parts.get(3) == Some(&"dry-run")),
+                        _ => invalid_command(&command),
+                    },
+                    "sort" => match command_parts.get(1) {
+                        Some(&"created") => store.dispatch( Sort(SortBy::Created) ),
+                        Some(&"due") => store.dispatch( Sort(SortBy::Due) ),
+                        Some(&"priority") => store.dispatch( Sort(SortBy::Priority) ),
+                        Some(&"title") => store.dispatch( Sort(SortBy::Title) ),
+                        _ => invalid_command(&command),
+                    },
+                    "edit" => match command_parts.get(1).and_then(|n| n.parse::<TodoId>().ok()) {
+                        Some(num) if command_parts.len() > 2 => {
+                            store.dispatch( Todos(Edit(num, command_parts[2..].join(" "))));
+                        },
+                        _ => invalid_command(&command),
+                    },
+                    "undo" => if !store.undo() { println!("Nothing to undo") },
+                    "redo" => if !store.redo() { println!("Nothing to redo") },
+                    "history" => print_history(store),
+                    "jump" => match command_parts.get(1).and_then(|n| n.parse::<usize>().ok()) {
+                        Some(n) => if !store.jump_to(n) { println!("No state {} in history", n) },
+                        None => invalid_command(&command),
+                    },
                     "show" => match command_parts[1] {
                         "all" => store.dispatch( Visibility(ShowAll) ),
                         "active" => store.dispatch( Visibility(ShowActive) ),
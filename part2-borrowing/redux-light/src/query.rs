@@ -0,0 +1,395 @@
+use std::cmp::Ordering;
+use std::fmt;
+use { Priority, SortBy, Todo, VisibilityFilter, is_overdue, parse_date };
+
+// A small query language for the find command, like
+//
+//     status:active tag:ops due:<2026-11-01 sort:-priority "weekly report"
+//
+// Terms are separated by spaces and a todo has to match all of them. A term is
+// either field:value or some text the title has to contain, text with spaces
+// or colons goes in double quotes. A - in front of a term turns it around, so
+// -tag:ops is every todo without the ops tag. The fields are
+//
+//     status:all, status:active or status:completed
+//     tag:<tag>
+//     due:<date>, with <, <=, > or >= in front of the date to compare,
+//         due:none for no due date and due:overdue
+//     priority:<low|medium|high>, with the same comparisons, and priority:none
+//     sort:created, sort:due, sort:priority or sort:title, with - in front of
+//         the order to reverse it. Later sorts break ties in the earlier ones
+//
+// part3-web/todo-web has the same language in its query.rs, the two are meant
+// to mirror each other so a fix to one goes in the other too
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Less,
+    LessOrEqual,
+    Equal,
+    GreaterOrEqual,
+    Greater,
+}
+
+impl Comparison {
+    // Splits the comparison off the front of a value, no comparison means Equal
+    fn split(value: &str) -> (Comparison, &str) {
+        for &(prefix, comparison) in &[("<=", Comparison::LessOrEqual), (">=", Comparison::GreaterOrEqual),
+                                       ("<", Comparison::Less), (">", Comparison::Greater), ("=", Comparison::Equal)] {
+            if value.starts_with(prefix) {
+                return (comparison, &value[prefix.len()..]);
+            }
+        }
+        (Comparison::Equal, value)
+    }
+
+    fn holds(&self, ordering: Ordering) -> bool {
+        match *self {
+            Comparison::Less => ordering == Ordering::Less,
+            Comparison::LessOrEqual => ordering != Ordering::Greater,
+            Comparison::Equal => ordering == Ordering::Equal,
+            Comparison::GreaterOrEqual => ordering != Ordering::Less,
+            Comparison::Greater => ordering == Ordering::Greater,
+        }
+    }
+}
+
+// One thing a todo has to match
+#[derive(Clone, Debug, PartialEq)]
+pub enum Term {
+    Status(VisibilityFilter),
+    Tag(String),
+    Due(Comparison, String),
+    NoDue,
+    Overdue,
+    Priority(Comparison, Priority),
+    NoPriority,
+    // Lower case, matched against the lower case title
+    Text(String),
+    Not(Box<Term>),
+}
+
+impl Term {
+    // `today` is only needed for due:overdue
+    pub fn matches(&self, todo: &Todo, today: &str) -> bool {
+        match *self {
+            Term::Status(VisibilityFilter::ShowAll) => true,
+            Term::Status(VisibilityFilter::ShowActive) => !todo.completed,
+            Term::Status(VisibilityFilter::ShowCompleted) => todo.completed,
+            Term::Tag(ref tag) => todo.tags.contains(tag),
+            Term::Due(comparison, ref date) => todo.due.as_ref()
+                .map_or(false, |due| comparison.holds(due.as_str().cmp(date.as_str()))),
+            Term::NoDue => todo.due.is_none(),
+            Term::Overdue => is_overdue(todo, today),
+            Term::Priority(comparison, priority) => todo.priority
+                .map_or(false, |own| comparison.holds(own.cmp(&priority))),
+            Term::NoPriority => todo.priority.is_none(),
+            Term::Text(ref text) => todo.title.to_lowercase().contains(text.as_str()),
+            Term::Not(ref term) => !term.matches(todo, today),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SortKey {
+    pub by: SortBy,
+    pub descending: bool,
+}
+
+impl SortKey {
+    // `index` is where the todo is in the list, which is what Created sorts by.
+    // Todos without a due date or priority always go last, whichever way we sort
+    fn compare(&self, (a_index, a): (usize, &Todo), (b_index, b): (usize, &Todo)) -> Ordering {
+        let ordering = match self.by {
+            SortBy::Created => a_index.cmp(&b_index),
+            SortBy::Due => match (a.due.as_ref(), b.due.as_ref()) {
+                (Some(a_due), Some(b_due)) => a_due.cmp(b_due),
+                (a_due, b_due) => return b_due.is_some().cmp(&a_due.is_some()),
+            },
+            SortBy::Priority => match (a.priority, b.priority) {
+                (Some(a_priority), Some(b_priority)) => a_priority.cmp(&b_priority),
+                (a_priority, b_priority) => return b_priority.is_some().cmp(&a_priority.is_some()),
+            },
+            SortBy::Title => a.title.to_lowercase().cmp(&b.title.to_lowercase()),
+        };
+        if self.descending { ordering.reverse() } else { ordering }
+    }
+}
+
+// A parsed query. The empty query matches every todo that isn't deleted and
+// leaves them in the order they're in
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Query {
+    pub terms: Vec<Term>,
+    pub sort: Vec<SortKey>,
+}
+
+impl Query {
+    pub fn matches(&self, todo: &Todo, today: &str) -> bool {
+        !todo.deleted && self.terms.iter().all(|term| term.matches(todo, today))
+    }
+
+    // The todos the query matches, in the order it asks for
+    pub fn select<'a>(&self, todos: &'a [Todo], today: &str) -> Vec<&'a Todo> {
+        let mut selected: Vec<(usize, &Todo)> = todos.iter()
+            .enumerate()
+            .filter(|&(_, todo)| self.matches(todo, today))
+            .collect();
+        // Sorting is stable, so todos the keys can't tell apart stay in list order
+        selected.sort_by(|&a, &b| {
+            self.sort.iter()
+                .map(|key| key.compare(a, b))
+                .find(|ordering| *ordering != Ordering::Equal)
+                .unwrap_or(Ordering::Equal)
+        });
+        selected.into_iter().map(|(_, todo)| todo).collect()
+    }
+}
+
+// Where the query stopped making sense, columns count characters from 1
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "column {}: {}", self.column, self.message)
+    }
+}
+
+fn error<T>(column: usize, message: String) -> Result<T, ParseError> {
+    Err(ParseError { column: column, message: message })
+}
+
+// A term as it was typed, before we know what it means
+struct Token {
+    column: usize,
+    negated: bool,
+    quoted: bool,
+    text: String,
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, ParseError> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut position = 0;
+    while position < chars.len() {
+        if chars[position].is_whitespace() {
+            position += 1;
+            continue;
+        }
+        let column = position + 1;
+        let negated = chars[position] == '-' && position + 1 < chars.len() && !chars[position + 1].is_whitespace();
+        if negated {
+            position += 1;
+        }
+
+        let quoted = chars[position] == '"';
+        let start = if quoted { position + 1 } else { position };
+        let mut end = start;
+        if quoted {
+            while end < chars.len() && chars[end] != '"' { end += 1; }
+            if end == chars.len() {
+                return error(position + 1, "this quote is never closed".to_string());
+            }
+            position = end + 1;
+        } else {
+            while end < chars.len() && !chars[end].is_whitespace() { end += 1; }
+            position = end;
+        }
+        tokens.push(Token {
+            column: column,
+            negated: negated,
+            quoted: quoted,
+            text: chars[start..end].iter().cloned().collect(),
+        });
+    }
+    Ok(tokens)
+}
+
+fn parse_status(column: usize, value: &str) -> Result<Term, ParseError> {
+    match value {
+        "all" => Ok(Term::Status(VisibilityFilter::ShowAll)),
+        "active" => Ok(Term::Status(VisibilityFilter::ShowActive)),
+        "completed" => Ok(Term::Status(VisibilityFilter::ShowCompleted)),
+        _ => error(column, format!("status is all, active or completed, not `{}`", value)),
+    }
+}
+
+// Tags are stored in lower case without the #, like the tag command does it
+fn parse_tag(column: usize, value: &str) -> Result<Term, ParseError> {
+    let tag = value.trim_matches('#').to_lowercase();
+    if tag.is_empty() {
+        return error(column, "tag: needs a tag after the colon".to_string());
+    }
+    Ok(Term::Tag(tag))
+}
+
+fn parse_due(column: usize, value: &str) -> Result<Term, ParseError> {
+    match Comparison::split(value) {
+        (Comparison::Equal, "none") => Ok(Term::NoDue),
+        (Comparison::Equal, "overdue") => Ok(Term::Overdue),
+        (comparison, date) => match parse_date(date) {
+            Some(date) => Ok(Term::Due(comparison, date)),
+            None => error(column, format!("due needs a date like 2016-06-17, none or overdue, not `{}`", value)),
+        },
+    }
+}
+
+fn parse_priority(column: usize, value: &str) -> Result<Term, ParseError> {
+    match Comparison::split(value) {
+        (Comparison::Equal, "none") => Ok(Term::NoPriority),
+        (comparison, name) => match Priority::parse(&name.to_lowercase()) {
+            Some(priority) => Ok(Term::Priority(comparison, priority)),
+            None => error(column, format!("priority is low, medium, high or none, not `{}`", value)),
+        },
+    }
+}
+
+fn parse_sort(column: usize, value: &str) -> Result<SortKey, ParseError> {
+    let descending = value.starts_with('-');
+    let name = if descending { &value[1..] } else { value };
+    let by = match name {
+        "created" => SortBy::Created,
+        "due" => SortBy::Due,
+        "priority" => SortBy::Priority,
+        "title" => SortBy::Title,
+        _ => return error(column, format!("sort by created, due, priority or title, not `{}`", name)),
+    };
+    Ok(SortKey { by: by, descending: descending })
+}
+
+pub fn parse(input: &str) -> Result<Query, ParseError> {
+    let mut query = Query::default();
+    for token in try!(tokenize(input)) {
+        let column = token.column;
+        let colon = if token.quoted { None } else { token.text.find(':') };
+        let term = match colon {
+            None => Term::Text(token.text.to_lowercase()),
+            Some(colon) => {
+                let (field, value) = (&token.text[..colon], &token.text[colon + 1..]);
+                if value.is_empty() {
+                    return error(column, format!("{}: needs a value after the colon", field));
+                }
+                match field {
+                    "status" => try!(parse_status(column, value)),
+                    "tag" => try!(parse_tag(column, value)),
+                    "due" => try!(parse_due(column, value)),
+                    "priority" => try!(parse_priority(column, value)),
+                    "sort" => {
+                        if token.negated {
+                            return error(column, format!("put the - after the colon to reverse the order, sort:-{}", value));
+                        }
+                        query.sort.push(try!(parse_sort(column, value)));
+                        continue;
+                    },
+                    _ => return error(column, format!(
+                        "there's no `{}` field, try status, tag, due, priority or sort, or put text with a colon in \"quotes\"",
+                        field)),
+                }
+            },
+        };
+        query.terms.push(if token.negated { Term::Not(Box::new(term)) } else { term });
+    }
+    Ok(query)
+}
+
+#[cfg(test)]
+mod tests {
+    use { Priority, SortBy, Todo, VisibilityFilter };
+    use super::{ Comparison, ParseError, Query, SortKey, Term, parse, tokenize };
+
+    fn error_at(input: &str) -> usize {
+        match parse(input) {
+            Err(ParseError { column, .. }) => column,
+            Ok(query) => panic!("{} parsed as {:?}", input, query),
+        }
+    }
+
+    #[test]
+    fn splits_terms_on_whitespace_and_keeps_quoted_text_together() {
+        let tokens = tokenize("  tag:ops   -\"weekly report\" - x").unwrap();
+        let texts: Vec<&str> = tokens.iter().map(|token| token.text.as_str()).collect();
+        assert_eq!(texts, vec!["tag:ops", "weekly report", "-", "x"]);
+        let columns: Vec<usize> = tokens.iter().map(|token| token.column).collect();
+        assert_eq!(columns, vec![3, 13, 30, 32]);
+        // A - on its own is text, not a negation
+        assert!(tokens[1].negated && tokens[1].quoted);
+        assert!(!tokens[2].negated && !tokens[2].quoted);
+    }
+
+    #[test]
+    fn quoted_text_is_never_a_field() {
+        let query = parse("\"Status: Done\" plain").unwrap();
+        assert_eq!(query.terms, vec![Term::Text("status: done".to_string()), Term::Text("plain".to_string())]);
+        assert_eq!(parse("\"\"").unwrap().terms, vec![Term::Text(String::new())]);
+    }
+
+    #[test]
+    fn parses_tags_and_due_dates() {
+        let query = parse("tag:#Ops due:<=2026-11-01 -due:none due:overdue due:2016-6-7").unwrap();
+        assert_eq!(query.terms, vec![
+            Term::Tag("ops".to_string()),
+            Term::Due(Comparison::LessOrEqual, "2026-11-01".to_string()),
+            Term::Not(Box::new(Term::NoDue)),
+            Term::Overdue,
+            Term::Due(Comparison::Equal, "2016-06-07".to_string()),
+        ]);
+        assert_eq!(parse("due:>2016-01-01").unwrap().terms, vec![Term::Due(Comparison::Greater, "2016-01-01".to_string())]);
+    }
+
+    #[test]
+    fn parses_status_priority_and_sorts() {
+        let query = parse("status:active priority:>=Medium priority:none sort:-due sort:title").unwrap();
+        assert_eq!(query.terms, vec![
+            Term::Status(VisibilityFilter::ShowActive),
+            Term::Priority(Comparison::GreaterOrEqual, Priority::Medium),
+            Term::NoPriority,
+        ]);
+        assert_eq!(query.sort, vec![SortKey { by: SortBy::Due, descending: true },
+                                    SortKey { by: SortBy::Title, descending: false }]);
+        assert_eq!(parse("   ").unwrap(), Query::default());
+    }
+
+    #[test]
+    fn reports_the_column_of_malformed_terms() {
+        assert_eq!(error_at("ok \"never closed"), 4);
+        assert_eq!(error_at("ok -\"never closed"), 5);
+        assert_eq!(error_at("tag:"), 1);
+        assert_eq!(error_at("a tag:#"), 3);
+        assert_eq!(error_at("due:tomorrow"), 1);
+        assert_eq!(error_at("due:<2016-02-30"), 1);
+        assert_eq!(error_at("priority:urgent"), 1);
+        assert_eq!(error_at("status:done"), 1);
+        assert_eq!(error_at("x sort:size"), 3);
+        assert_eq!(error_at("-sort:due"), 1);
+        assert_eq!(error_at("a b owner:me"), 5);
+    }
+
+    #[test]
+    fn selects_and_sorts_matching_todos() {
+        let mut todos: Vec<Todo> = ["Write report", "ops: rotate keys", "Buy milk", "Old"].iter().enumerate()
+            .map(|(index, title)| Todo::new(index as u64 + 1, title.to_string()))
+            .collect();
+        todos[0].due = Some("2026-10-20".to_string());
+        todos[0].tags.insert("ops".to_string());
+        todos[1].due = Some("2026-10-01".to_string());
+        todos[1].tags.insert("ops".to_string());
+        todos[1].priority = Some(Priority::High);
+        todos[2].completed = true;
+        todos[3].deleted = true;
+        let ids = |input: &str| -> Vec<u64> {
+            parse(input).unwrap().select(&todos, "2026-10-18").iter().map(|todo| todo.id).collect()
+        };
+
+        assert_eq!(ids(""), vec![1, 2, 3]);
+        assert_eq!(ids("tag:ops sort:due"), vec![2, 1]);
+        assert_eq!(ids("-tag:ops"), vec![3]);
+        assert_eq!(ids("due:overdue"), vec![2]);
+        assert_eq!(ids("due:>=2026-10-02 status:active"), vec![1]);
+        assert_eq!(ids("\"ops:\""), vec![2]);
+        assert_eq!(ids("sort:-priority sort:title"), vec![2, 3, 1]);
+    }
+}
@@ -0,0 +1,999 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::mem;
+use { Action, Priority, State, Todo, TodoId, parse_date };
+use Action::Todos;
+use TodoAction::{ Add, AddTag, SetDue, SetPriority, Toggle };
+
+// Getting todos in and out of a list with the export and import commands.
+// Every format can be exported and imported
+//
+//     json      an array of todos with id, title, completed, due, priority and tags
+//     csv       a header line and then id,title,completed,due,priority,tags
+//     markdown  a GitHub checklist, - [ ] for open todos and - [x] for done ones
+//     todotxt   todo.txt lines, x for done, (A) to (C) for the priority,
+//               due:2016-06-17 for the due date and +tags
+//
+// Importing goes by title. A todo that's already in the list only gets its
+// completed state brought in line with the file, the rest are added
+//
+// part3-web/todo-web has the same formats in its transfer.rs, with iCalendar on
+// top and rustc-serialize for the JSON. Apart from the JSON reader below the
+// two are meant to mirror each other, so a fix to one goes in the other too
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    Csv,
+    Markdown,
+    TodoTxt,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Format> {
+        match name {
+            "json" => Some(Format::Json),
+            "csv" => Some(Format::Csv),
+            "markdown" | "md" => Some(Format::Markdown),
+            "todotxt" | "todo.txt" => Some(Format::TodoTxt),
+            _ => None,
+        }
+    }
+}
+
+// One todo as a file describes it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    // The line it's on, or for JSON which todo in the array it is, from 1
+    pub line: usize,
+    pub title: String,
+    pub completed: bool,
+    pub due: Option<String>,
+    pub priority: Option<Priority>,
+    pub tags: BTreeSet<String>,
+}
+
+impl Entry {
+    fn new(line: usize, title: &str, completed: bool) -> Entry {
+        Entry {
+            line: line,
+            title: title.trim().to_string(),
+            completed: completed,
+            due: None,
+            priority: None,
+            tags: BTreeSet::new(),
+        }
+    }
+}
+
+// Where a file stopped making sense
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+fn error<T>(line: usize, message: String) -> Result<T, ParseError> {
+    Err(ParseError { line: line, message: message })
+}
+
+// Tags are single words, stored in lower case without the #, like the tag command does it
+fn parse_tag(word: &str) -> Option<String> {
+    let tag = word.trim_matches('#').to_lowercase();
+    if tag.is_empty() || tag.contains(char::is_whitespace) { None } else { Some(tag) }
+}
+
+fn priority_name(priority: Option<Priority>) -> &'static str {
+    match priority {
+        Some(Priority::Low) => "low",
+        Some(Priority::Medium) => "medium",
+        Some(Priority::High) => "high",
+        None => "",
+    }
+}
+
+// Titles can't span lines in the line based formats
+fn one_line(title: &str) -> String {
+    title.lines().map(|line| line.trim()).collect::<Vec<_>>().join(" ")
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", field.replace("\"", "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn todo_txt_priority(priority: Priority) -> char {
+    match priority {
+        Priority::High => 'A',
+        Priority::Medium => 'B',
+        Priority::Low => 'C',
+    }
+}
+
+// (A) is high, (B) medium and everything from (C) on is low
+fn from_todo_txt_priority(letter: char) -> Option<Priority> {
+    match letter {
+        'A' => Some(Priority::High),
+        'B' => Some(Priority::Medium),
+        letter if letter >= 'C' && letter <= 'Z' => Some(Priority::Low),
+        _ => None,
+    }
+}
+
+// A JSON string with the quotes around it
+fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn json_todo(todo: &Todo) -> String {
+    let due = todo.due.as_ref().map_or("null".to_string(), |due| json_string(due));
+    let priority = match todo.priority {
+        Some(_) => json_string(priority_name(todo.priority)),
+        None => "null".to_string(),
+    };
+    let tags: Vec<String> = todo.tags.iter().map(|tag| json_string(tag)).collect();
+    format!("{{\"id\": {}, \"title\": {}, \"completed\": {}, \"due\": {}, \"priority\": {}, \"tags\": [{}]}}",
+            todo.id, json_string(&todo.title), todo.completed, due, priority, tags.join(", "))
+}
+
+// Writes the todos out in `format`, deleted todos are left out
+pub fn export(todos: &[Todo], format: Format) -> String {
+    let todos: Vec<&Todo> = todos.iter().filter(|todo| !todo.deleted).collect();
+    let mut output = String::new();
+    match format {
+        Format::Json => {
+            let todos: Vec<String> = todos.iter().map(|todo| format!("  {}", json_todo(todo))).collect();
+            if todos.is_empty() {
+                output.push_str("[]\n");
+            } else {
+                output.push_str(&format!("[\n{}\n]\n", todos.join(",\n")));
+            }
+        },
+        Format::Csv => {
+            output.push_str("id,title,completed,due,priority,tags\r\n");
+            for todo in todos {
+                let tags: Vec<&str> = todo.tags.iter().map(|tag| tag.as_str()).collect();
+                let fields = [
+                    todo.id.to_string(),
+                    csv_field(&todo.title),
+                    todo.completed.to_string(),
+                    todo.due.clone().unwrap_or(String::new()),
+                    priority_name(todo.priority).to_string(),
+                    tags.join(" "),
+                ];
+                output.push_str(&fields.join(","));
+                output.push_str("\r\n");
+            }
+        },
+        Format::Markdown => {
+            for todo in todos {
+                let check = if todo.completed { "x" } else { " " };
+                output.push_str(&format!("- [{}] {}\n", check, one_line(&todo.title)));
+            }
+        },
+        Format::TodoTxt => {
+            for todo in todos {
+                let mut parts = Vec::new();
+                // todo.txt wants done todos to start with x, and the priority
+                // goes in a pri: tag for those
+                match (todo.completed, todo.priority) {
+                    (true, priority) => {
+                        parts.push("x".to_string());
+                        parts.push(one_line(&todo.title));
+                        if let Some(priority) = priority {
+                            parts.push(format!("pri:{}", todo_txt_priority(priority)));
+                        }
+                    },
+                    (false, Some(priority)) => {
+                        parts.push(format!("({})", todo_txt_priority(priority)));
+                        parts.push(one_line(&todo.title));
+                    },
+                    (false, None) => parts.push(one_line(&todo.title)),
+                }
+                if let Some(ref due) = todo.due {
+                    parts.push(format!("due:{}", due));
+                }
+                for tag in &todo.tags {
+                    parts.push(format!("+{}", tag));
+                }
+                output.push_str(&parts.join(" "));
+                output.push('\n');
+            }
+        },
+    }
+    output
+}
+
+// Reads the todos in a file. Lines that aren't todos, like the headings in a
+// Markdown file, are skipped, but a todo we can't make sense of is an error
+pub fn parse(text: &str, format: Format) -> Result<Vec<Entry>, ParseError> {
+    match format {
+        Format::Json => parse_json(text),
+        Format::Csv => parse_csv(text),
+        Format::Markdown => Ok(parse_markdown(text)),
+        Format::TodoTxt => parse_todo_txt(text),
+    }
+}
+
+// We don't have a JSON crate, so here's just enough JSON to read todos with
+#[derive(Clone, Debug, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    // A field of an object, a field that's null counts as missing
+    fn get(&self, name: &str) -> Option<&Json> {
+        match *self {
+            Json::Object(ref fields) => fields.iter()
+                .find(|&&(ref field, ref value)| field == name && *value != Json::Null)
+                .map(|&(_, ref value)| value),
+            _ => None,
+        }
+    }
+}
+
+struct JsonParser {
+    chars: Vec<char>,
+    position: usize,
+    line: usize,
+}
+
+impl JsonParser {
+    fn error<T>(&self, message: &str) -> Result<T, ParseError> {
+        error(self.line, format!("invalid JSON, {}", message))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).cloned()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        if let Some(c) = c {
+            self.position += 1;
+            if c == '\n' { self.line += 1; }
+        }
+        c
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().map_or(false, char::is_whitespace) {
+            self.next();
+        }
+    }
+
+    // Skips the whitespace and takes `expected` if it's next
+    fn take(&mut self, expected: char) -> bool {
+        self.skip_whitespace();
+        if self.peek() == Some(expected) {
+            self.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, ParseError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('{') => self.object(),
+            Some('[') => self.array(),
+            Some('"') => Ok(Json::String(try!(self.string()))),
+            Some('t') => self.word("true", Json::Bool(true)),
+            Some('f') => self.word("false", Json::Bool(false)),
+            Some('n') => self.word("null", Json::Null),
+            Some(c) if c == '-' || c.is_digit(10) => self.number(),
+            Some(c) => self.error(&format!("didn't expect `{}`", c)),
+            None => self.error("it ends too soon"),
+        }
+    }
+
+    fn word(&mut self, word: &str, value: Json) -> Result<Json, ParseError> {
+        for expected in word.chars() {
+            if self.next() != Some(expected) {
+                return self.error(&format!("expected `{}`", word));
+            }
+        }
+        Ok(value)
+    }
+
+    fn number(&mut self) -> Result<Json, ParseError> {
+        let mut number = String::new();
+        while let Some(c) = self.peek() {
+            if !c.is_digit(10) && !"+-.eE".contains(c) {
+                break;
+            }
+            number.push(c);
+            self.next();
+        }
+        match number.parse() {
+            Ok(number) => Ok(Json::Number(number)),
+            Err(_) => self.error(&format!("`{}` is not a number", number)),
+        }
+    }
+
+    fn hex_escape(&mut self) -> Result<u32, ParseError> {
+        let digits: String = (0..4).filter_map(|_| self.next()).collect();
+        match u32::from_str_radix(&digits, 16) {
+            Ok(code) if digits.len() == 4 => Ok(code),
+            _ => self.error("\\u needs four hex digits"),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, ParseError> {
+        self.next();
+        let mut text = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(text),
+                Some('\\') => match self.next() {
+                    Some('n') => text.push('\n'),
+                    Some('r') => text.push('\r'),
+                    Some('t') => text.push('\t'),
+                    Some('b') => text.push('\u{8}'),
+                    Some('f') => text.push('\u{c}'),
+                    Some('u') => {
+                        let mut code = try!(self.hex_escape());
+                        // Characters outside the BMP come as two escapes
+                        if code >= 0xD800 && code < 0xDC00 {
+                            if self.next() != Some('\\') || self.next() != Some('u') {
+                                return self.error("a \\u escape is missing its second half");
+                            }
+                            let low = try!(self.hex_escape());
+                            code = 0x10000 + ((code - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF);
+                        }
+                        match ::std::char::from_u32(code) {
+                            Some(c) => text.push(c),
+                            None => return self.error("a \\u escape isn't a character"),
+                        }
+                    },
+                    Some(c) => text.push(c),
+                    None => return self.error("a string is never closed"),
+                },
+                Some('\n') | None => return self.error("a string is never closed"),
+                Some(c) => text.push(c),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, ParseError> {
+        self.next();
+        let mut values = Vec::new();
+        if self.take(']') {
+            return Ok(Json::Array(values));
+        }
+        loop {
+            values.push(try!(self.value()));
+            if self.take(']') {
+                return Ok(Json::Array(values));
+            }
+            if !self.take(',') {
+                return self.error("expected , or ] in an array");
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, ParseError> {
+        self.next();
+        let mut fields = Vec::new();
+        if self.take('}') {
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some('"') {
+                return self.error("expected a field name in quotes");
+            }
+            let name = try!(self.string());
+            if !self.take(':') {
+                return self.error("expected : after a field name");
+            }
+            fields.push((name, try!(self.value())));
+            if self.take('}') {
+                return Ok(Json::Object(fields));
+            }
+            if !self.take(',') {
+                return self.error("expected , or } in an object");
+            }
+        }
+    }
+}
+
+// Takes an array of todos, or an object with the array in a todos field
+fn parse_json(text: &str) -> Result<Vec<Entry>, ParseError> {
+    let mut parser = JsonParser { chars: text.chars().collect(), position: 0, line: 1 };
+    let json = try!(parser.value());
+    parser.skip_whitespace();
+    if parser.peek().is_some() {
+        return parser.error("there's more after the end");
+    }
+    let todos = match json.get("todos").cloned().unwrap_or(json) {
+        Json::Array(todos) => todos,
+        _ => return error(1, "expected an array of todos".to_string()),
+    };
+
+    let mut entries = Vec::new();
+    for (index, todo) in todos.iter().enumerate() {
+        let line = index + 1;
+        let invalid = |message: &str| ParseError { line: line, message: message.to_string() };
+        if todo.get("deleted") == Some(&Json::Bool(true)) {
+            continue;
+        }
+        let title = match todo.get("title") {
+            Some(&Json::String(ref title)) => title,
+            _ => return error(line, "every todo needs a title".to_string()),
+        };
+        let completed = match todo.get("completed") {
+            Some(&Json::Bool(completed)) => completed,
+            None => false,
+            _ => return Err(invalid("completed must be true or false")),
+        };
+        let mut entry = Entry::new(line, title, completed);
+        if let Some(due) = todo.get("due") {
+            entry.due = match *due {
+                Json::String(ref due) => parse_date(due),
+                _ => None,
+            };
+            if entry.due.is_none() {
+                return Err(invalid("due must be a date like 2016-06-17"));
+            }
+        }
+        if let Some(priority) = todo.get("priority") {
+            entry.priority = match *priority {
+                Json::String(ref name) => Priority::parse(&name.to_lowercase()),
+                _ => None,
+            };
+            if entry.priority.is_none() {
+                return Err(invalid("priority must be low, medium or high"));
+            }
+        }
+        if let Some(tags) = todo.get("tags") {
+            let tags = match *tags {
+                Json::Array(ref tags) => tags.iter().map(|tag| match *tag {
+                    Json::String(ref tag) => parse_tag(tag),
+                    _ => None,
+                }).collect(),
+                _ => None,
+            };
+            entry.tags = try!(tags.ok_or_else(|| invalid("tags must be an array of single words")));
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+// Splits CSV into records of fields, with the line each record starts on.
+// Quoted fields can have commas, "" for a quote and line breaks in them
+fn csv_records(text: &str) -> Result<Vec<(usize, Vec<String>)>, ParseError> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut line = 1;
+    let mut record_line = 1;
+    let mut in_quotes = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\n' { line += 1; }
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => { chars.next(); field.push('"'); },
+                '"' => in_quotes = false,
+                c => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => in_quotes = true,
+            ',' => record.push(mem::replace(&mut field, String::new())),
+            '\r' => (),
+            '\n' => {
+                record.push(mem::replace(&mut field, String::new()));
+                records.push((record_line, mem::replace(&mut record, Vec::new())));
+                record_line = line;
+            },
+            c => field.push(c),
+        }
+    }
+    if in_quotes {
+        return error(record_line, "a quoted field is never closed".to_string());
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push((record_line, record));
+    }
+    // Blank lines aren't todos
+    records.retain(|&(_, ref record)| !(record.len() == 1 && record[0].trim().is_empty()));
+    Ok(records)
+}
+
+fn parse_completed(value: &str) -> Option<bool> {
+    match value.trim().to_lowercase().as_str() {
+        "true" | "yes" | "1" | "x" | "done" => Some(true),
+        "false" | "no" | "0" | "" => Some(false),
+        _ => None,
+    }
+}
+
+// The first line names the columns, only title is required and columns we
+// don't know are ignored. Tags are separated by spaces
+fn parse_csv(text: &str) -> Result<Vec<Entry>, ParseError> {
+    let mut records = try!(csv_records(text)).into_iter();
+    let header = match records.next() {
+        Some((_, header)) => header,
+        None => return Ok(Vec::new()),
+    };
+    let column = |name: &str| header.iter().position(|column| column.trim().to_lowercase() == name);
+    let title_column = match column("title") {
+        Some(title_column) => title_column,
+        None => return error(1, "the first line needs a title column".to_string()),
+    };
+    let (completed_column, due_column, priority_column, tags_column) =
+        (column("completed"), column("due"), column("priority"), column("tags"));
+
+    let mut entries = Vec::new();
+    for (line, record) in records {
+        let value = |column: Option<usize>| column.and_then(|column| record.get(column))
+            .map_or("", |value| value.trim());
+        let completed = match parse_completed(value(completed_column)) {
+            Some(completed) => completed,
+            None => return error(line, format!("completed can't be `{}`", value(completed_column))),
+        };
+        let mut entry = Entry::new(line, value(Some(title_column)), completed);
+        match value(due_column) {
+            "" => (),
+            due => match parse_date(due) {
+                Some(due) => entry.due = Some(due),
+                None => return error(line, format!("`{}` is not a date like 2016-06-17", due)),
+            },
+        }
+        match value(priority_column) {
+            "" => (),
+            priority => match Priority::parse(&priority.to_lowercase()) {
+                Some(priority) => entry.priority = Some(priority),
+                None => return error(line, format!("`{}` is not low, medium or high", priority)),
+            },
+        }
+        for tag in value(tags_column).split_whitespace() {
+            entry.tags.extend(parse_tag(tag));
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+// Every `- [ ] title` or `- [x] title` line, * and + work as bullets too
+fn parse_markdown(text: &str) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line_text = line.trim();
+        let item = match line_text.chars().next() {
+            Some('-') | Some('*') | Some('+') => line_text[1..].trim(),
+            _ => continue,
+        };
+        let completed = if item.starts_with("[ ]") {
+            false
+        } else if item.starts_with("[x]") || item.starts_with("[X]") {
+            true
+        } else {
+            continue;
+        };
+        entries.push(Entry::new(index + 1, &item[3..], completed));
+    }
+    entries
+}
+
+fn is_date(word: &str) -> bool {
+    word.len() == 10 && parse_date(word).is_some()
+}
+
+// A todo.txt line is `x` for done, then the dates it was done and created,
+// then (A) for the priority. due:, pri:, +project and @context tags can be
+// anywhere after that, and both projects and contexts become tags
+fn parse_todo_txt(text: &str) -> Result<Vec<Entry>, ParseError> {
+    let mut entries = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let mut words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+        let completed = words[0] == "x";
+        if completed {
+            words.remove(0);
+        }
+        let mut priority = None;
+        if let Some(first) = words.first().cloned() {
+            let letters: Vec<char> = first.chars().collect();
+            if letters.len() == 3 && letters[0] == '(' && letters[2] == ')' {
+                priority = from_todo_txt_priority(letters[1]);
+                if priority.is_some() { words.remove(0); }
+            }
+        }
+        // Up to two dates, when it was done and when it was created
+        for _ in 0..2 {
+            if words.first().map_or(false, |word| is_date(word)) {
+                words.remove(0);
+            }
+        }
+
+        let mut title = Vec::new();
+        let mut entry = Entry::new(line_number, "", completed);
+        entry.priority = priority;
+        for word in words {
+            let tag = if word.len() > 1 && (word.starts_with('+') || word.starts_with('@')) {
+                parse_tag(&word[1..])
+            } else {
+                None
+            };
+            if word.starts_with("due:") {
+                entry.due = Some(try!(parse_date(&word[4..]).ok_or(ParseError {
+                    line: line_number,
+                    message: format!("`{}` is not a date like due:2016-06-17", word),
+                })));
+            } else if word.starts_with("pri:") && word.len() == 5 {
+                entry.priority = word[4..].chars().next().and_then(from_todo_txt_priority);
+            } else if let Some(tag) = tag {
+                entry.tags.insert(tag);
+            } else {
+                title.push(word);
+            }
+        }
+        entry.title = title.join(" ");
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+// What importing one entry would do
+#[derive(Clone, Debug)]
+pub enum Change {
+    Add(Entry),
+    // A todo that's in the list already, marked as done
+    Complete(TodoId, Entry),
+    // Or marked as not done any more
+    Reopen(TodoId, Entry),
+    Unchanged(TodoId, Entry),
+    // The entry can't be a todo, with why not
+    Skip(Entry, String),
+}
+
+// Everything an import would change, worked out before anything is
+// dispatched so it can be shown as a dry run first
+pub struct ImportPlan {
+    pub changes: Vec<Change>,
+}
+
+// Matches the entries against the todos in `state` by title. Each todo is only
+// matched once, so a file with the same title twice adds the second one
+pub fn plan(state: &State, entries: Vec<Entry>) -> ImportPlan {
+    let mut matched = BTreeSet::new();
+    let mut changes = Vec::new();
+    for entry in entries {
+        if entry.title.is_empty() {
+            changes.push(Change::Skip(entry, "it has no title".to_string()));
+            continue;
+        }
+        let existing = state.todos.iter()
+            .find(|todo| !todo.deleted && !matched.contains(&todo.id) && todo.title == entry.title)
+            .map(|todo| (todo.id, todo.completed));
+        changes.push(match existing {
+            Some((todo_id, completed)) => {
+                matched.insert(todo_id);
+                match (completed, entry.completed) {
+                    (false, true) => Change::Complete(todo_id, entry),
+                    (true, false) => Change::Reopen(todo_id, entry),
+                    _ => Change::Unchanged(todo_id, entry),
+                }
+            },
+            None => Change::Add(entry),
+        });
+    }
+    ImportPlan { changes: changes }
+}
+
+impl ImportPlan {
+    // The actions that carry out the plan, starting from a state whose next todo
+    // gets `next_id`. A new todo is an Add, followed by a Toggle if it's done and
+    // its due date, priority and tags
+    pub fn actions(&self, next_id: TodoId) -> Vec<Action> {
+        let mut next_id = next_id;
+        let mut actions = Vec::new();
+        for change in &self.changes {
+            match *change {
+                Change::Add(ref entry) => {
+                    let todo_id = next_id;
+                    next_id += 1;
+                    actions.push(Todos(Add(entry.title.clone())));
+                    if entry.completed {
+                        actions.push(Todos(Toggle(todo_id)));
+                    }
+                    if entry.due.is_some() {
+                        actions.push(Todos(SetDue(todo_id, entry.due.clone())));
+                    }
+                    if entry.priority.is_some() {
+                        actions.push(Todos(SetPriority(todo_id, entry.priority)));
+                    }
+                    for tag in &entry.tags {
+                        actions.push(Todos(AddTag(todo_id, tag.clone())));
+                    }
+                },
+                Change::Complete(todo_id, _) | Change::Reopen(todo_id, _) => actions.push(Todos(Toggle(todo_id))),
+                Change::Unchanged(..) | Change::Skip(..) => (),
+            }
+        }
+        actions
+    }
+
+    // The dry run report, a line per entry and how many there are of each change
+    pub fn print(&self) {
+        let mut counts = [0; 5];
+        println!("\nImport:\n-------------------");
+        for change in &self.changes {
+            // What the entry matched in the list, or why it's skipped
+            let (index, name, entry, note) = match *change {
+                Change::Add(ref entry) => (0, "add", entry, None),
+                Change::Complete(todo_id, ref entry) => (1, "complete", entry, Some(format!("#{}", todo_id))),
+                Change::Reopen(todo_id, ref entry) => (2, "reopen", entry, Some(format!("#{}", todo_id))),
+                Change::Unchanged(todo_id, ref entry) => (3, "unchanged", entry, Some(format!("#{}", todo_id))),
+                Change::Skip(ref entry, ref reason) => (4, "skip", entry, Some(reason.clone())),
+            };
+            counts[index] += 1;
+            match note {
+                Some(note) => println!("{:>4}: {:<10} {} ({})", entry.line, name, entry.title, note),
+                None => println!("{:>4}: {:<10} {}", entry.line, name, entry.title),
+            }
+        }
+        println!("-------------------");
+        println!("{} to add, {} to complete, {} to reopen, {} unchanged, {} skipped",
+                 counts[0], counts[1], counts[2], counts[3], counts[4]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use { Action, Priority, State, Store, Todo, reducer };
+    use Action::{ Batch, Todos };
+    use TodoAction::Add;
+    use super::{ Entry, Format, export, parse, plan };
+
+    fn tags(tags: &[&str]) -> BTreeSet<String> {
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    fn todos() -> Vec<Todo> {
+        let mut todos = vec![Todo::new(1, "Pay rent, \"now\"".to_string()), Todo::new(2, "Call mum".to_string()),
+                             Todo::new(3, "Thrown away".to_string()), Todo::new(4, "Plain".to_string())];
+        todos[0].completed = true;
+        todos[0].due = Some("2026-11-01".to_string());
+        todos[0].priority = Some(Priority::High);
+        todos[0].tags = tags(&["bills", "home"]);
+        todos[1].priority = Some(Priority::Low);
+        todos[1].due = Some("2026-10-18".to_string());
+        todos[1].tags = tags(&["phone"]);
+        todos[2].deleted = true;
+        todos
+    }
+
+    type Summary = (String, bool, Option<String>, Option<Priority>, BTreeSet<String>);
+
+    // Everything but the line, which depends on the format
+    fn summary(entries: &[Entry]) -> Vec<Summary> {
+        entries.iter().map(|entry| {
+            (entry.title.clone(), entry.completed, entry.due.clone(), entry.priority, entry.tags.clone())
+        }).collect()
+    }
+
+    // Files as other tools write them, rather than as export does
+
+    // A release checklist from a GitHub issue
+    const GITHUB_CHECKLIST: &str = "\
+## Release 0.3
+
+Left to do before we tag it, see #42.
+
+- [x] Bump the version in `Cargo.toml`
+- [ ] Write the changelog
+  - [ ] Mention the new import formats
+  - [X] Thank @jdoe and @mkim
+* [ ] Check the [docs](https://example.com/docs) build
+- Not a task, just a note
++ [x] Tag the release
+";
+
+    // From a phone app that syncs todo.txt. Done todos have the date they
+    // were done and created, t: is a threshold date we don't know about
+    const TODO_TXT: &str = "\
+(A) 2026-10-01 Call the landlord about the boiler @Phone +Flat
+x 2026-10-12 2026-10-02 Renew passport +Travel due:2026-10-15
+2026-10-05 Pick up dry cleaning @errands t:2026-10-20
+(C) Read \"Designing Data-Intensive Applications\" +books
+";
+
+    // Saved from a spreadsheet, which ends rows with CRLF but keeps a bare LF
+    // for the line breaks in a cell
+    const SPREADSHEET_CSV: &str = "Title,Completed,Due,Priority,Tags,Notes\r\n\
+Pay rent,TRUE,2026-11-01,High,bills home,\r\n\
+\"Call mum, then dad\",FALSE,,low,phone,\"Ask about\nthe weekend\"\r\n\
+Plain,,,,,\r\n";
+
+    // Another app's backup, with fields we don't read and escapes
+    const APP_JSON: &str = r##"{
+  "exported": "2026-10-18T09:30:00Z",
+  "todos": [
+    {"id": 17, "title": "Water the plants \u2618", "completed": false, "due": "2026-10-20",
+     "priority": "medium", "tags": ["home"], "notes": {"text": "the balcony ones", "pinned": false}},
+    {"id": 18, "title": "Send the \"final\" report", "completed": true, "due": null, "priority": null, "tags": []},
+    {"id": 19, "title": "Old idea", "deleted": true},
+    {"id": 20, "title": "Plan trip \ud83c\udfd4", "tags": ["Travel", "#Summer"], "estimate": 1.5e1}
+  ]
+}"##;
+
+    #[test]
+    fn reads_a_github_checklist() {
+        let entries = parse(GITHUB_CHECKLIST, Format::Markdown).unwrap();
+        let titles: Vec<(usize, &str, bool)> = entries.iter()
+            .map(|entry| (entry.line, entry.title.as_str(), entry.completed))
+            .collect();
+        assert_eq!(titles, vec![
+            (5, "Bump the version in `Cargo.toml`", true),
+            (6, "Write the changelog", false),
+            (7, "Mention the new import formats", false),
+            (8, "Thank @jdoe and @mkim", true),
+            (9, "Check the [docs](https://example.com/docs) build", false),
+            (11, "Tag the release", true),
+        ]);
+    }
+
+    #[test]
+    fn reads_todo_txt_from_another_app() {
+        let entries = parse(TODO_TXT, Format::TodoTxt).unwrap();
+        assert_eq!(summary(&entries), vec![
+            ("Call the landlord about the boiler".to_string(), false, None, Some(Priority::High), tags(&["flat", "phone"])),
+            ("Renew passport".to_string(), true, Some("2026-10-15".to_string()), None, tags(&["travel"])),
+            ("Pick up dry cleaning t:2026-10-20".to_string(), false, None, None, tags(&["errands"])),
+            ("Read \"Designing Data-Intensive Applications\"".to_string(), false, None, Some(Priority::Low), tags(&["books"])),
+        ]);
+    }
+
+    #[test]
+    fn reads_csv_from_a_spreadsheet() {
+        let entries = parse(SPREADSHEET_CSV, Format::Csv).unwrap();
+        assert_eq!(summary(&entries), vec![
+            ("Pay rent".to_string(), true, Some("2026-11-01".to_string()), Some(Priority::High), tags(&["bills", "home"])),
+            ("Call mum, then dad".to_string(), false, None, Some(Priority::Low), tags(&["phone"])),
+            ("Plain".to_string(), false, None, None, tags(&[])),
+        ]);
+        assert_eq!(entries.iter().map(|entry| entry.line).collect::<Vec<_>>(), vec![2, 3, 5]);
+    }
+
+    #[test]
+    fn reads_json_from_another_app() {
+        let entries = parse(APP_JSON, Format::Json).unwrap();
+        assert_eq!(summary(&entries), vec![
+            ("Water the plants \u{2618}".to_string(), false, Some("2026-10-20".to_string()), Some(Priority::Medium), tags(&["home"])),
+            ("Send the \"final\" report".to_string(), true, None, None, tags(&[])),
+            ("Plan trip \u{1f3d4}".to_string(), false, None, None, tags(&["summer", "travel"])),
+        ]);
+        assert_eq!(entries.iter().map(|entry| entry.line).collect::<Vec<_>>(), vec![1, 2, 4]);
+    }
+
+    #[test]
+    fn every_format_reads_back_what_it_wrote() {
+        let todos = todos();
+        let expected: Vec<Summary> = todos.iter().filter(|todo| !todo.deleted)
+            .map(|todo| (todo.title.clone(), todo.completed, todo.due.clone(), todo.priority, todo.tags.clone()))
+            .collect();
+        for format in [Format::Json, Format::Csv, Format::TodoTxt] {
+            let entries = parse(&export(&todos, format), format).unwrap();
+            assert_eq!(summary(&entries), expected, "{:?}", format);
+        }
+
+        // Markdown only has room for the title and whether it's done
+        let entries = parse(&export(&todos, Format::Markdown), Format::Markdown).unwrap();
+        let titles: Vec<(&str, bool)> = entries.iter().map(|entry| (entry.title.as_str(), entry.completed)).collect();
+        assert_eq!(titles, vec![("Pay rent, \"now\"", true), ("Call mum", false), ("Plain", false)]);
+    }
+
+    #[test]
+    fn csv_and_json_keep_quotes_commas_and_line_breaks_in_titles() {
+        let title = "First line\nsecond, \"quoted\" line\twith a tab";
+        let todos = vec![Todo::new(1, title.to_string())];
+        let csv = export(&todos, Format::Csv);
+        assert!(csv.contains("\"First line\nsecond, \"\"quoted\"\" line\twith a tab\""));
+        assert_eq!(parse(&csv, Format::Csv).unwrap()[0].title, title);
+        assert_eq!(parse(&export(&todos, Format::Json), Format::Json).unwrap()[0].title, title);
+
+        // Line based formats put it on one line instead
+        assert_eq!(export(&todos, Format::TodoTxt), "First line second, \"quoted\" line\twith a tab\n");
+    }
+
+    #[test]
+    fn csv_records_start_on_the_line_their_first_field_is_on() {
+        let csv = "Title,Completed,Due,Color\r\n\"Line one\nline \"\"two\"\"\",yes,,red\r\n\r\n\"a, b\",no,2016-6-7\r\n";
+        let entries = parse(csv, Format::Csv).unwrap();
+        assert_eq!(entries.iter().map(|entry| entry.line).collect::<Vec<_>>(), vec![2, 5]);
+        assert_eq!(entries[0].title, "Line one\nline \"two\"");
+        assert!(entries[0].completed);
+        assert_eq!(entries[1].title, "a, b");
+        assert_eq!(entries[1].due, Some("2016-06-07".to_string()));
+
+        let bad_date = format!("{}\"unfinished\",no,someday\r\n", csv);
+        assert_eq!(parse(&bad_date, Format::Csv).unwrap_err().line, 6);
+        let unclosed = "title\r\nfine\r\n\"never\nclosed\r\n";
+        assert_eq!(parse(unclosed, Format::Csv).unwrap_err().line, 3);
+        assert_eq!(parse("name,done\r\nx,yes\r\n", Format::Csv).unwrap_err().line, 1);
+    }
+
+    #[test]
+    fn todo_txt_reads_priorities_dates_and_tags() {
+        let text = "x 2026-10-18 2026-10-01 Pay rent pri:A due:2026-11-01 +bills @Home\n\
+                    (B) Call mum +phone\n\
+                    \n\
+                    (a) lower case is no priority +\n";
+        let entries = parse(text, Format::TodoTxt).unwrap();
+        assert_eq!(summary(&entries), vec![
+            ("Pay rent".to_string(), true, Some("2026-11-01".to_string()), Some(Priority::High), tags(&["bills", "home"])),
+            ("Call mum".to_string(), false, None, Some(Priority::Medium), tags(&["phone"])),
+            ("(a) lower case is no priority +".to_string(), false, None, None, tags(&[])),
+        ]);
+        assert_eq!(entries.iter().map(|entry| entry.line).collect::<Vec<_>>(), vec![1, 2, 4]);
+
+        let error = parse("Buy milk\n(C) Bake due:tomorrow\n", Format::TodoTxt).unwrap_err();
+        assert_eq!(error.line, 2);
+    }
+
+    #[test]
+    fn json_errors_name_the_todo_or_the_line_they_are_on() {
+        let entries = parse(r#"{"todos":[{"title":"a"},{"title":"b","deleted":true}],"next_id":3}"#, Format::Json).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(parse(r#"[{"title":"a"},{"completed":true}]"#, Format::Json).unwrap_err().line, 2);
+        assert_eq!(parse(r#"[{"title":"a"},{"title":"b"},{"title":"c","due":"soon"}]"#, Format::Json).unwrap_err().line, 3);
+        assert_eq!(parse("[\n  {\"title\": \"a\"},\n  {\"title\" \"b\"}\n]", Format::Json).unwrap_err().line, 3);
+    }
+
+    #[test]
+    fn an_import_is_undone_in_one_go() {
+        let mut store: Store<State, Action> = Store::create_store(reducer(), State::default());
+        store.dispatch( Todos( Add("Call mum".to_string()) ) );
+        let entries = parse(&export(&todos(), Format::Json), Format::Json).unwrap();
+        let actions = plan(store.get_state(), entries).actions(store.get_state().next_id);
+        store.dispatch( Batch(actions) );
+        assert_eq!(store.get_state().todos.len(), 3);
+        assert_eq!(store.get_state().todos[0].priority, None);
+        assert_eq!(store.get_state().todos[1].tags, tags(&["bills", "home"]));
+
+        assert!(store.undo());
+        assert_eq!(store.get_state().todos.len(), 1);
+        assert!(store.redo());
+        assert_eq!(store.get_state().todos.len(), 3);
+    }
+}
@@ -0,0 +1,143 @@
+// The store isn't in a library, so this pulls in the modules it's made of
+// straight from src and only uses some of what they have
+#![allow(dead_code)]
+#[macro_use] extern crate lazy_static;
+extern crate nickel;
+extern crate rusqlite;
+extern crate rustc_serialize;
+extern crate toml;
+#[path = "../src/config.rs"] mod config;
+#[path = "../src/error.rs"] mod error;
+#[path = "../src/history.rs"] mod history;
+#[path = "../src/middleware.rs"] mod middleware;
+#[path = "../src/persist.rs"] mod persist;
+#[path = "../src/query.rs"] mod query;
+#[path = "../src/sqlite.rs"] mod sqlite;
+#[path = "../src/storage.rs"] mod storage;
+#[path = "../src/store.rs"] mod store;
+#[path = "../src/subscription.rs"] mod subscription;
+#[path = "../src/todo.rs"] mod todo;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use rustc_serialize::json::{ Json, ToJson };
+use store::{ Store, State, SharedTodoStore, View, reducer, select_todos };
+use store::Action::{ Todos };
+use todo::TodoAction::{ Add, Toggle };
+
+// `cargo run --release --example dispatch_benchmark` measures how many
+// dispatches a second one list gets through while other threads keep
+// rendering it, the way request threads do. It runs on a store that only
+// lives in memory, so no data is touched
+
+// How long every round runs and how many threads dispatch in each
+const ROUND_SECONDS: u64 = 3;
+const WRITERS: usize = 4;
+
+// Enough todos that rendering the list takes a while
+const TODOS: u64 = 500;
+
+// Every round runs with this many threads rendering
+const READERS: [usize; 3] = [0, 4, 16];
+
+// How the rendering threads get at the state
+#[derive(Clone, Copy, PartialEq)]
+enum Reads {
+    // The last published state, without the lock
+    Snapshot,
+    // Holding the store lock while rendering, like every page used to
+    Locked,
+}
+
+impl Reads {
+    fn describe(&self) -> &'static str {
+        match *self {
+            Reads::Snapshot => "rendering snapshots",
+            Reads::Locked => "rendering in the lock",
+        }
+    }
+}
+
+fn new_store() -> SharedTodoStore {
+    let mut store = Store::create_store(reducer(), State::default());
+    for number in 0..TODOS {
+        store.dispatch( Todos( Add(format!("Todo number {}", number + 1)) ) );
+    }
+    store.share()
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1_000_000_000.0
+}
+
+// Dispatches and renders per second for one round
+fn round<F>(readers: usize, reads: Reads, render: &Arc<F>) -> (f64, f64)
+    where F: Fn(&State) + Send + Sync + 'static {
+    let store = new_store();
+    let done = Arc::new(AtomicBool::new(false));
+    let dispatches = Arc::new(AtomicUsize::new(0));
+    let renders = Arc::new(AtomicUsize::new(0));
+
+    let mut threads = Vec::new();
+    for writer in 0..WRITERS {
+        let (store, done, dispatches) = (store.clone(), done.clone(), dispatches.clone());
+        threads.push(thread::spawn(move || {
+            // Every writer toggles its own todos, one after the other
+            let mut todo_id = writer as u64;
+            while !done.load(Ordering::Relaxed) {
+                store.lock().dispatch( Todos( Toggle(todo_id % TODOS + 1) ) );
+                dispatches.fetch_add(1, Ordering::Relaxed);
+                todo_id += WRITERS as u64;
+            }
+        }));
+    }
+    for _ in 0..readers {
+        let (store, done, renders, render) = (store.clone(), done.clone(), renders.clone(), render.clone());
+        threads.push(thread::spawn(move || {
+            while !done.load(Ordering::Relaxed) {
+                match reads {
+                    Reads::Snapshot => render(&*store.state()),
+                    Reads::Locked => render(store.lock().get_state()),
+                }
+                renders.fetch_add(1, Ordering::Relaxed);
+            }
+        }));
+    }
+
+    let started = Instant::now();
+    thread::sleep(Duration::from_secs(ROUND_SECONDS));
+    done.store(true, Ordering::Relaxed);
+    let elapsed = seconds(started.elapsed());
+    let counts = (dispatches.load(Ordering::Relaxed), renders.load(Ordering::Relaxed));
+    for thread in threads {
+        let _ = thread.join();
+    }
+    (counts.0 as f64 / elapsed, counts.1 as f64 / elapsed)
+}
+
+// Stands in for the list page, which needs the templates and a request. It
+// turns the visible todos into JSON text the way the page does before
+// handlebars gets them
+fn render(state: &State) {
+    let todos = select_todos(state, &View::default()).iter().map(|todo| todo.to_json()).collect();
+    Json::Array(todos).to_string();
+}
+
+// Runs every round and prints the results
+fn main() {
+    let render = Arc::new(render);
+    println!("{} threads dispatching to a list of {} todos, {} seconds per round", WRITERS, TODOS, ROUND_SECONDS);
+    for &readers in READERS.iter() {
+        for &reads in &[Reads::Snapshot, Reads::Locked] {
+            // Without readers it doesn't matter how they read
+            if readers == 0 && reads == Reads::Locked {
+                continue;
+            }
+            let (dispatches, renders) = round(readers, reads, &render);
+            println!("{:>2} threads {:<22} {:>9.0} dispatches/s {:>7.0} renders/s",
+                     readers, reads.describe(), dispatches, renders);
+        }
+    }
+}
@@ -0,0 +1,506 @@
+use std::collections::{ BTreeMap, BTreeSet };
+use std::io::Read;
+use std::sync::Arc;
+use rustc_serialize::json::{Json, ToJson};
+use nickel::{Nickel, HttpRouter, Request, Response, MiddlewareResult, MediaType, QueryString};
+use nickel::status::StatusCode;
+use store::State;
+use auth::{ Auth, Access, check_access, session_token };
+use user_stores::UserStores;
+use config;
+use store::Action::{ Todos };
+use store::{ View, select_todos };
+use todo::{ Todo, TodoId, Priority, parse_date, parse_tag, retag };
+use todo::TodoAction::{ Add, Edit, Move, Remove, SetDue, SetPriority, Toggle };
+use lists::TodoList;
+use transfer::{ self, Format };
+use error::AppError;
+
+// Every route is served under /api/v1, and /api always points at the newest version
+pub const API_VERSION: &'static str = "v1";
+
+// Encodes anything that implements ToJson and sends it with the given status code
+fn send_json<'mw, T: ToJson>(mut res: Response<'mw>, status: StatusCode, data: &T) -> MiddlewareResult<'mw> {
+    res.set(status);
+    res.set(MediaType::Json);
+    res.send(data.to_json().to_string())
+}
+
+// Sends an export as a download named after the list, like Todos.csv
+pub fn send_file<'mw>(mut res: Response<'mw>, list: &TodoList, format: Format, file: String) -> MiddlewareResult<'mw> {
+    let file_name: String = list.name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    res.headers_mut().set_raw("Content-Type", vec![format.content_type().as_bytes().to_vec()]);
+    res.headers_mut().set_raw("Content-Disposition",
+                              vec![format!("attachment; filename=\"{}.{}\"", file_name, format.extension()).into_bytes()]);
+    res.send(file)
+}
+
+fn send_no_content<'mw>(mut res: Response<'mw>) -> MiddlewareResult<'mw> {
+    res.set(StatusCode::NoContent);
+    res.send("")
+}
+
+// Errors are always sent as `{ "error": "what went wrong" }`
+fn send_error<'mw>(res: Response<'mw>, status: StatusCode, message: &str) -> MiddlewareResult<'mw> {
+    let mut body = BTreeMap::new();
+    body.insert("error".to_string(), message.to_json());
+    send_json(res, status, &Json::Object(body))
+}
+
+// Reads the request body, as long as it's no longer than the configured max_body_bytes
+pub fn read_body(req: &mut Request) -> Result<String, String> {
+    let max_body_bytes = config::limits().max_body_bytes as u64;
+    let mut body = String::new();
+    if let Err(e) = (&mut req.origin).take(max_body_bytes + 1).read_to_string(&mut body) {
+        return Err(format!("Could not read request body: {}", e));
+    }
+    if body.len() as u64 > max_body_bytes {
+        return Err(format!("Request body is larger than {} bytes", max_body_bytes));
+    }
+    Ok(body)
+}
+
+// Reads the request body and parses it as a JSON object
+fn read_json_object(req: &mut Request) -> Result<BTreeMap<String, Json>, String> {
+    let body = try!(read_body(req));
+    match Json::from_str(&body) {
+        Ok(Json::Object(object)) => Ok(object),
+        Ok(_) => Err("Request body must be a JSON object".to_string()),
+        Err(e) => Err(format!("Invalid JSON: {}", e)),
+    }
+}
+
+// Deleted todos are still in State::todos, but as far as the API goes they're gone
+fn find_todo(state: &State, todo_id: TodoId) -> Option<Todo> {
+    state.todos.iter()
+        .find(|todo| todo.id == todo_id && !todo.deleted)
+        .cloned()
+}
+
+// The JSON version of auth::deny for everything but Access::Granted
+fn send_denied<'mw>(res: Response<'mw>, access: Access) -> MiddlewareResult<'mw> {
+    match access {
+        Access::Anonymous => send_error(res, StatusCode::Unauthorized, "not logged in"),
+        Access::Forbidden => send_error(res, StatusCode::Forbidden, "request came from another site"),
+        Access::Missing => send_error(res, StatusCode::NotFound, "list not found"),
+        Access::Failed(e) => send_error(res, StatusCode::InternalServerError,
+                                        &format!("could not open todo list: {}", e)),
+        Access::Granted(..) => send_error(res, StatusCode::InternalServerError, "access was granted"),
+    }
+}
+
+// For the errors UserStores sends back when managing lists
+fn send_app_error<'mw>(res: Response<'mw>, error: AppError) -> MiddlewareResult<'mw> {
+    send_error(res, error.status(), &error.to_string())
+}
+
+pub fn mount(server: &mut Nickel, auth: &Arc<Auth>, stores: &Arc<UserStores>) {
+    let versioned = format!("/api/{}", API_VERSION);
+    for prefix in &["/api", versioned.as_str()] {
+        mount_at(server, prefix, auth, stores);
+    }
+}
+
+fn mount_at(server: &mut Nickel, prefix: &str, auth: &Arc<Auth>, stores: &Arc<UserStores>) {
+    // POST /api/session with `{ "username": "...", "password": "..." }` logs in
+    // and returns `{ "token": "..." }`, send it as `Authorization: Bearer <token>`
+    let auth_clone = auth.clone();
+    server.post(format!("{}/session", prefix), middleware! { |req, res|
+        let body = match read_json_object(req) {
+            Ok(body) => body,
+            Err(message) => return send_error(res, StatusCode::BadRequest, &message),
+        };
+        let username = body.get("username").and_then(|username| username.as_string()).unwrap_or("");
+        let password = body.get("password").and_then(|password| password.as_string()).unwrap_or("");
+        return match auth_clone.login(username, password) {
+            Some(token) => {
+                let mut session = BTreeMap::new();
+                session.insert("token".to_string(), token.to_json());
+                send_json(res, StatusCode::Created, &Json::Object(session))
+            },
+            None => send_error(res, StatusCode::Unauthorized, "wrong username or password"),
+        }
+    });
+
+    // DELETE /api/session logs the token out again
+    let auth_clone = auth.clone();
+    server.delete(format!("{}/session", prefix), middleware! { |req, res|
+        if let Some(token) = session_token(req) {
+            auth_clone.logout(&token);
+        }
+        return send_no_content(res)
+    });
+
+    // GET /api/lists returns every list, archived or not, as
+    // `[{ "id": 1, "name": "Todos", "archived": false, "url": "/lists/1" }]`
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+    server.get(format!("{}/lists", prefix), middleware! { |req, res|
+        let username = match check_access(req, &auth_clone, &stores_clone, false) {
+            Access::Granted(username, _, _) => username,
+            denied => return send_denied(res, denied),
+        };
+        return match stores_clone.lists(&username) {
+            Ok(lists) => send_json(res, StatusCode::Ok, &lists),
+            Err(e) => send_app_error(res, e),
+        }
+    });
+
+    // POST /api/lists with `{ "name": "..." }` creates a list and returns it
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+    server.post(format!("{}/lists", prefix), middleware! { |req, res|
+        let username = match check_access(req, &auth_clone, &stores_clone, true) {
+            Access::Granted(username, _, _) => username,
+            denied => return send_denied(res, denied),
+        };
+        let body = match read_json_object(req) {
+            Ok(body) => body,
+            Err(message) => return send_error(res, StatusCode::BadRequest, &message),
+        };
+        let name = body.get("name").and_then(|name| name.as_string()).unwrap_or("");
+        return match stores_clone.create_list(&username, name) {
+            Ok(list) => send_json(res, StatusCode::Created, &list),
+            Err(e) => send_app_error(res, e),
+        }
+    });
+
+    // PATCH /api/lists/:list with `{ "name": "..." }` renames the list and
+    // `{ "archived": true }` archives it
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+    server.patch(format!("{}/lists/:list", prefix), middleware! { |req, res|
+        let (username, list) = match check_access(req, &auth_clone, &stores_clone, true) {
+            Access::Granted(username, list, _) => (username, list),
+            denied => return send_denied(res, denied),
+        };
+        let body = match read_json_object(req) {
+            Ok(body) => body,
+            Err(message) => return send_error(res, StatusCode::BadRequest, &message),
+        };
+        let name = match body.get("name") {
+            Some(name) => match name.as_string() {
+                Some(name) => Some(name.to_string()),
+                None => return send_error(res, StatusCode::UnprocessableEntity, "name must be a string"),
+            },
+            None => None,
+        };
+        let archived = match body.get("archived") {
+            Some(archived) => match archived.as_boolean() {
+                Some(archived) => Some(archived),
+                None => return send_error(res, StatusCode::UnprocessableEntity, "archived must be a boolean"),
+            },
+            None => None,
+        };
+        if name.is_none() && archived.is_none() {
+            return send_error(res, StatusCode::UnprocessableEntity, "send name or archived");
+        }
+
+        let mut list = list;
+        if let Some(name) = name {
+            list = match stores_clone.rename_list(&username, list.id, &name) {
+                Ok(list) => list,
+                Err(e) => return send_app_error(res, e),
+            };
+        }
+        if let Some(archived) = archived {
+            list = match stores_clone.archive_list(&username, list.id, archived) {
+                Ok(list) => list,
+                Err(e) => return send_app_error(res, e),
+            };
+        }
+        return send_json(res, StatusCode::Ok, &list)
+    });
+
+    // DELETE /api/lists/:list deletes the list and every todo in it, the last
+    // list can't be deleted
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+    server.delete(format!("{}/lists/:list", prefix), middleware! { |req, res|
+        let (username, list) = match check_access(req, &auth_clone, &stores_clone, true) {
+            Access::Granted(username, list, _) => (username, list),
+            denied => return send_denied(res, denied),
+        };
+        return match stores_clone.delete_list(&username, list.id) {
+            Ok(_) => send_no_content(res),
+            Err(e) => send_app_error(res, e),
+        }
+    });
+
+    // The todo routes work on the default list under /api, and on any list
+    // under /api/lists/:list, like /api/lists/2/todos
+    let list_prefix = format!("{}/lists/:list", prefix);
+    for todos_prefix in &[prefix, list_prefix.as_str()] {
+        mount_todos_at(server, todos_prefix, auth, stores);
+    }
+
+    // PUT /api/visibility used to change the filter for everyone, now every
+    // request picks its own with GET /api/todos?filter=
+    server.put(format!("{}/visibility", prefix), middleware! { |_req, res|
+        return send_error(res, StatusCode::Gone, "the filter is per request now, use GET /todos?filter=active")
+    });
+}
+
+fn mount_todos_at(server: &mut Nickel, prefix: &str, auth: &Arc<Auth>, stores: &Arc<UserStores>) {
+    // GET /api/todos returns the whole State. With ?filter=, ?tag=, ?sort= or a
+    // ?q= search it only has the todos that view shows, in its order. The filters
+    // are all, active and completed, the orders created, due, priority and title,
+    // and query.rs has what a search can do. A search that doesn't parse is a 400
+    // with the column it went wrong at. Percent-encode everything in the search
+    // but letters, digits, - and _, nickel doesn't route anything else
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+    server.get(format!("{}/todos", prefix), middleware! { |req, res|
+        let store = match check_access(req, &auth_clone, &stores_clone, false) {
+            Access::Granted(_, _, store) => store,
+            denied => return send_denied(res, denied),
+        };
+        let view = {
+            let query = req.query();
+            match View::from_query(query.get("filter"), query.get("tag"), query.get("sort"), query.get("q")) {
+                Ok(view) => view,
+                Err(message) => return send_error(res, StatusCode::BadRequest, &message),
+            }
+        };
+
+        // Serialized from the last published state, without locking the store
+        let published = store.state();
+        let mut state = published.to_json();
+        if view != View::default() {
+            if let Json::Object(ref mut object) = state {
+                let todos = select_todos(&published, &view).iter().map(|todo| todo.to_json()).collect();
+                object.insert("todos".to_string(), Json::Array(todos));
+            }
+        }
+        return send_json(res, StatusCode::Ok, &state)
+    });
+
+    // POST /api/todos with `{ "title": "..." }` adds a todo and returns it
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+    server.post(format!("{}/todos", prefix), middleware! { |req, res|
+        let store = match check_access(req, &auth_clone, &stores_clone, true) {
+            Access::Granted(_, _, store) => store,
+            denied => return send_denied(res, denied),
+        };
+        let body = match read_json_object(req) {
+            Ok(body) => body,
+            Err(message) => return send_error(res, StatusCode::BadRequest, &message),
+        };
+        let title = match body.get("title").and_then(|title| title.as_string()) {
+            Some(title) if title.trim().len() > 0 => title.to_string(),
+            _ => return send_error(res, StatusCode::UnprocessableEntity, "title must be a non-empty string"),
+        };
+        let max_title_length = config::limits().max_title_length;
+        if title.trim().chars().count() > max_title_length {
+            return send_error(res, StatusCode::UnprocessableEntity,
+                              &format!("title can't be longer than {} characters", max_title_length));
+        }
+
+        let mut store = store.lock();
+        // The new todo gets the next id, unless middleware refused the action
+        let new_id = store.get_state().next_id;
+        store.dispatch( Todos( Add(title) ) );
+        return match find_todo(store.get_state(), new_id) {
+            Some(todo) => send_json(res, StatusCode::Created, &todo),
+            None => send_error(res, StatusCode::UnprocessableEntity, "todo was not added"),
+        }
+    });
+
+    // GET /api/todos/:id returns a single todo
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+    server.get(format!("{}/todos/:id", prefix), middleware! { |req, res|
+        let store = match check_access(req, &auth_clone, &stores_clone, false) {
+            Access::Granted(_, _, store) => store,
+            denied => return send_denied(res, denied),
+        };
+        let todo_id = match req.param("id").unwrap_or("").parse::<TodoId>() {
+            Ok(todo_id) => todo_id,
+            Err(_) => return send_error(res, StatusCode::BadRequest, "id must be a number"),
+        };
+        return match find_todo(&store.state(), todo_id) {
+            Some(todo) => send_json(res, StatusCode::Ok, &todo),
+            None => send_error(res, StatusCode::NotFound, "todo not found"),
+        }
+    });
+
+    // PATCH /api/todos/:id with `{ "completed": true }` toggles the todo if needed,
+    // `{ "title": "..." }` renames it, `{ "due": "2016-06-17" }`, `{ "priority": "high" }`
+    // and `{ "tags": ["work"] }` plan it. Send null to clear the due date or priority.
+    // `{ "position": 0 }` moves it to the top of the list, positions don't count
+    // deleted todos. Any of them can be sent together
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+    server.patch(format!("{}/todos/:id", prefix), middleware! { |req, res|
+        let store = match check_access(req, &auth_clone, &stores_clone, true) {
+            Access::Granted(_, _, store) => store,
+            denied => return send_denied(res, denied),
+        };
+        let todo_id = match req.param("id").unwrap_or("").parse::<TodoId>() {
+            Ok(todo_id) => todo_id,
+            Err(_) => return send_error(res, StatusCode::BadRequest, "id must be a number"),
+        };
+        let body = match read_json_object(req) {
+            Ok(body) => body,
+            Err(message) => return send_error(res, StatusCode::BadRequest, &message),
+        };
+        let completed = match body.get("completed") {
+            Some(completed) => match completed.as_boolean() {
+                Some(completed) => Some(completed),
+                None => return send_error(res, StatusCode::UnprocessableEntity, "completed must be a boolean"),
+            },
+            None => None,
+        };
+        let title = match body.get("title") {
+            Some(title) => match title.as_string() {
+                Some(title) if title.trim().len() > 0 => Some(title.to_string()),
+                _ => return send_error(res, StatusCode::UnprocessableEntity, "title must be a non-empty string"),
+            },
+            None => None,
+        };
+        let due = match body.get("due") {
+            Some(&Json::Null) => Some(None),
+            Some(due) => match due.as_string().and_then(parse_date) {
+                Some(due) => Some(Some(due)),
+                None => return send_error(res, StatusCode::UnprocessableEntity, "due must be a date like 2016-06-17 or null"),
+            },
+            None => None,
+        };
+        let priority = match body.get("priority") {
+            Some(&Json::Null) => Some(None),
+            Some(priority) => match priority.as_string().and_then(Priority::from_name) {
+                Some(priority) => Some(Some(priority)),
+                None => return send_error(res, StatusCode::UnprocessableEntity, "priority must be low, medium, high or null"),
+            },
+            None => None,
+        };
+        let tags = match body.get("tags") {
+            Some(tags) => {
+                let tags: Option<BTreeSet<String>> = tags.as_array().and_then(|tags| {
+                    tags.iter().map(|tag| tag.as_string().and_then(parse_tag)).collect()
+                });
+                match tags {
+                    Some(tags) => Some(tags),
+                    None => return send_error(res, StatusCode::UnprocessableEntity, "tags must be an array of single words"),
+                }
+            },
+            None => None,
+        };
+        let position = match body.get("position") {
+            Some(position) => match position.as_u64() {
+                Some(position) => Some(position as usize),
+                None => return send_error(res, StatusCode::UnprocessableEntity, "position must be a number from 0 up"),
+            },
+            None => None,
+        };
+        if completed.is_none() && title.is_none() && due.is_none() && priority.is_none() && tags.is_none() && position.is_none() {
+            return send_error(res, StatusCode::UnprocessableEntity, "send completed, title, due, priority, tags or position");
+        }
+        let max_title_length = config::limits().max_title_length;
+        if title.as_ref().map_or(false, |title| title.trim().chars().count() > max_title_length) {
+            return send_error(res, StatusCode::UnprocessableEntity,
+                              &format!("title can't be longer than {} characters", max_title_length));
+        }
+
+        let mut store = store.lock();
+        let todo = match find_todo(store.get_state(), todo_id) {
+            Some(todo) => todo,
+            None => return send_error(res, StatusCode::NotFound, "todo not found"),
+        };
+        // One PATCH is one change as far as undo and the event stream go
+        store.batch(|store| {
+            if let Some(completed) = completed {
+                if todo.completed != completed {
+                    store.dispatch( Todos( Toggle(todo_id) ) );
+                }
+            }
+            if let Some(due) = due {
+                store.dispatch( Todos( SetDue(todo_id, due) ) );
+            }
+            if let Some(priority) = priority {
+                store.dispatch( Todos( SetPriority(todo_id, priority) ) );
+            }
+            if let Some(tags) = tags {
+                for action in retag(&todo, &tags) {
+                    store.dispatch( Todos(action) );
+                }
+            }
+            if let Some(position) = position {
+                store.dispatch( Todos( Move(todo_id, position) ) );
+            }
+            if let Some(title) = title {
+                store.dispatch( Todos( Edit(todo_id, title) ) );
+            }
+        });
+        return match find_todo(store.get_state(), todo_id) {
+            Some(todo) => send_json(res, StatusCode::Ok, &todo),
+            None => send_error(res, StatusCode::NotFound, "todo not found"),
+        }
+    });
+
+    // DELETE /api/todos/:id removes the todo
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+    server.delete(format!("{}/todos/:id", prefix), middleware! { |req, res|
+        let store = match check_access(req, &auth_clone, &stores_clone, true) {
+            Access::Granted(_, _, store) => store,
+            denied => return send_denied(res, denied),
+        };
+        let todo_id = match req.param("id").unwrap_or("").parse::<TodoId>() {
+            Ok(todo_id) => todo_id,
+            Err(_) => return send_error(res, StatusCode::BadRequest, "id must be a number"),
+        };
+        let mut store = store.lock();
+        if find_todo(store.get_state(), todo_id).is_none() {
+            return send_error(res, StatusCode::NotFound, "todo not found");
+        }
+        store.dispatch( Todos( Remove(todo_id) ) );
+        return send_no_content(res)
+    });
+
+    // GET /api/export/csv sends every todo that isn't deleted as a file, the
+    // formats are json, csv, markdown, todotxt and ical, see transfer.rs
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+    server.get(format!("{}/export/:format", prefix), middleware! { |req, res|
+        let (list, store) = match check_access(req, &auth_clone, &stores_clone, false) {
+            Access::Granted(_, list, store) => (list, store),
+            denied => return send_denied(res, denied),
+        };
+        let format = match Format::from_name(req.param("format").unwrap_or("")) {
+            Some(format) => format,
+            None => return send_error(res, StatusCode::NotFound, "formats are json, csv, markdown, todotxt and ical"),
+        };
+        let file = transfer::export(&list, &store.state().todos, format);
+        return send_file(res, &list, format, file)
+    });
+
+    // POST /api/import/csv with a file of that format as the body adds its todos
+    // and brings the completed state of the ones already in the list in line.
+    // With ?dry_run=true nothing changes. Either way we get back a report like
+    // `{ "added": 2, "completed": 1, ..., "changes": [{ "change": "add", ... }] }`
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+    server.post(format!("{}/import/:format", prefix), middleware! { |req, res|
+        let store = match check_access(req, &auth_clone, &stores_clone, true) {
+            Access::Granted(_, _, store) => store,
+            denied => return send_denied(res, denied),
+        };
+        let format = match Format::from_name(req.param("format").unwrap_or("")) {
+            Some(format) => format,
+            None => return send_error(res, StatusCode::NotFound, "formats are json, csv, markdown, todotxt and ical"),
+        };
+        let dry_run = req.query().get("dry_run") == Some("true");
+        let body = match read_body(req) {
+            Ok(body) => body,
+            Err(message) => return send_error(res, StatusCode::BadRequest, &message),
+        };
+        let entries = match transfer::parse(&body, format) {
+            Ok(entries) => entries,
+            Err(e) => return send_error(res, StatusCode::UnprocessableEntity, &e.to_string()),
+        };
+
+        // A dry run only reads, a real import plans against the state it changes
+        let plan = if dry_run {
+            transfer::plan(&store.state(), entries)
+        } else {
+            let mut store = store.lock();
+            let plan = transfer::plan(store.get_state(), entries);
+            plan.apply(&mut store);
+            plan
+        };
+        return send_json(res, StatusCode::Ok, &plan)
+    });
+}
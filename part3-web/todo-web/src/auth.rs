@@ -0,0 +1,388 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use rand::{OsRng, Rng};
+use rustc_serialize::json::{self, Json, ToJson};
+use rustc_serialize::hex::ToHex;
+use rustc_serialize::base64::FromBase64;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use crypto::scrypt::{scrypt_simple, scrypt_check, ScryptParams};
+use nickel::{Request, Response, MiddlewareResult};
+use nickel::status::StatusCode;
+use store::SharedTodoStore;
+use lists::{ ListId, TodoList };
+use template::{ render_with_status, redirect, error_page };
+use user_stores::UserStores;
+use error::{ AppError, lock };
+
+// The cookie our session token lives in
+pub const SESSION_COOKIE: &'static str = "todo_session";
+
+// Sessions are only kept in memory, a restart logs everyone out
+const SESSION_LIFETIME_SECONDS: u64 = 7 * 24 * 60 * 60;
+
+// Calendar apps send the username and password along with every request.
+// Checking a password is slow on purpose, so we remember the ones that
+// worked for this long
+const BASIC_LOGIN_SECONDS: u64 = 15 * 60;
+
+const USERS_FILE: &'static str = "users.json";
+const MIN_PASSWORD_LENGTH: usize = 8;
+const MAX_USERNAME_LENGTH: usize = 32;
+
+// What we keep on disk for every user, the password only as an scrypt hash
+#[derive(Clone, RustcEncodable, RustcDecodable)]
+struct Account {
+    username: String,
+    password_hash: String,
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    InvalidUsername,
+    PasswordTooShort,
+    UsernameTaken,
+    Io(io::Error),
+}
+
+impl AuthError {
+    pub fn message(&self) -> String {
+        match *self {
+            AuthError::InvalidUsername => format!(
+                "Usernames are 1 to {} letters, digits, - or _", MAX_USERNAME_LENGTH),
+            AuthError::PasswordTooShort => format!(
+                "Passwords need at least {} characters", MIN_PASSWORD_LENGTH),
+            AuthError::UsernameTaken => "That username is already taken".to_string(),
+            AuthError::Io(ref e) => format!("Could not save the account: {}", e),
+        }
+    }
+}
+
+struct Session {
+    username: String,
+    expires: Instant,
+}
+
+pub struct Auth {
+    users_path: PathBuf,
+    accounts: Mutex<HashMap<String, Account>>,
+    // Session token -> who it belongs to
+    sessions: Mutex<HashMap<String, Session>>,
+    // SHA-256 of a Basic auth header that had the right password -> who sent it
+    basic_logins: Mutex<HashMap<String, Session>>,
+}
+
+// Usernames double as directory names for the users' todo lists, so we're strict
+pub fn valid_username(username: &str) -> bool {
+    username.len() > 0 && username.len() <= MAX_USERNAME_LENGTH &&
+        username.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// 32 random bytes from the OS, hex encoded
+fn new_token() -> io::Result<String> {
+    let mut rng = try!(OsRng::new());
+    let mut bytes = [0u8; 32];
+    rng.fill_bytes(&mut bytes);
+    Ok(bytes.to_hex())
+}
+
+impl Auth {
+    // Loads the accounts from `dir`/users.json, if there are any yet
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Auth> {
+        try!(fs::create_dir_all(dir.as_ref()));
+        let users_path = dir.as_ref().join(USERS_FILE);
+
+        let mut accounts = HashMap::new();
+        match File::open(&users_path) {
+            Ok(mut file) => {
+                let mut contents = String::new();
+                try!(file.read_to_string(&mut contents));
+                let list: Vec<Account> = try!(json::decode(&contents)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string())));
+                for account in list {
+                    accounts.insert(account.username.clone(), account);
+                }
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+
+        Ok(Auth {
+            users_path: users_path,
+            accounts: Mutex::new(accounts),
+            sessions: Mutex::new(HashMap::new()),
+            basic_logins: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Same write-to-a-temporary-file-and-rename trick as the action log snapshots
+    fn save(&self, accounts: &HashMap<String, Account>) -> io::Result<()> {
+        let list: Vec<&Account> = accounts.values().collect();
+        let contents = try!(json::encode(&list)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string())));
+        let tmp_path = self.users_path.with_extension("json.tmp");
+        {
+            let mut tmp = try!(File::create(&tmp_path));
+            try!(tmp.write_all(contents.as_bytes()));
+            try!(tmp.sync_all());
+        }
+        fs::rename(&tmp_path, &self.users_path)
+    }
+
+    pub fn register(&self, username: &str, password: &str) -> Result<(), AuthError> {
+        if !valid_username(username) {
+            return Err(AuthError::InvalidUsername);
+        }
+        if password.chars().count() < MIN_PASSWORD_LENGTH {
+            return Err(AuthError::PasswordTooShort);
+        }
+
+        if lock(&self.accounts).contains_key(username) {
+            return Err(AuthError::UsernameTaken);
+        }
+        // Hashing is slow on purpose, so like check_password we do it without
+        // the lock and look again once we have it, in case someone else took
+        // the name in the meantime
+        let password_hash = try!(scrypt_simple(password, &ScryptParams::new(14, 8, 1))
+            .map_err(AuthError::Io));
+        let mut accounts = lock(&self.accounts);
+        if accounts.contains_key(username) {
+            return Err(AuthError::UsernameTaken);
+        }
+        accounts.insert(username.to_string(), Account {
+            username: username.to_string(),
+            password_hash: password_hash,
+        });
+        if let Err(e) = self.save(&accounts) {
+            accounts.remove(username);
+            return Err(AuthError::Io(e));
+        }
+        Ok(())
+    }
+
+    fn check_password(&self, username: &str, password: &str) -> bool {
+        let password_hash = match lock(&self.accounts).get(username) {
+            Some(account) => account.password_hash.clone(),
+            None => return false,
+        };
+        // Hashing is slow on purpose, so we do it without holding the lock
+        scrypt_check(password, &password_hash) == Ok(true)
+    }
+
+    // Checks the password and starts a new session, returns its token
+    pub fn login(&self, username: &str, password: &str) -> Option<String> {
+        if !self.check_password(username, password) {
+            return None;
+        }
+
+        let token = match new_token() {
+            Ok(token) => token,
+            Err(_) => return None,
+        };
+        let mut sessions = lock(&self.sessions);
+        // Good moment to forget about sessions that have run out
+        let now = Instant::now();
+        sessions.retain(|_, session| session.expires > now);
+        sessions.insert(token.clone(), Session {
+            username: username.to_string(),
+            expires: now + Duration::from_secs(SESSION_LIFETIME_SECONDS),
+        });
+        Some(token)
+    }
+
+    pub fn logout(&self, token: &str) {
+        lock(&self.sessions).remove(token);
+    }
+
+    // Who a session token belongs to, if it's still valid
+    pub fn username_for(&self, token: &str) -> Option<String> {
+        match lock(&self.sessions).get(token) {
+            Some(session) if session.expires > Instant::now() => Some(session.username.clone()),
+            _ => None,
+        }
+    }
+
+    // The logged in user for a request, from the session cookie or an
+    // `Authorization: Bearer <token>` header for scripts
+    pub fn current_user(&self, req: &Request) -> Option<String> {
+        session_token(req).and_then(|token| self.username_for(&token))
+    }
+
+    // The user from an `Authorization: Basic` header, for calendar apps that
+    // can't hold on to a session
+    pub fn basic_user(&self, req: &Request) -> Option<String> {
+        let credentials = match basic_credentials(req) {
+            Some(credentials) => credentials,
+            None => return None,
+        };
+        let mut sha = Sha256::new();
+        sha.input_str(&credentials);
+        let key = sha.result_str();
+        let now = Instant::now();
+        match lock(&self.basic_logins).get(&key) {
+            Some(login) if login.expires > now => return Some(login.username.clone()),
+            _ => (),
+        }
+
+        let mut parts = credentials.splitn(2, ':');
+        let username = parts.next().unwrap_or("").to_string();
+        if !self.check_password(&username, parts.next().unwrap_or("")) {
+            return None;
+        }
+        let mut logins = lock(&self.basic_logins);
+        logins.retain(|_, login| login.expires > now);
+        logins.insert(key, Session {
+            username: username.clone(),
+            expires: now + Duration::from_secs(BASIC_LOGIN_SECONDS),
+        });
+        Some(username)
+    }
+}
+
+pub fn header_values(req: &Request, name: &str) -> Vec<String> {
+    match req.origin.headers.get_raw(name) {
+        Some(values) => values.iter()
+            .filter_map(|value| String::from_utf8(value.clone()).ok())
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+// Finds our session token in the Cookie header or in an Authorization header
+pub fn session_token(req: &Request) -> Option<String> {
+    for header in header_values(req, "Authorization") {
+        if header.starts_with("Bearer ") {
+            return Some(header["Bearer ".len()..].trim().to_string());
+        }
+    }
+    for header in header_values(req, "Cookie") {
+        for pair in header.split(';') {
+            let mut parts = pair.trim().splitn(2, '=');
+            if parts.next() == Some(SESSION_COOKIE) {
+                return parts.next().map(|value| value.to_string());
+            }
+        }
+    }
+    None
+}
+
+// The username:password in an `Authorization: Basic` header
+fn basic_credentials(req: &Request) -> Option<String> {
+    for header in header_values(req, "Authorization") {
+        if header.starts_with("Basic ") {
+            return header["Basic ".len()..].trim().from_base64().ok()
+                .and_then(|bytes| String::from_utf8(bytes).ok());
+        }
+    }
+    None
+}
+
+// The Set-Cookie header value that logs a browser in, or out with an empty token
+pub fn session_cookie(token: &str) -> String {
+    if token.len() > 0 {
+        format!("{}={}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}",
+                SESSION_COOKIE, token, SESSION_LIFETIME_SECONDS)
+    } else {
+        format!("{}=; Path=/; HttpOnly; SameSite=Lax; Max-Age=0", SESSION_COOKIE)
+    }
+}
+
+// Cookies are sent along with cross-site form posts, so for anything that changes
+// data we check that a browser's Origin header matches the Host we're served on
+pub fn same_origin(req: &Request) -> bool {
+    let origins = header_values(req, "Origin");
+    let origin = match origins.first() {
+        Some(origin) => origin,
+        // Older browsers and scripts don't send one
+        None => return true,
+    };
+    match header_values(req, "Host").first() {
+        Some(host) => origin == &format!("http://{}", host) || origin == &format!("https://{}", host),
+        None => false,
+    }
+}
+
+// What check_access found out about a request
+pub enum Access {
+    // A logged in user, the list the request is for and its store
+    Granted(String, TodoList, SharedTodoStore),
+    // Nobody is logged in
+    Anonymous,
+    // Logged in, but the request came from another site
+    Forbidden,
+    // Logged in, but they don't have the list the request is for
+    Missing,
+    // Logged in, but we couldn't open their todo list
+    Failed(AppError),
+}
+
+// Every route that touches a todo list goes through here. Pass `changes_data`
+// for anything that dispatches, those also get the same origin check. Routes
+// with a :list param get that list, the others the user's default list
+pub fn check_access(req: &Request, auth: &Auth, stores: &UserStores, changes_data: bool) -> Access {
+    let username = match auth.current_user(req) {
+        Some(username) => username,
+        None => return Access::Anonymous,
+    };
+    if changes_data && !same_origin(req) {
+        return Access::Forbidden;
+    }
+    list_access(req, stores, username)
+}
+
+// check_access for CalDAV, where clients log in with Basic auth. There's no
+// same origin check, a browser has to ask before sending PUT, DELETE or
+// PROPFIND to another site and we never say yes
+pub fn check_basic_access(req: &Request, auth: &Auth, stores: &UserStores) -> Access {
+    match auth.current_user(req).or_else(|| auth.basic_user(req)) {
+        Some(username) => list_access(req, stores, username),
+        None => Access::Anonymous,
+    }
+}
+
+// The list in the :list param for the user, or their default list
+fn list_access(req: &Request, stores: &UserStores, username: String) -> Access {
+    let list_id = match req.param("list") {
+        Some(list) => match list.parse::<ListId>() {
+            Ok(list_id) => Some(list_id),
+            Err(_) => return Access::Missing,
+        },
+        None => None,
+    };
+    match stores.get(&username, list_id) {
+        Ok((list, store)) => Access::Granted(username, list, store),
+        Err(AppError::NotFound(_)) => Access::Missing,
+        Err(e) => Access::Failed(e),
+    }
+}
+
+// The HTML response for anything but Access::Granted, anonymous users get
+// the login page along with their 401
+pub fn deny<'mw>(res: Response<'mw>, access: Access) -> MiddlewareResult<'mw> {
+    match access {
+        Access::Anonymous => render_login(res, StatusCode::Unauthorized, "Please log in to see your todos"),
+        Access::Forbidden => error_page(res, AppError::Forbidden("That request came from another site".to_string())),
+        Access::Missing => error_page(res, AppError::NotFound("That list doesn't exist".to_string())),
+        Access::Failed(e) => error_page(res, e),
+        Access::Granted(..) => error_page(res, AppError::Forbidden("Access was granted".to_string())),
+    }
+}
+
+pub fn render_login<'mw>(res: Response<'mw>, status: StatusCode, error: &str) -> MiddlewareResult<'mw> {
+    let mut data = BTreeMap::new();
+    if error.len() > 0 {
+        data.insert("error".to_string(), error.to_json());
+    }
+    render_with_status(res, status, "login", &Json::Object(data))
+}
+
+// Hands the browser its session cookie (or clears it with an empty token)
+// and sends it on to `path`
+pub fn redirect_with_session<'mw>(mut res: Response<'mw>, token: &str, path: &str) -> MiddlewareResult<'mw> {
+    res.headers_mut().set_raw("Set-Cookie", vec![session_cookie(token).into_bytes()]);
+    redirect(res, path)
+}
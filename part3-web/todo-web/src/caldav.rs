@@ -0,0 +1,435 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{ Hash, Hasher };
+use std::sync::Arc;
+use nickel::{Nickel, HttpRouter, Request, Response, MiddlewareResult};
+use nickel::status::StatusCode;
+use hyper::header::Location;
+use hyper::method::Method;
+use api::read_body;
+use auth::{ Auth, Access, check_basic_access, header_values };
+use config;
+use ical;
+use lists::TodoList;
+use store::{ State, TodoStore };
+use store::Action::{ Todos };
+use todo::{ Todo, TodoId, retag };
+use todo::TodoAction::{ Edit, Remove, SetDue, SetPriority, Toggle };
+use transfer::{ self, Entry };
+use user_stores::UserStores;
+
+// Just enough CalDAV (RFC 4791) for calendar apps to sync a list's todos.
+// Point the app at /caldav/lists/1/ and log in with the todo-web username
+// and password, or at /caldav/lists/ to get all of them. Every list is a
+// calendar collection and every todo in it a resource, /caldav/lists/1/3.ics
+// is todo 3. The app can
+//
+//     PROPFIND  the lists and what's in them, with an ETag for every todo
+//     REPORT    a calendar-query for every todo or a calendar-multiget for some
+//     GET       a todo, or the whole list as one calendar
+//     PUT       a new todo, or a changed one like a completed todo
+//     DELETE    a todo, which moves it to the trash
+//
+// Todos keep their own ids, so a todo PUT under a name of the app's choosing
+// shows up at <id>.ics instead. The Location header of the 201 says where
+
+const DAV_COMPLIANCE: &'static str = "1, 3, calendar-access";
+const ALLOWED_METHODS: &'static str = "OPTIONS, GET, PUT, DELETE, PROPFIND, REPORT";
+const ICALENDAR_TYPE: &'static str = "text/calendar; charset=utf-8";
+
+// Where clients find every list
+const HOME_URL: &'static str = "/caldav/lists/";
+
+fn collection_url(list: &TodoList) -> String {
+    format!("/caldav{}/", list.base_url())
+}
+
+fn resource_url(list: &TodoList, todo: &Todo) -> String {
+    format!("{}{}.ics", collection_url(list), todo.id)
+}
+
+fn send_text<'mw>(mut res: Response<'mw>, status: StatusCode, message: &str) -> MiddlewareResult<'mw> {
+    res.set(status);
+    res.headers_mut().set_raw("Content-Type", vec![b"text/plain; charset=utf-8".to_vec()]);
+    res.send(message.to_string())
+}
+
+// A calendar, with the ETag of the todo in it if it's just the one
+fn send_calendar<'mw>(mut res: Response<'mw>, calendar: String, etag: Option<String>) -> MiddlewareResult<'mw> {
+    res.headers_mut().set_raw("Content-Type", vec![ICALENDAR_TYPE.as_bytes().to_vec()]);
+    if let Some(etag) = etag {
+        res.headers_mut().set_raw("ETag", vec![etag.into_bytes()]);
+    }
+    res.send(calendar)
+}
+
+// After a PUT, clients want the new ETag, and the URL of a todo we added
+fn send_stored<'mw>(mut res: Response<'mw>, status: StatusCode, etag: String, location: Option<String>) -> MiddlewareResult<'mw> {
+    res.headers_mut().set_raw("ETag", vec![etag.into_bytes()]);
+    if let Some(location) = location {
+        res.headers_mut().set(Location(location));
+    }
+    send_text(res, status, "")
+}
+
+fn send_options<'mw>(mut res: Response<'mw>) -> MiddlewareResult<'mw> {
+    res.headers_mut().set_raw("DAV", vec![DAV_COMPLIANCE.as_bytes().to_vec()]);
+    res.headers_mut().set_raw("Allow", vec![ALLOWED_METHODS.as_bytes().to_vec()]);
+    send_text(res, StatusCode::Ok, "")
+}
+
+// Calendar apps only ask for a password after a 401 that says how to send it
+fn send_denied<'mw>(mut res: Response<'mw>, access: Access) -> MiddlewareResult<'mw> {
+    match access {
+        Access::Anonymous => {
+            res.headers_mut().set_raw("WWW-Authenticate", vec![b"Basic realm=\"Nickel Todo\"".to_vec()]);
+            send_text(res, StatusCode::Unauthorized, "log in with your username and password")
+        },
+        Access::Forbidden => send_text(res, StatusCode::Forbidden, "request came from another site"),
+        Access::Missing => send_text(res, StatusCode::NotFound, "list not found"),
+        Access::Failed(e) => send_text(res, StatusCode::InternalServerError, &format!("could not open todo list: {}", e)),
+        Access::Granted(..) => send_text(res, StatusCode::InternalServerError, "access was granted"),
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace("&", "&amp;").replace("<", "&lt;").replace(">", "&gt;").replace("\"", "&quot;")
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&apos;", "'").replace("&amp;", "&")
+}
+
+// The text of every href element in a request body. Clients pick their own
+// prefix for the DAV: namespace, so <D:href> and <href> both count
+fn hrefs(body: &str) -> Vec<String> {
+    body.split('<').filter_map(|tag| {
+        let mut parts = tag.splitn(2, '>');
+        let name = parts.next().and_then(|name| name.split_whitespace().next()).unwrap_or("");
+        if name == "href" || name.ends_with(":href") {
+            parts.next().map(|text| unescape_xml(text.trim()))
+        } else {
+            None
+        }
+    }).collect()
+}
+
+// The todo id in a resource href like /caldav/lists/1/3.ics
+fn todo_id_in(href: &str) -> Option<TodoId> {
+    href.split('/').filter(|part| part.len() > 0).last()
+        .and_then(|name| if name.ends_with(".ics") { Some(&name[..name.len() - 4]) } else { None })
+        .and_then(|id| id.parse().ok())
+}
+
+// What a PROPFIND or REPORT says about one href, every property we know of
+// whatever the client asked for
+fn response(href: &str, properties: &[String]) -> String {
+    format!("<d:response><d:href>{}</d:href><d:propstat><d:prop>{}</d:prop>\
+             <d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response>",
+            escape_xml(href), properties.concat())
+}
+
+fn not_found_response(href: &str) -> String {
+    format!("<d:response><d:href>{}</d:href><d:status>HTTP/1.1 404 Not Found</d:status></d:response>",
+            escape_xml(href))
+}
+
+fn send_multistatus<'mw>(mut res: Response<'mw>, responses: Vec<String>) -> MiddlewareResult<'mw> {
+    res.set(StatusCode::MultiStatus);
+    res.headers_mut().set_raw("Content-Type", vec![b"application/xml; charset=utf-8".to_vec()]);
+    res.send(format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+                      <d:multistatus xmlns:d=\"DAV:\" xmlns:c=\"urn:ietf:params:xml:ns:caldav\" \
+                      xmlns:cs=\"http://calendarserver.org/ns/\">{}</d:multistatus>\n",
+                     responses.concat()))
+}
+
+// Where the user's lists are, for clients that go looking
+fn home_properties() -> Vec<String> {
+    vec![
+        format!("<d:current-user-principal><d:href>{}</d:href></d:current-user-principal>", HOME_URL),
+        format!("<c:calendar-home-set><d:href>{}</d:href></c:calendar-home-set>", HOME_URL),
+    ]
+}
+
+// A list is a calendar that only holds VTODOs. The ctag changes whenever a
+// todo in it does, so clients know when to look for changes
+fn collection_properties(list: &TodoList, state: &State) -> Vec<String> {
+    let mut hasher = DefaultHasher::new();
+    list.name.hash(&mut hasher);
+    for todo in state.todos.iter().filter(|todo| !todo.deleted) {
+        ical::etag(todo).hash(&mut hasher);
+    }
+    let mut properties = vec![
+        "<d:resourcetype><d:collection/><c:calendar/></d:resourcetype>".to_string(),
+        format!("<d:displayname>{}</d:displayname>", escape_xml(&list.name)),
+        "<c:supported-calendar-component-set><c:comp name=\"VTODO\"/></c:supported-calendar-component-set>".to_string(),
+        format!("<cs:getctag>\"{:016x}\"</cs:getctag>", hasher.finish()),
+    ];
+    properties.extend(home_properties());
+    properties
+}
+
+fn todo_properties(list: &TodoList, todo: &Todo, with_data: bool) -> Vec<String> {
+    let mut properties = vec![
+        "<d:resourcetype/>".to_string(),
+        format!("<d:getetag>{}</d:getetag>", escape_xml(&ical::etag(todo))),
+        "<d:getcontenttype>text/calendar; charset=utf-8; component=VTODO</d:getcontenttype>".to_string(),
+    ];
+    if with_data {
+        properties.push(format!("<c:calendar-data>{}</c:calendar-data>",
+                                escape_xml(&ical::calendar(list, &[todo]))));
+    }
+    properties
+}
+
+// Depth: 0 is just the collection, anything else gets what's in it too
+fn wants_members(req: &Request) -> bool {
+    header_values(req, "Depth").first().map_or(true, |depth| depth.trim() != "0")
+}
+
+fn find_todo<'a>(state: &'a State, req: &Request) -> Option<&'a Todo> {
+    req.param("id").and_then(|id| id.parse::<TodoId>().ok())
+        .and_then(|todo_id| state.todos.iter().find(|todo| todo.id == todo_id && !todo.deleted))
+}
+
+// If-Match and If-None-Match let a client make sure nobody else changed the
+// todo since it last looked, and that a new todo doesn't replace one
+fn preconditions_hold(req: &Request, todo: Option<&Todo>) -> bool {
+    let etag = todo.map(ical::etag);
+    let matches = |values: Vec<String>| values.iter()
+        .flat_map(|value| value.split(',').map(|tag| tag.trim().to_string()).collect::<Vec<_>>())
+        .any(|tag| (tag == "*" && etag.is_some()) || Some(&tag) == etag.as_ref());
+    let if_match = header_values(req, "If-Match");
+    let if_none_match = header_values(req, "If-None-Match");
+    (if_match.is_empty() || matches(if_match)) && (if_none_match.is_empty() || !matches(if_none_match))
+}
+
+// A PUT has to be one todo we can keep
+fn read_todo(body: &str) -> Result<Entry, (StatusCode, String)> {
+    let mut entries = match ical::parse(body) {
+        Ok(entries) => entries,
+        Err(e) => return Err((StatusCode::BadRequest, e.to_string())),
+    };
+    if entries.len() != 1 {
+        return Err((StatusCode::Forbidden, "send one VTODO, only todos can be stored here".to_string()));
+    }
+    let entry = entries.remove(0);
+    let max_title_length = config::limits().max_title_length;
+    if entry.title.len() == 0 {
+        return Err((StatusCode::UnprocessableEntity, "the VTODO needs a SUMMARY".to_string()));
+    }
+    if entry.title.chars().count() > max_title_length {
+        return Err((StatusCode::UnprocessableEntity,
+                    format!("SUMMARY can't be longer than {} characters", max_title_length)));
+    }
+    Ok(entry)
+}
+
+// Brings an existing todo in line with what the client sent, in one batch so
+// a client syncing a todo is one undo step and one event
+fn update(store: &mut TodoStore, todo: &Todo, entry: &Entry) {
+    store.batch(|store| {
+        if todo.completed != entry.completed {
+            store.dispatch( Todos( Toggle(todo.id) ) );
+        }
+        if todo.due != entry.due {
+            store.dispatch( Todos( SetDue(todo.id, entry.due.clone()) ) );
+        }
+        if todo.priority != entry.priority {
+            store.dispatch( Todos( SetPriority(todo.id, entry.priority) ) );
+        }
+        for action in retag(todo, &entry.tags) {
+            store.dispatch( Todos(action) );
+        }
+        if todo.title != entry.title {
+            store.dispatch( Todos( Edit(todo.id, entry.title.clone()) ) );
+        }
+    })
+}
+
+fn propfind() -> Method {
+    Method::Extension("PROPFIND".to_string())
+}
+
+pub fn mount(server: &mut Nickel, auth: &Arc<Auth>, stores: &Arc<UserStores>) {
+    // Clients check what we support with an OPTIONS first
+    for path in &["/caldav/lists", "/caldav/lists/:list", "/caldav/lists/:list/:id.ics"] {
+        server.add_route(Method::Options, *path, middleware! { |_req, res|
+            return send_options(res)
+        });
+    }
+
+    // PROPFIND /caldav/lists/ is the calendar home, with every list in it
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+    server.add_route(propfind(), "/caldav/lists", middleware! { |req, res|
+        let username = match check_basic_access(req, &auth_clone, &stores_clone) {
+            Access::Granted(username, _, _) => username,
+            denied => return send_denied(res, denied),
+        };
+        let mut home = vec!["<d:resourcetype><d:collection/></d:resourcetype>".to_string()];
+        home.extend(home_properties());
+        let mut responses = vec![response(HOME_URL, &home)];
+        if wants_members(req) {
+            let lists = match stores_clone.lists(&username) {
+                Ok(lists) => lists,
+                Err(e) => return send_text(res, e.status(), &e.to_string()),
+            };
+            for list in lists.iter().filter(|list| !list.archived) {
+                let store = match stores_clone.get(&username, Some(list.id)) {
+                    Ok((_, store)) => store,
+                    Err(e) => return send_text(res, e.status(), &e.to_string()),
+                };
+                let properties = collection_properties(list, &store.state());
+                responses.push(response(&collection_url(list), &properties));
+            }
+        }
+        return send_multistatus(res, responses)
+    });
+
+    // PROPFIND /caldav/lists/1/ describes the list, and with Depth: 1 every todo in it
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+    server.add_route(propfind(), "/caldav/lists/:list", middleware! { |req, res|
+        let (list, store) = match check_basic_access(req, &auth_clone, &stores_clone) {
+            Access::Granted(_, list, store) => (list, store),
+            denied => return send_denied(res, denied),
+        };
+        let state = store.state();
+        let mut responses = vec![response(&collection_url(&list), &collection_properties(&list, &state))];
+        if wants_members(req) {
+            for todo in state.todos.iter().filter(|todo| !todo.deleted) {
+                responses.push(response(&resource_url(&list, todo), &todo_properties(&list, todo, false)));
+            }
+        }
+        return send_multistatus(res, responses)
+    });
+
+    // PROPFIND /caldav/lists/1/3.ics is the ETag of a single todo
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+    server.add_route(propfind(), "/caldav/lists/:list/:id.ics", middleware! { |req, res|
+        let (list, store) = match check_basic_access(req, &auth_clone, &stores_clone) {
+            Access::Granted(_, list, store) => (list, store),
+            denied => return send_denied(res, denied),
+        };
+        let state = store.state();
+        return match find_todo(&state, req) {
+            Some(todo) => send_multistatus(res, vec![response(&resource_url(&list, todo), &todo_properties(&list, todo, false))]),
+            None => send_text(res, StatusCode::NotFound, "todo not found"),
+        }
+    });
+
+    // REPORT /caldav/lists/1/ with a calendar-multiget sends the todos it has
+    // hrefs for, a calendar-query gets every todo. Either way with the VTODO
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+    server.add_route(Method::Extension("REPORT".to_string()), "/caldav/lists/:list", middleware! { |req, res|
+        let (list, store) = match check_basic_access(req, &auth_clone, &stores_clone) {
+            Access::Granted(_, list, store) => (list, store),
+            denied => return send_denied(res, denied),
+        };
+        let body = match read_body(req) {
+            Ok(body) => body,
+            Err(message) => return send_text(res, StatusCode::BadRequest, &message),
+        };
+        let state = store.state();
+        let todos: Vec<&Todo> = state.todos.iter().filter(|todo| !todo.deleted).collect();
+        let mut responses = Vec::new();
+        if body.contains("calendar-multiget") {
+            for href in hrefs(&body) {
+                match todo_id_in(&href).and_then(|todo_id| todos.iter().find(|todo| todo.id == todo_id)) {
+                    Some(todo) => responses.push(response(&href, &todo_properties(&list, todo, true))),
+                    None => responses.push(not_found_response(&href)),
+                }
+            }
+        } else if !body.contains("\"VEVENT\"") || body.contains("\"VTODO\"") {
+            // A query for events alone finds nothing, there are only todos here
+            for todo in todos {
+                responses.push(response(&resource_url(&list, todo), &todo_properties(&list, todo, true)));
+            }
+        }
+        return send_multistatus(res, responses)
+    });
+
+    // GET /caldav/lists/1/ is the whole list as one calendar, for apps that
+    // subscribe to a calendar instead of syncing it
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+    server.get("/caldav/lists/:list", middleware! { |req, res|
+        let (list, store) = match check_basic_access(req, &auth_clone, &stores_clone) {
+            Access::Granted(_, list, store) => (list, store),
+            denied => return send_denied(res, denied),
+        };
+        let calendar = transfer::export(&list, &store.state().todos, transfer::Format::ICalendar);
+        return send_calendar(res, calendar, None)
+    });
+
+    // GET /caldav/lists/1/3.ics is todo 3 as a calendar with one VTODO
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+    server.get("/caldav/lists/:list/:id.ics", middleware! { |req, res|
+        let (list, store) = match check_basic_access(req, &auth_clone, &stores_clone) {
+            Access::Granted(_, list, store) => (list, store),
+            denied => return send_denied(res, denied),
+        };
+        let state = store.state();
+        let todo = match find_todo(&state, req) {
+            Some(todo) => todo,
+            None => return send_text(res, StatusCode::NotFound, "todo not found"),
+        };
+        return send_calendar(res, ical::calendar(&list, &[todo]), Some(ical::etag(todo)))
+    });
+
+    // PUT /caldav/lists/1/3.ics changes todo 3 to match the VTODO, which is how
+    // clients complete todos. A PUT anywhere else in the list adds a new todo
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+    server.put("/caldav/lists/:list/:id.ics", middleware! { |req, res|
+        let (list, store) = match check_basic_access(req, &auth_clone, &stores_clone) {
+            Access::Granted(_, list, store) => (list, store),
+            denied => return send_denied(res, denied),
+        };
+        let body = match read_body(req) {
+            Ok(body) => body,
+            Err(message) => return send_text(res, StatusCode::BadRequest, &message),
+        };
+        let entry = match read_todo(&body) {
+            Ok(entry) => entry,
+            Err((status, message)) => return send_text(res, status, &message),
+        };
+
+        let mut store = store.lock();
+        let existing = find_todo(store.get_state(), req).cloned();
+        if !preconditions_hold(req, existing.as_ref()) {
+            return send_text(res, StatusCode::PreconditionFailed, "the todo has changed");
+        }
+        let (status, todo_id) = match existing {
+            Some(todo) => {
+                update(&mut store, &todo, &entry);
+                (StatusCode::NoContent, todo.id)
+            },
+            None => match transfer::add(&mut store, &entry) {
+                Some(todo_id) => (StatusCode::Created, todo_id),
+                None => return send_text(res, StatusCode::UnprocessableEntity, "todo was not added"),
+            },
+        };
+        let todo = match store.get_state().todos.iter().find(|todo| todo.id == todo_id) {
+            Some(todo) => todo,
+            None => return send_text(res, StatusCode::NotFound, "todo not found"),
+        };
+        let location = if status == StatusCode::Created { Some(resource_url(&list, todo)) } else { None };
+        return send_stored(res, status, ical::etag(todo), location)
+    });
+
+    // DELETE /caldav/lists/1/3.ics moves todo 3 to the trash
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+    server.delete("/caldav/lists/:list/:id.ics", middleware! { |req, res|
+        let store = match check_basic_access(req, &auth_clone, &stores_clone) {
+            Access::Granted(_, _, store) => store,
+            denied => return send_denied(res, denied),
+        };
+        let mut store = store.lock();
+        let todo = match find_todo(store.get_state(), req).cloned() {
+            Some(todo) => todo,
+            None => return send_text(res, StatusCode::NotFound, "todo not found"),
+        };
+        if !preconditions_hold(req, Some(&todo)) {
+            return send_text(res, StatusCode::PreconditionFailed, "the todo has changed");
+        }
+        store.dispatch( Todos( Remove(todo.id) ) );
+        return send_text(res, StatusCode::NoContent, "")
+    });
+}
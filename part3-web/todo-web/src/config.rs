@@ -0,0 +1,363 @@
+use std::env;
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::Duration;
+use rustc_serialize::Decodable;
+use toml;
+use error::{ read, write };
+use history::DEFAULT_HISTORY_LIMIT;
+use storage::Backend;
+
+// Read when there's no --config flag or TODO_WEB_CONFIG variable, it's fine if it doesn't exist
+const DEFAULT_CONFIG_FILE: &'static str = "todo-web.toml";
+
+// Every environment variable is this plus the setting's name in upper case,
+// so listen is TODO_WEB_LISTEN and max_title_length is TODO_WEB_MAX_TITLE_LENGTH
+const ENV_PREFIX: &'static str = "TODO_WEB_";
+
+// Threads hyper handles requests on when worker_threads isn't set
+const DEFAULT_WORKER_THREADS: usize = 16;
+
+// Limits on what a single request or user can do
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    // The longest todo title the validate middleware lets through
+    pub max_title_length: usize,
+    // Every open /events stream keeps a worker thread busy, so this stays at
+    // most half of worker_threads to leave the rest for page requests
+    pub max_event_streams: usize,
+    // The largest JSON body the API reads
+    pub max_body_bytes: usize,
+}
+
+#[derive(Clone, Debug, RustcEncodable)]
+pub struct Config {
+    // Address and port to listen on
+    pub listen: String,
+    // Where the *.tpl files are
+    pub template_dir: PathBuf,
+    // Files in here are served as they are, if it's set
+    pub static_dir: Option<PathBuf>,
+    // Accounts and every user's action log
+    pub data_dir: PathBuf,
+    // Recompile the templates whenever they change
+    pub dev: bool,
+    // Threads hyper handles requests on
+    pub worker_threads: usize,
+    pub max_title_length: usize,
+    // Half of worker_threads when it isn't set
+    pub max_event_streams: Option<usize>,
+    pub max_body_bytes: usize,
+    // How many states each user can undo back through
+    pub history_limit: usize,
+    // Deleted todos are purged after this many days in the trash, 0 keeps them forever
+    pub trash_retention_days: usize,
+    // Where every list is kept, file or sqlite. Lists saved with the other
+    // one are migrated the first time they're opened
+    pub storage: String,
+}
+
+// The same settings with everything optional, for the layers that only
+// change some of them
+#[derive(Default, RustcDecodable)]
+struct PartialConfig {
+    listen: Option<String>,
+    template_dir: Option<PathBuf>,
+    static_dir: Option<PathBuf>,
+    data_dir: Option<PathBuf>,
+    dev: Option<bool>,
+    worker_threads: Option<usize>,
+    max_title_length: Option<usize>,
+    max_event_streams: Option<usize>,
+    max_body_bytes: Option<usize>,
+    history_limit: Option<usize>,
+    trash_retention_days: Option<usize>,
+    storage: Option<String>,
+}
+
+// What to do once the command line has been read
+pub enum Command {
+    Serve(Config),
+    PrintConfig(Config),
+    Help,
+}
+
+pub const USAGE: &'static str = "Usage: todo-web [options]
+
+Settings are read from a TOML file, then TODO_WEB_* environment variables,
+then these flags, each one overriding the ones before it.
+
+Options:
+    --config <file>             TOML file to read, default ./todo-web.toml
+    --listen <address>          Address to listen on, default 0.0.0.0:3000
+    --template-dir <dir>        Where the *.tpl templates are
+    --static-dir <dir>          Serve the files in this directory
+    --data-dir <dir>            Where accounts and todo lists are saved
+    --dev                       Recompile templates when they change
+    --max-title-length <n>      Longest todo title allowed
+    --worker-threads <n>        Threads handling requests, default 16
+    --max-event-streams <n>     Most /events streams open at once, default
+                                half of the worker threads
+    --max-body-bytes <n>        Largest JSON request body the API reads
+    --history-limit <n>         How many states every user can undo
+    --trash-retention-days <n>  Purge deleted todos after n days, 0 never does
+    --storage <backend>         Keep lists in file (the default) or sqlite
+    --print-config              Print the resulting settings as TOML and exit
+    --help                      Print this and exit";
+
+impl Default for Config {
+    // The templates ship with the source, so by default we look for them (and
+    // keep the data) next to Cargo.toml instead of in the working directory,
+    // which works wherever the server is started from. A server that runs
+    // somewhere without the source sets template_dir and data_dir
+    fn default() -> Config {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        Config {
+            listen: "0.0.0.0:3000".to_string(),
+            template_dir: root.join("src"),
+            static_dir: None,
+            data_dir: root.join("data"),
+            dev: false,
+            max_title_length: 200,
+            worker_threads: DEFAULT_WORKER_THREADS,
+            max_event_streams: None,
+            max_body_bytes: 64 * 1024,
+            history_limit: DEFAULT_HISTORY_LIMIT,
+            trash_retention_days: 0,
+            storage: Backend::File.name().to_string(),
+        }
+    }
+}
+
+impl Config {
+    pub fn limits(&self) -> Limits {
+        Limits {
+            max_title_length: self.max_title_length,
+            max_event_streams: self.max_event_streams.unwrap_or(self.worker_threads / 2),
+            max_body_bytes: self.max_body_bytes,
+        }
+    }
+
+    // How long deleted todos stay in the trash, None keeps them forever. Also
+    // None when there are too many days to count in seconds, validate() turns
+    // those down
+    pub fn trash_retention(&self) -> Option<Duration> {
+        match self.trash_retention_days {
+            0 => None,
+            days => (days as u64).checked_mul(24 * 60 * 60).map(Duration::from_secs),
+        }
+    }
+
+    fn apply(&mut self, layer: PartialConfig) {
+        if let Some(listen) = layer.listen { self.listen = listen; }
+        if let Some(template_dir) = layer.template_dir { self.template_dir = template_dir; }
+        if let Some(static_dir) = layer.static_dir { self.static_dir = Some(static_dir); }
+        if let Some(data_dir) = layer.data_dir { self.data_dir = data_dir; }
+        if let Some(dev) = layer.dev { self.dev = dev; }
+        if let Some(max_title_length) = layer.max_title_length { self.max_title_length = max_title_length; }
+        if let Some(worker_threads) = layer.worker_threads { self.worker_threads = worker_threads; }
+        if let Some(max_event_streams) = layer.max_event_streams { self.max_event_streams = Some(max_event_streams); }
+        if let Some(max_body_bytes) = layer.max_body_bytes { self.max_body_bytes = max_body_bytes; }
+        if let Some(history_limit) = layer.history_limit { self.history_limit = history_limit; }
+        if let Some(trash_retention_days) = layer.trash_retention_days { self.trash_retention_days = trash_retention_days; }
+        if let Some(storage) = layer.storage { self.storage = storage; }
+    }
+
+    // Every problem with the settings, so they can all be fixed in one go
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+        match self.listen.to_socket_addrs() {
+            Ok(mut addresses) => if addresses.next().is_none() {
+                problems.push(format!("listen address {} doesn't resolve to anything", self.listen));
+            },
+            Err(e) => problems.push(format!("listen address {} is invalid: {}", self.listen, e)),
+        }
+        if !self.template_dir.join("todos.tpl").is_file() {
+            problems.push(format!("template_dir {} has no todos.tpl, point it at the directory with the templates",
+                                  self.template_dir.display()));
+        }
+        if let Some(ref static_dir) = self.static_dir {
+            if !static_dir.is_dir() {
+                problems.push(format!("static_dir {} is not a directory", static_dir.display()));
+            }
+        }
+        if self.data_dir.exists() && !self.data_dir.is_dir() {
+            problems.push(format!("data_dir {} is not a directory", self.data_dir.display()));
+        }
+        if Backend::from_name(&self.storage).is_none() {
+            problems.push(format!("storage has to be file or sqlite, got {}", self.storage));
+        }
+        if self.trash_retention_days > 0 && self.trash_retention().is_none() {
+            problems.push(format!("trash_retention_days {} is too many days", self.trash_retention_days));
+        }
+        // Streams never get more than half of the workers, so pages still load
+        // with every stream open
+        let max_event_streams = self.limits().max_event_streams;
+        if self.worker_threads < 2 {
+            problems.push("worker_threads has to be at least 2".to_string());
+        } else if max_event_streams == 0 {
+            problems.push("max_event_streams has to be at least 1".to_string());
+        } else if max_event_streams > self.worker_threads / 2 {
+            problems.push(format!("max_event_streams can be at most {}, half of worker_threads", self.worker_threads / 2));
+        }
+        let limits = [("max_title_length", self.max_title_length),
+                      ("max_body_bytes", self.max_body_bytes),
+                      ("history_limit", self.history_limit)];
+        for &(name, value) in &limits {
+            if value == 0 {
+                problems.push(format!("{} has to be at least 1", name));
+            }
+        }
+
+        if problems.is_empty() { Ok(()) } else { Err(problems) }
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::encode_str(self)
+    }
+}
+
+// Relative paths in the config file are relative to the file, not to
+// wherever the server happens to be started from
+fn relative_to(dir: &Path, path: Option<PathBuf>) -> Option<PathBuf> {
+    path.map(|path| if path.is_relative() { dir.join(path) } else { path })
+}
+
+fn read_file(path: &Path, required: bool) -> Result<PartialConfig, String> {
+    let mut contents = String::new();
+    match File::open(path) {
+        Ok(mut file) => if let Err(e) = file.read_to_string(&mut contents) {
+            return Err(format!("Could not read {}: {}", path.display(), e));
+        },
+        Err(ref e) if !required && e.kind() == io::ErrorKind::NotFound => return Ok(PartialConfig::default()),
+        Err(e) => return Err(format!("Could not open {}: {}", path.display(), e)),
+    }
+
+    let mut parser = toml::Parser::new(&contents);
+    let table = match parser.parse() {
+        Some(table) => table,
+        None => {
+            let messages: Vec<String> = parser.errors.iter().map(|error| {
+                let (line, column) = parser.to_linecol(error.lo);
+                format!("{}:{}:{}: {}", path.display(), line + 1, column + 1, error.desc)
+            }).collect();
+            return Err(messages.join("\n"));
+        },
+    };
+    let mut decoder = toml::Decoder::new(toml::Value::Table(table));
+    let mut layer = try!(PartialConfig::decode(&mut decoder)
+        .map_err(|e| format!("{}: {}", path.display(), e)));
+
+    let dir = path.parent().unwrap_or(Path::new("."));
+    layer.template_dir = relative_to(dir, layer.template_dir);
+    layer.static_dir = relative_to(dir, layer.static_dir);
+    layer.data_dir = relative_to(dir, layer.data_dir);
+    Ok(layer)
+}
+
+fn parse_number(name: &str, value: &str) -> Result<usize, String> {
+    value.parse().map_err(|_| format!("{} has to be a number, got {}", name, value))
+}
+
+fn parse_bool(name: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "1" | "true" | "yes" => Ok(true),
+        "0" | "false" | "no" | "" => Ok(false),
+        _ => Err(format!("{} has to be true or false, got {}", name, value)),
+    }
+}
+
+// Sets one setting from its name and a string, shared by the
+// environment variables and the command line flags
+fn set(layer: &mut PartialConfig, name: &str, value: &str) -> Result<(), String> {
+    match name {
+        "listen" => layer.listen = Some(value.to_string()),
+        "template_dir" => layer.template_dir = Some(PathBuf::from(value)),
+        "static_dir" => layer.static_dir = Some(PathBuf::from(value)),
+        "data_dir" => layer.data_dir = Some(PathBuf::from(value)),
+        "dev" => layer.dev = Some(try!(parse_bool(name, value))),
+        "max_title_length" => layer.max_title_length = Some(try!(parse_number(name, value))),
+        "worker_threads" => layer.worker_threads = Some(try!(parse_number(name, value))),
+        "max_event_streams" => layer.max_event_streams = Some(try!(parse_number(name, value))),
+        "max_body_bytes" => layer.max_body_bytes = Some(try!(parse_number(name, value))),
+        "history_limit" => layer.history_limit = Some(try!(parse_number(name, value))),
+        "trash_retention_days" => layer.trash_retention_days = Some(try!(parse_number(name, value))),
+        "storage" => layer.storage = Some(value.to_string()),
+        _ => return Err(format!("Unknown setting {}", name)),
+    }
+    Ok(())
+}
+
+const SETTINGS: [&'static str; 12] = ["listen", "template_dir", "static_dir", "data_dir", "dev",
+                                      "worker_threads", "max_title_length", "max_event_streams",
+                                      "max_body_bytes", "history_limit", "trash_retention_days",
+                                      "storage"];
+
+fn from_env() -> Result<PartialConfig, String> {
+    let mut layer = PartialConfig::default();
+    for name in SETTINGS.iter() {
+        if let Ok(value) = env::var(format!("{}{}", ENV_PREFIX, name.to_uppercase())) {
+            try!(set(&mut layer, name, &value));
+        }
+    }
+    Ok(layer)
+}
+
+// Builds the settings from defaults, the config file, the environment and
+// finally `args`, which shouldn't include the program name
+pub fn from_args<I: Iterator<Item = String>>(args: I) -> Result<Command, String> {
+    let mut flags = PartialConfig::default();
+    let mut config_file = env::var(format!("{}CONFIG", ENV_PREFIX)).ok().map(PathBuf::from);
+    let mut print_config = false;
+
+    let mut args = args;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--help" | "-h" => return Ok(Command::Help),
+            "--print-config" => print_config = true,
+            "--dev" => flags.dev = Some(true),
+            flag if flag.starts_with("--") => {
+                // --max-title-length sets max_title_length and so on
+                let name = flag[2..].replace("-", "_");
+                let value = try!(args.next().ok_or(format!("{} needs a value", flag)));
+                if name == "config" {
+                    config_file = Some(PathBuf::from(value));
+                } else if SETTINGS.contains(&name.as_str()) {
+                    try!(set(&mut flags, &name, &value));
+                } else {
+                    return Err(format!("Unknown option {}, see --help", flag));
+                }
+            },
+            _ => return Err(format!("Unexpected argument {}, see --help", arg)),
+        }
+    }
+
+    let mut config = Config::default();
+    // A config file that was asked for has to be there
+    let file = match config_file {
+        Some(ref path) => try!(read_file(path, true)),
+        None => try!(read_file(Path::new(DEFAULT_CONFIG_FILE), false)),
+    };
+    config.apply(file);
+    config.apply(try!(from_env()));
+    config.apply(flags);
+
+    if print_config { Ok(Command::PrintConfig(config)) } else { Ok(Command::Serve(config)) }
+}
+
+lazy_static! {
+    // The limits are checked deep inside middleware and handlers, so they're
+    // set once at startup and read from here
+    static ref LIMITS: RwLock<Limits> = RwLock::new(Config::default().limits());
+}
+
+pub fn set_limits(limits: Limits) {
+    *write(&LIMITS) = limits;
+}
+
+pub fn limits() -> Limits {
+    *read(&LIMITS)
+}
@@ -0,0 +1,78 @@
+use std::fmt;
+use std::io;
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
+use nickel::status::StatusCode;
+
+// Everything that can go wrong while handling a request. Each one knows which
+// status code it should be sent with
+#[derive(Debug)]
+pub enum AppError {
+    // The request itself is broken, like a form body we can't read
+    BadRequest(String),
+    NotFound(String),
+    // The request is fine but what it asks for isn't allowed, like an empty title
+    Unprocessable(String),
+    Forbidden(String),
+    // A template failed to render
+    Template(String),
+    // Reading or writing the data directory failed
+    Io(io::Error),
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match *self {
+            AppError::BadRequest(_) => StatusCode::BadRequest,
+            AppError::NotFound(_) => StatusCode::NotFound,
+            AppError::Unprocessable(_) => StatusCode::UnprocessableEntity,
+            AppError::Forbidden(_) => StatusCode::Forbidden,
+            AppError::Template(_) | AppError::Io(_) => StatusCode::InternalServerError,
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AppError::BadRequest(ref message) |
+            AppError::NotFound(ref message) |
+            AppError::Unprocessable(ref message) |
+            AppError::Forbidden(ref message) => write!(f, "{}", message),
+            AppError::Template(ref message) => write!(f, "Could not render the page: {}", message),
+            AppError::Io(ref e) => write!(f, "Could not read or write your data: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for AppError {
+    fn from(e: io::Error) -> AppError {
+        AppError::Io(e)
+    }
+}
+
+// Poisoned locks stay poisoned, this makes sure we only say so once
+static POISON_REPORTED: AtomicBool = AtomicBool::new(false);
+
+// A thread that panics while holding a lock poisons it, and a plain lock().unwrap()
+// would then panic in every request after it. A panicking reducer leaves the
+// store as it was, the new state is only swapped in and the action only saved
+// once the reducer has returned. Anything else a panic can interrupt, like a
+// middleware re-dispatching or a listener halfway through, may have done part
+// of its work, which is still better than taking down every request after it
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    mutex.lock().unwrap_or_else(|poisoned| {
+        if !POISON_REPORTED.swap(true, Ordering::SeqCst) {
+            println!("Recovered a lock poisoned by a panicking request, carrying on with it");
+        }
+        poisoned.into_inner()
+    })
+}
+
+pub fn read<T>(rwlock: &RwLock<T>) -> RwLockReadGuard<T> {
+    rwlock.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+pub fn write<T>(rwlock: &RwLock<T>) -> RwLockWriteGuard<T> {
+    rwlock.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
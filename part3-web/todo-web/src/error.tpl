@@ -0,0 +1,32 @@
+<!DOCTYPE html>
+<html>
+  <head>
+    <meta charset="utf-8">
+    <title>Nickel Todo - {{status}}</title>
+    <link rel="stylesheet" href="http://todomvc.com/examples/react/node_modules/todomvc-common/base.css">
+    <link rel="stylesheet" href="http://todomvc.com/examples/react/node_modules/todomvc-app-css/index.css">
+    <style type="text/css">
+    .problem {
+      padding: 16px;
+    }
+    .problem h2 {
+      margin: 0 0 10px;
+      font-size: 18px;
+      font-weight: 400;
+      color: #af2f2f;
+    }
+    </style>
+  </head>
+  <body>
+    <section class="todoapp">
+      <header class="header">
+        <h1>todos</h1>
+      </header>
+      <div class="problem">
+        <h2>{{status}}</h2>
+        <p>{{message}}</p>
+        <p><a href="/">Back to your todos</a></p>
+      </div>
+    </section>
+  </body>
+</html>
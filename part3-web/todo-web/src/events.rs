@@ -0,0 +1,91 @@
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
+use rustc_serialize::json::ToJson;
+use nickel::{Response, MiddlewareResult, Halt};
+use nickel::status::StatusCode;
+use hyper::header::{ContentType, CacheControl, CacheDirective};
+use hyper::mime::{Mime, TopLevel, SubLevel};
+use config;
+use store::{ State, SharedTodoStore };
+
+// Without any traffic we can't tell if the browser has gone away, so we send
+// a comment line every so often. A failed write ends the stream
+const KEEP_ALIVE_SECONDS: u64 = 15;
+
+// Every open stream keeps one of the server's worker threads busy, so we cap
+// them at max_event_streams to leave threads over for normal requests
+static OPEN_STREAMS: AtomicUsize = AtomicUsize::new(0);
+
+// Decrements the open stream count however the stream ends
+struct StreamSlot;
+
+impl StreamSlot {
+    fn take() -> Option<StreamSlot> {
+        if OPEN_STREAMS.fetch_add(1, Ordering::SeqCst) >= config::limits().max_event_streams {
+            OPEN_STREAMS.fetch_sub(1, Ordering::SeqCst);
+            None
+        } else {
+            Some(StreamSlot)
+        }
+    }
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        OPEN_STREAMS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn write_event<W: Write>(stream: &mut W, data: &str) -> ::std::io::Result<()> {
+    try!(write!(stream, "data: {}\n\n", data));
+    stream.flush()
+}
+
+// Streams the State as Server-Sent Events, first the current one and then a new
+// one after every change, until the browser disconnects
+pub fn stream<'mw>(mut res: Response<'mw>, store: &SharedTodoStore) -> MiddlewareResult<'mw> {
+    let _slot = match StreamSlot::take() {
+        Some(slot) => slot,
+        None => {
+            res.set(StatusCode::ServiceUnavailable);
+            return res.send("Too many open event streams");
+        },
+    };
+
+    // The listener runs while whoever dispatched holds the store lock, so it
+    // only tells us something changed. We pick up the published state and turn
+    // it into JSON here, without the lock
+    let (sender, receiver) = mpsc::channel();
+    let _subscription = store.lock().subscribe(Box::new(move |_: &State| {
+        let _ = sender.send(());
+    }));
+    let current = store.state().to_json().to_string();
+
+    res.headers_mut().set(ContentType(Mime(TopLevel::Text, SubLevel::Ext("event-stream".to_string()), vec![])));
+    res.headers_mut().set(CacheControl(vec![CacheDirective::NoCache]));
+    let mut stream = try!(res.start());
+
+    if write_event(&mut stream, &current).is_ok() {
+        loop {
+            let written = match receiver.recv_timeout(Duration::from_secs(KEEP_ALIVE_SECONDS)) {
+                Ok(()) => {
+                    // A burst of changes only needs the last state sent
+                    while receiver.try_recv().is_ok() {}
+                    write_event(&mut stream, &store.state().to_json().to_string())
+                },
+                Err(RecvTimeoutError::Timeout) => {
+                    write!(stream, ": keep-alive\n\n").and_then(|_| stream.flush())
+                },
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            if written.is_err() {
+                break;
+            }
+        }
+    }
+
+    // Returning drops _subscription, which removes our listener from the store
+    Ok(Halt(stream))
+}
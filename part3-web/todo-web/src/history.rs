@@ -0,0 +1,98 @@
+use std::collections::VecDeque;
+use std::mem;
+
+// How many past states we keep around unless told otherwise
+pub const DEFAULT_HISTORY_LIMIT: usize = 50;
+
+// Time travel for the store. The present state lives in the store itself, History
+// only keeps what came before it (`past`, oldest first) and what we've undone
+// (`future`, the next state to redo is last)
+pub struct History<S> {
+    past: VecDeque<S>,
+    future: Vec<S>,
+    limit: usize,
+}
+
+impl<S: Clone> History<S> {
+    pub fn new(limit: usize) -> History<S> {
+        History {
+            past: VecDeque::new(),
+            future: Vec::new(),
+            limit: limit,
+        }
+    }
+
+    // Changes how many past states we keep, dropping the oldest ones if needed
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        while self.past.len() > self.limit {
+            self.past.pop_front();
+        }
+        self.future.truncate(self.limit);
+    }
+
+    // Called with the state we're leaving behind when a new action comes in.
+    // A new action starts a new timeline so anything we could redo is gone
+    pub fn record(&mut self, previous: S) {
+        self.future.clear();
+        if self.limit == 0 {
+            return;
+        }
+        self.past.push_back(previous);
+        if self.past.len() > self.limit {
+            self.past.pop_front();
+        }
+    }
+
+    // Swaps the present for the previous state, returns false if there is none
+    pub fn undo(&mut self, present: &mut S) -> bool {
+        match self.past.pop_back() {
+            Some(previous) => {
+                self.future.push(mem::replace(present, previous));
+                true
+            },
+            None => false,
+        }
+    }
+
+    // The opposite of undo
+    pub fn redo(&mut self, present: &mut S) -> bool {
+        match self.future.pop() {
+            Some(next) => {
+                self.past.push_back(mem::replace(present, next));
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Jumps to a position in `states()`, undoing or redoing as many times as needed
+    pub fn jump_to(&mut self, present: &mut S, index: usize) -> bool {
+        if index >= self.count() {
+            return false;
+        }
+        while self.position() > index {
+            self.undo(present);
+        }
+        while self.position() < index {
+            self.redo(present);
+        }
+        true
+    }
+
+    // Every state we can travel to, oldest first, with the present at `position()`
+    pub fn states<'a>(&'a self, present: &'a S) -> Vec<&'a S> {
+        let mut states: Vec<&S> = self.past.iter().collect();
+        states.push(present);
+        states.extend(self.future.iter().rev());
+        states
+    }
+
+    pub fn position(&self) -> usize {
+        self.past.len()
+    }
+
+    pub fn count(&self) -> usize {
+        self.past.len() + 1 + self.future.len()
+    }
+}
@@ -0,0 +1,320 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{ Hash, Hasher };
+use std::time::{ SystemTime, UNIX_EPOCH };
+use lists::TodoList;
+use todo::{ Todo, Priority, civil_from_days, parse_date, parse_tag };
+use transfer::{ Entry, ParseError };
+
+// iCalendar (RFC 5545) for calendar apps, every todo is a VTODO with
+//
+//     UID         todo-web-<list id>-<todo id>, the same in every export
+//     SUMMARY     the title
+//     STATUS      COMPLETED or NEEDS-ACTION
+//     DUE         the due date, as a date without a time
+//     PRIORITY    1 for high, 5 for medium and 9 for low like most apps do it
+//     CATEGORIES  the tags
+//
+// Reading goes the other way. Any PRIORITY from 1 to 4 is high and 6 to 9 is
+// low, a DUE with a time only keeps the date and categories that aren't
+// single words are left out since they can't be tags
+
+const PRODUCT_ID: &'static str = "-//Nickel Todo//todo-web//EN";
+
+// Content lines are folded when they get longer than this many bytes
+const MAX_LINE_BYTES: usize = 75;
+
+pub fn uid(list: &TodoList, todo: &Todo) -> String {
+    format!("todo-web-{}-{}", list.id, todo.id)
+}
+
+// Changes whenever anything in the VTODO would. DTSTAMP is left out since it's
+// always now, and DefaultHasher::new() hashes the same way every time
+pub fn etag(todo: &Todo) -> String {
+    let mut hasher = DefaultHasher::new();
+    todo.id.hash(&mut hasher);
+    todo.title.hash(&mut hasher);
+    todo.completed.hash(&mut hasher);
+    todo.due.hash(&mut hasher);
+    todo.priority.map(ical_priority).hash(&mut hasher);
+    todo.tags.hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+fn ical_priority(priority: Priority) -> u32 {
+    match priority {
+        Priority::High => 1,
+        Priority::Medium => 5,
+        Priority::Low => 9,
+    }
+}
+
+// 0 means no priority
+fn from_ical_priority(priority: u32) -> Option<Option<Priority>> {
+    match priority {
+        0 => Some(None),
+        5 => Some(Some(Priority::Medium)),
+        priority if priority < 5 => Some(Some(Priority::High)),
+        priority if priority <= 9 => Some(Some(Priority::Low)),
+        _ => None,
+    }
+}
+
+// Now in UTC, like 20161106T120000Z
+fn timestamp() -> String {
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0);
+    let (year, month, day) = civil_from_days(seconds / (24 * 60 * 60));
+    let time = seconds % (24 * 60 * 60);
+    format!("{:04}{:02}{:02}T{:02}{:02}{:02}Z", year, month, day, time / 3600, time / 60 % 60, time % 60)
+}
+
+// Backslashes, commas, semicolons and line breaks are escaped in TEXT values
+fn escape_text(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ';' => escaped.push_str("\\;"),
+            ',' => escaped.push_str("\\,"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => (),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape_text(text: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') | Some('N') => unescaped.push('\n'),
+            Some(c) => unescaped.push(c),
+            None => (),
+        }
+    }
+    unescaped
+}
+
+// Splits a list value like CATEGORIES on the commas that aren't escaped
+fn split_list(value: &str) -> Vec<String> {
+    let mut items = vec![String::new()];
+    let mut escaped = false;
+    for c in value.chars() {
+        if !escaped && c == ',' {
+            items.push(String::new());
+            continue;
+        }
+        escaped = !escaped && c == '\\';
+        if let Some(item) = items.last_mut() {
+            item.push(c);
+        }
+    }
+    items.iter().map(|item| unescape_text(item)).collect()
+}
+
+// Writes a content line with CRLF at the end, folding it onto more lines that
+// start with a space if it's too long. Folds never split a UTF-8 character
+fn push_line(output: &mut String, line: &str) {
+    let mut width = 0;
+    for c in line.chars() {
+        if width + c.len_utf8() > MAX_LINE_BYTES {
+            output.push_str("\r\n ");
+            width = 1;
+        }
+        output.push(c);
+        width += c.len_utf8();
+    }
+    output.push_str("\r\n");
+}
+
+fn push_vtodo(output: &mut String, list: &TodoList, todo: &Todo, stamp: &str) {
+    push_line(output, "BEGIN:VTODO");
+    push_line(output, &format!("UID:{}", uid(list, todo)));
+    push_line(output, &format!("DTSTAMP:{}", stamp));
+    push_line(output, &format!("SUMMARY:{}", escape_text(&todo.title)));
+    if todo.completed {
+        push_line(output, "STATUS:COMPLETED");
+        push_line(output, "PERCENT-COMPLETE:100");
+    } else {
+        push_line(output, "STATUS:NEEDS-ACTION");
+    }
+    if let Some(ref due) = todo.due {
+        push_line(output, &format!("DUE;VALUE=DATE:{}", due.replace("-", "")));
+    }
+    if let Some(priority) = todo.priority {
+        push_line(output, &format!("PRIORITY:{}", ical_priority(priority)));
+    }
+    if todo.tags.len() > 0 {
+        let tags: Vec<&str> = todo.tags.iter().map(|tag| tag.as_str()).collect();
+        push_line(output, &format!("CATEGORIES:{}", tags.join(",")));
+    }
+    push_line(output, "END:VTODO");
+}
+
+// A VCALENDAR named after the list with a VTODO for each of the todos
+pub fn calendar(list: &TodoList, todos: &[&Todo]) -> String {
+    let stamp = timestamp();
+    let mut output = String::new();
+    push_line(&mut output, "BEGIN:VCALENDAR");
+    push_line(&mut output, "VERSION:2.0");
+    push_line(&mut output, &format!("PRODID:{}", PRODUCT_ID));
+    push_line(&mut output, &format!("X-WR-CALNAME:{}", escape_text(&list.name)));
+    for todo in todos {
+        push_vtodo(&mut output, list, todo, &stamp);
+    }
+    push_line(&mut output, "END:VCALENDAR");
+    output
+}
+
+// A content line like DUE;VALUE=DATE:20161106 is the name, the parameters
+// after the semicolon and the value after the first colon that isn't in quotes
+fn split_property(line: &str) -> Option<(String, &str)> {
+    let mut in_quotes = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ':' if !in_quotes => {
+                let name = line[..index].split(';').next().unwrap_or("");
+                return Some((name.trim().to_uppercase(), &line[index + 1..]));
+            },
+            _ => (),
+        }
+    }
+    None
+}
+
+// Puts folded lines back together, with the line number each one starts on
+fn unfold(text: &str) -> Vec<(usize, String)> {
+    let mut lines: Vec<(usize, String)> = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some(&mut (_, ref mut previous)) = lines.last_mut() {
+                previous.push_str(&line[1..]);
+                continue;
+            }
+        }
+        lines.push((index + 1, line.to_string()));
+    }
+    lines
+}
+
+// A DATE like 20161106, or the date of a DATE-TIME like 20161106T120000Z
+fn parse_ical_date(value: &str) -> Option<String> {
+    let value = value.trim();
+    // Only digits can be sliced up by byte below without landing inside a character
+    if value.len() < 8 || !value.bytes().take(8).all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let (date, time) = value.split_at(8);
+    if time.len() > 0 && !time.starts_with('T') {
+        return None;
+    }
+    parse_date(&format!("{}-{}-{}", &date[..4], &date[4..6], &date[6..]))
+}
+
+fn error<T>(line: usize, message: &str) -> Result<T, ParseError> {
+    Err(ParseError { line: line, message: message.to_string() })
+}
+
+// Every VTODO in the text, whether it's a whole calendar or one CalDAV resource.
+// Other components like VEVENTs are skipped, and so are the VALARMs in a VTODO
+pub fn parse(text: &str) -> Result<Vec<Entry>, ParseError> {
+    let mut entries = Vec::new();
+    let mut entry: Option<Entry> = None;
+    // How many components deep in the VTODO we are, alarms and the like
+    let mut depth = 0;
+    // STATUS has the last word on whether it's done, without it a COMPLETED
+    // time or PERCENT-COMPLETE:100 means it is
+    let mut status = None;
+    let mut done = false;
+
+    for (line, content) in unfold(text) {
+        let (name, value) = match split_property(&content) {
+            Some(property) => property,
+            None => continue,
+        };
+        let value = value.trim();
+        if entry.is_none() {
+            if name == "BEGIN" && value.eq_ignore_ascii_case("VTODO") {
+                entry = Some(Entry::new(line, "", false));
+                depth = 0;
+                status = None;
+                done = false;
+            }
+            continue;
+        }
+        if name == "BEGIN" {
+            depth += 1;
+            continue;
+        }
+        if name == "END" {
+            if depth > 0 {
+                depth -= 1;
+                continue;
+            }
+            if let Some(mut finished) = entry.take() {
+                finished.completed = status.unwrap_or(done);
+                entries.push(finished);
+            }
+            continue;
+        }
+        if depth > 0 {
+            continue;
+        }
+
+        let current = match entry.as_mut() {
+            Some(current) => current,
+            None => continue,
+        };
+        match name.as_str() {
+            "SUMMARY" => current.title = unescape_text(value).trim().to_string(),
+            "STATUS" => status = Some(value.eq_ignore_ascii_case("COMPLETED")),
+            "COMPLETED" => done = true,
+            "PERCENT-COMPLETE" => done = done || value == "100",
+            "DUE" => match parse_ical_date(value) {
+                Some(due) => current.due = Some(due),
+                None => return error(line, "DUE has to be a date like 20161106"),
+            },
+            "PRIORITY" => match value.parse().ok().and_then(from_ical_priority) {
+                Some(priority) => current.priority = priority,
+                None => return error(line, "PRIORITY has to be a number from 0 to 9"),
+            },
+            "CATEGORIES" => current.tags.extend(split_list(value).iter().filter_map(|tag| parse_tag(tag))),
+            _ => (),
+        }
+    }
+    if let Some(unfinished) = entry {
+        return error(unfinished.line, "the VTODO is never ended with END:VTODO");
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ parse, parse_ical_date };
+
+    #[test]
+    fn parses_dates_and_date_times() {
+        assert_eq!(parse_ical_date("20161106"), Some("2016-11-06".to_string()));
+        assert_eq!(parse_ical_date("20161106T120000Z"), Some("2016-11-06".to_string()));
+        assert_eq!(parse_ical_date("20161106X"), None);
+        assert_eq!(parse_ical_date("20161306"), None);
+        assert_eq!(parse_ical_date("2016"), None);
+    }
+
+    #[test]
+    fn rejects_dates_with_other_characters_instead_of_panicking() {
+        assert_eq!(parse_ical_date("123\u{e9}5678"), None);
+        assert_eq!(parse_ical_date("2016\u{e9}106"), None);
+        assert_eq!(parse_ical_date("\u{e9}\u{e9}\u{e9}\u{e9}"), None);
+        let error = parse("BEGIN:VTODO\r\nSUMMARY:Pay rent\r\nDUE:123\u{e9}5678\r\nEND:VTODO\r\n").unwrap_err();
+        assert_eq!(error.line, 3);
+    }
+}
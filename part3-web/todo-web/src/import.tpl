@@ -0,0 +1,79 @@
+<!DOCTYPE html>
+<html>
+  <head>
+    <meta charset="utf-8">
+    <title>{{list.name}} - Nickel Todo - Import</title>
+    <link rel="stylesheet" href="http://todomvc.com/examples/react/node_modules/todomvc-common/base.css">
+    <link rel="stylesheet" href="http://todomvc.com/examples/react/node_modules/todomvc-app-css/index.css">
+    <style type="text/css">
+    .import {
+      padding: 16px;
+    }
+    .import textarea {
+      width: 100%;
+      height: 200px;
+      box-sizing: border-box;
+      font-family: monospace;
+      font-size: 14px;
+    }
+    .import .error {
+      color: #af2f2f;
+    }
+    .import .report li {
+      margin: 4px 0;
+    }
+    .import .report .change {
+      display: inline-block;
+      width: 90px;
+      color: #777;
+    }
+    .info .back {
+      color: inherit;
+    }
+    </style>
+  </head>
+  <body>
+    <section class="todoapp">
+      <header class="header">
+        <h1>import</h1>
+      </header>
+      <form class="import" action="{{base}}/import" method="post">
+        <p>
+          Paste a
+          <select name="format">
+            <option value="markdown"{{#is_selected_format "markdown"}} selected{{/is_selected_format}}>Markdown checklist</option>
+            <option value="todotxt"{{#is_selected_format "todotxt"}} selected{{/is_selected_format}}>todo.txt file</option>
+            <option value="csv"{{#is_selected_format "csv"}} selected{{/is_selected_format}}>CSV file</option>
+            <option value="json"{{#is_selected_format "json"}} selected{{/is_selected_format}}>JSON file</option>
+            <option value="ical"{{#is_selected_format "ical"}} selected{{/is_selected_format}}>iCalendar file</option>
+          </select>
+          to add its todos to {{list.name}}. Todos that are in the list already
+          are only marked as done or not done, like they are in the file.
+        </p>
+        <textarea name="text" placeholder="- [ ] Write the report&#10;- [x] Book the room">{{text}}</textarea>
+        {{#if error}}<p class="error">{{error}}</p>{{/if}}
+        {{#if report}}
+        <div class="report">
+          <p>
+            Importing this adds {{report.added}}, completes {{report.completed}} and reopens {{report.reopened}} todos.
+            {{report.unchanged}} are already the way they are in the file and {{report.skipped}} can't be imported.
+          </p>
+          <ul>
+            {{#each report.changes}}
+            <li><span class="change">{{change}}</span> {{title}}{{#if reason}}, {{reason}}{{/if}}</li>
+            {{/each}}
+          </ul>
+        </div>
+        {{/if}}
+        <p>
+          <button type="submit" name="dry_run" value="true">Preview</button>
+          <button type="submit">Import</button>
+        </p>
+      </form>
+    </section>
+    <footer class="info">
+      <p><a class="back" href="{{base}}">Back to {{list.name}}</a></p>
+      <p>Signed in as {{username}}</p>
+    </footer>
+  </body>
+</html>
@@ -0,0 +1,133 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use rustc_serialize::json::{self, Json, ToJson};
+use persist;
+
+pub type ListId = u64;
+
+// Every user starts out with one list called this, and users from before
+// there were lists find their todos in it
+pub const DEFAULT_LIST_NAME: &'static str = "Todos";
+
+const LISTS_FILE: &'static str = "lists.json";
+const MAX_LIST_NAME_LENGTH: usize = 64;
+
+#[derive(Clone, Debug, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct TodoList {
+    pub id: ListId,
+    pub name: String,
+    // Archived lists are tucked away in the list switcher, but still there
+    pub archived: bool,
+}
+
+impl TodoList {
+    // Where the list's pages live, every HTML route for it starts with this
+    pub fn base_url(&self) -> String {
+        format!("/lists/{}", self.id)
+    }
+}
+
+impl ToJson for TodoList {
+    fn to_json(&self) -> Json {
+        let mut object = BTreeMap::new();
+        object.insert("id".to_string(), self.id.to_json());
+        object.insert("name".to_string(), self.name.to_json());
+        object.insert("archived".to_string(), self.archived.to_json());
+        object.insert("url".to_string(), self.base_url().to_json());
+        Json::Object(object)
+    }
+}
+
+// Trims the name and checks that there's something left, but not too much
+pub fn parse_list_name(name: &str) -> Option<String> {
+    let name = name.trim();
+    if name.len() > 0 && name.chars().count() <= MAX_LIST_NAME_LENGTH {
+        Some(name.to_string())
+    } else {
+        None
+    }
+}
+
+pub fn list_name_rules() -> String {
+    format!("List names are 1 to {} characters", MAX_LIST_NAME_LENGTH)
+}
+
+// A user's lists, in the order they were created. Kept in `dir`/lists.json
+// and every list is saved in `dir`/lists/<id>
+#[derive(Clone, RustcEncodable, RustcDecodable)]
+pub struct ListIndex {
+    pub lists: Vec<TodoList>,
+    // The id the next created list will get, ids are never reused so an old
+    // link can't end up at a different list
+    next_id: ListId,
+}
+
+// Where the list with `id` is saved, `dir` being the user's directory
+pub fn list_dir(dir: &Path, id: ListId) -> PathBuf {
+    dir.join("lists").join(id.to_string())
+}
+
+fn invalid_data<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+impl ListIndex {
+    // Loads the lists in `dir`. The first time around the user gets the default
+    // list, and whatever they had saved from before there were lists is moved
+    // into it
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<ListIndex> {
+        let dir = dir.as_ref();
+        match File::open(dir.join(LISTS_FILE)) {
+            Ok(mut file) => {
+                let mut contents = String::new();
+                try!(file.read_to_string(&mut contents));
+                return json::decode(&contents).map_err(invalid_data);
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+
+        let mut index = ListIndex { lists: Vec::new(), next_id: 1 };
+        let list = index.add(DEFAULT_LIST_NAME.to_string());
+        try!(persist::move_journal(dir, list_dir(dir, list.id)));
+        try!(index.save(dir));
+        Ok(index)
+    }
+
+    // Same write-to-a-temporary-file-and-rename trick as users.json
+    pub fn save<P: AsRef<Path>>(&self, dir: P) -> io::Result<()> {
+        let dir = dir.as_ref();
+        try!(fs::create_dir_all(dir));
+        let contents = try!(json::encode(self).map_err(invalid_data));
+        let tmp_path = dir.join(format!("{}.tmp", LISTS_FILE));
+        {
+            let mut tmp = try!(File::create(&tmp_path));
+            try!(tmp.write_all(contents.as_bytes()));
+            try!(tmp.sync_all());
+        }
+        fs::rename(&tmp_path, dir.join(LISTS_FILE))
+    }
+
+    pub fn add(&mut self, name: String) -> TodoList {
+        let list = TodoList { id: self.next_id, name: name, archived: false };
+        self.next_id += 1;
+        self.lists.push(list.clone());
+        list
+    }
+
+    pub fn find(&self, id: ListId) -> Option<&TodoList> {
+        self.lists.iter().find(|list| list.id == id)
+    }
+
+    pub fn find_mut(&mut self, id: ListId) -> Option<&mut TodoList> {
+        self.lists.iter_mut().find(|list| list.id == id)
+    }
+
+    // Where / takes you, the first list that isn't archived. If they all are
+    // it's just the first one
+    pub fn default_list(&self) -> Option<&TodoList> {
+        self.lists.iter().find(|list| !list.archived).or(self.lists.first())
+    }
+}
@@ -0,0 +1,59 @@
+<!DOCTYPE html>
+<html>
+  <head>
+    <meta charset="utf-8">
+    <title>Nickel Todo</title>
+    <link rel="stylesheet" href="http://todomvc.com/examples/react/node_modules/todomvc-common/base.css">
+    <link rel="stylesheet" href="http://todomvc.com/examples/react/node_modules/todomvc-app-css/index.css">
+    <style type="text/css">
+    .account {
+      padding: 16px;
+      border-top: 1px solid #e6e6e6;
+    }
+    .account h2 {
+      margin: 0 0 10px;
+      font-size: 18px;
+      font-weight: 400;
+    }
+    .account input {
+      display: block;
+      width: 100%;
+      box-sizing: border-box;
+      margin-bottom: 10px;
+      padding: 8px;
+      font-size: 18px;
+    }
+    .account button {
+      padding: 6px 12px;
+      border: 1px solid #e6e6e6;
+      cursor: pointer;
+    }
+    .error {
+      padding: 16px;
+      color: #af2f2f;
+    }
+    </style>
+  </head>
+  <body>
+    <section class="todoapp">
+      <header class="header">
+        <h1>todos</h1>
+      </header>
+      {{#if error}}
+      <p class="error">{{error}}</p>
+      {{/if}}
+      <form class="account" action="/login" method="post">
+        <h2>Log in</h2>
+        <input name="username" placeholder="Username" autocomplete="username">
+        <input name="password" type="password" placeholder="Password" autocomplete="current-password">
+        <button type="submit">Log in</button>
+      </form>
+      <form class="account" action="/register" method="post">
+        <h2>New here? Sign up</h2>
+        <input name="username" placeholder="Username" autocomplete="username">
+        <input name="password" type="password" placeholder="Password, at least 8 characters" autocomplete="new-password">
+        <button type="submit">Sign up</button>
+      </form>
+    </section>
+  </body>
+</html>
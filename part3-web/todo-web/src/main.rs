@@ -1,99 +1,686 @@
 #[macro_use] extern crate nickel;
+#[macro_use] extern crate lazy_static;
 extern crate rustc_serialize;
+extern crate hyper;
 extern crate handlebars;
+extern crate crypto;
+extern crate rand;
+extern crate rusqlite;
+extern crate toml;
+mod api;
+mod auth;
+mod caldav;
+mod config;
+mod error;
+mod events;
+mod history;
+mod ical;
+mod lists;
+mod middleware;
+mod persist;
+mod query;
+mod sqlite;
+mod storage;
 mod store;
+mod subscription;
 mod template;
 mod todo;
-use template::render;
-use store::{ Store, reducer };
-use todo::TodoAction::{ Add, Remove, Toggle };
-use store::Action::{ Todos, Visibility };
-use store::VisibilityFilter::{ ShowAll, ShowActive, ShowCompleted };
+mod transfer;
+mod user_stores;
+use template::{ render, render_with_status, redirect, error_page };
+use store::State;
+use todo::{ TodoId, Priority, is_overdue, parse_date, parse_tag, position_before, retag, today };
+use todo::TodoAction::{ Add, ClearCompleted, Edit, EmptyTrash, Move, Purge, Remove, Restore, SetDue, SetPriority, Toggle, ToggleAll };
+use store::Action::{ Todos };
+use store::{ SortBy, View, VisibilityFilter, encode_query_value, select_todos };
+use auth::{ Auth, Access, check_access, deny, render_login, redirect_with_session, session_token };
+use lists::TodoList;
+use transfer::Format;
+use storage::Backend;
+use user_stores::UserStores;
 
-use std::sync::{Arc, Mutex};
+use std::collections::{ BTreeMap, BTreeSet };
+use std::env;
+use std::process;
+use std::sync::Arc;
+use rustc_serialize::json::{ Json, ToJson };
 
-use nickel::{Nickel, HttpRouter, FormBody};
+use nickel::{Nickel, Options, HttpRouter, FormBody, QueryString, Request, StaticFilesHandler};
+use nickel::status::StatusCode;
+use error::AppError;
+use config::Command;
+
+// Reads a field from a posted form, None if it wasn't sent at all
+fn optional_form_field(req: &mut Request, name: &str) -> Result<Option<String>, AppError> {
+    match req.form_body() {
+        Ok(form_body) => Ok(form_body.get(name).map(|value| value.to_string())),
+        Err(_) => Err(AppError::BadRequest("Could not read the form you sent".to_string())),
+    }
+}
+
+// Reads a field from a posted form, a field that isn't there is just empty
+fn form_field(req: &mut Request, name: &str) -> Result<String, AppError> {
+    optional_form_field(req, name).map(|value| value.unwrap_or(String::new()))
+}
+
+// The username and password from the login and register forms
+fn login_form(req: &mut Request) -> Result<(String, String), AppError> {
+    let username = try!(form_field(req, "username"));
+    let password = try!(form_field(req, "password"));
+    Ok((username, password))
+}
+
+// The view a request asks for with ?filter=, ?tag=, ?sort= and the ?q= search,
+// every view is per request so people sharing a list don't switch each other's
+fn request_view(req: &mut Request) -> Result<View, String> {
+    let query = req.query();
+    View::from_query(query.get("filter"), query.get("tag"), query.get("sort"), query.get("q"))
+}
+
+// Where to send the browser after an action. The trash actions are all done
+// from the trash page, everything else goes back to the list with the same filter
+fn back_to(req: &mut Request, list: &TodoList) -> String {
+    let action = req.param("action").unwrap_or("").to_string();
+    match action.as_str() {
+        "restore" | "purge" | "empty-trash" => format!("{}/trash", list.base_url()),
+        _ => format!("{}{}", list.base_url(), request_view(req).unwrap_or(View::default()).to_query()),
+    }
+}
+
+// The pages get the State plus who is logged in, which list they're on and the
+// view they asked for. `visible` has the todos the view shows in its order, each
+// with whether it's overdue, its tags as the space separated list the edit
+// form takes and where that form posts to. `base` is the list's URL, every link
+// and form on the page starts with it
+fn page_data(state: &State, username: &str, list: &TodoList, lists: &[TodoList], view: &View) -> Json {
+    let base = list.base_url();
+    let today = today();
+    let visible: Vec<Json> = select_todos(state, view).into_iter().map(|todo| {
+        let mut json = todo.to_json();
+        if let Json::Object(ref mut object) = json {
+            object.insert("overdue".to_string(), is_overdue(todo, &today).to_json());
+            let tags: Vec<&str> = todo.tags.iter().map(|tag| tag.as_str()).collect();
+            object.insert("tag_list".to_string(), tags.join(" ").to_json());
+            object.insert("edit_url".to_string(), format!("{}/edit/{}", base, todo.id).to_json());
+        }
+        json
+    }).collect();
+
+    // Links to the other filters and orders that keep the rest of the view
+    let mut filter_links = BTreeMap::new();
+    for filter in &[VisibilityFilter::ShowAll, VisibilityFilter::ShowActive, VisibilityFilter::ShowCompleted] {
+        let link = View { filter: filter.clone(), ..view.clone() };
+        filter_links.insert(filter.to_query().to_string(), format!("{}{}", base, link.to_query()).to_json());
+    }
+    let mut sort_links = BTreeMap::new();
+    for sort in &[SortBy::Created, SortBy::Due, SortBy::Priority, SortBy::Title] {
+        let link = View { sort: *sort, ..view.clone() };
+        sort_links.insert(sort.to_query().to_string(), format!("{}{}", base, link.to_query()).to_json());
+    }
+    let untagged = View { tag: None, ..view.clone() };
+
+    // For the list switcher, the archived lists go in a list of their own
+    let switcher = |archived: bool| -> Json {
+        Json::Array(lists.iter().filter(|other| other.archived == archived).map(|other| {
+            let mut json = other.to_json();
+            if let Json::Object(ref mut object) = json {
+                object.insert("current".to_string(), (other.id == list.id).to_json());
+            }
+            json
+        }).collect())
+    };
+
+    let mut data = state.to_json();
+    if let Json::Object(ref mut object) = data {
+        object.insert("username".to_string(), username.to_json());
+        object.insert("list".to_string(), list.to_json());
+        object.insert("base".to_string(), base.to_json());
+        object.insert("open_lists".to_string(), switcher(false));
+        object.insert("archived_lists".to_string(), switcher(true));
+        object.insert("can_delete_list".to_string(), (lists.len() > 1).to_json());
+        object.insert("visible".to_string(), Json::Array(visible));
+        object.insert("visibility_filter".to_string(), format!("{:?}", view.filter).to_json());
+        object.insert("tag".to_string(), view.tag.to_json());
+        object.insert("sort".to_string(), view.sort.to_query().to_json());
+        object.insert("search".to_string(), view.search_text.to_json());
+        // Dragging todos around only makes sense when they're listed in list order
+        object.insert("reorderable".to_string(), view.query().sort.is_empty().to_json());
+        object.insert("query".to_string(), view.to_query().to_json());
+        object.insert("filter_links".to_string(), Json::Object(filter_links));
+        object.insert("sort_links".to_string(), Json::Object(sort_links));
+        object.insert("untagged_link".to_string(), format!("{}{}", base, untagged.to_query()).to_json());
+    }
+    data
+}
+
+// The import page, with the form filled in with what was sent. `report` is
+// the dry run of the import when there is one
+fn import_page_data(username: &str, list: &TodoList, format: &str, text: &str) -> BTreeMap<String, Json> {
+    let mut data = BTreeMap::new();
+    data.insert("username".to_string(), username.to_json());
+    data.insert("list".to_string(), list.to_json());
+    data.insert("base".to_string(), list.base_url().to_json());
+    data.insert("format".to_string(), format.to_json());
+    data.insert("text".to_string(), text.to_json());
+    data
+}
+
+// Startup problems are the user's to fix, so they get a message instead of a panic
+fn exit_with(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
 
 fn main() {
+    let config = match config::from_args(env::args().skip(1)) {
+        Ok(Command::Serve(config)) => config,
+        Ok(Command::PrintConfig(config)) => {
+            print!("{}", config.to_toml());
+            return;
+        },
+        Ok(Command::Help) => {
+            println!("{}", config::USAGE);
+            return;
+        },
+        Err(message) => exit_with(&message),
+    };
+    if let Err(problems) = config.validate() {
+        exit_with(&format!("Invalid configuration:\n  {}", problems.join("\n  ")));
+    }
+    config::set_limits(config.limits());
+
     let mut server = Nickel::new();
+    // Every /events stream holds on to one of these, validate() made sure
+    // max_event_streams leaves at least half of them for everything else
+    server.options = Options::default().thread_count(Some(config.worker_threads));
+
+    // The templates are compiled once up front. In dev mode they're
+    // recompiled whenever one of them changes on disk
+    match template::load(&config.template_dir) {
+        Ok(count) => println!("Compiled {} templates from {}", count, config.template_dir.display()),
+        Err(message) => exit_with(&message),
+    }
+    if config.dev {
+        println!("Development mode, watching {} for template changes", config.template_dir.display());
+        template::watch(&config.template_dir);
+    }
 
-    // Create our todo list store
-    let store = Store::create_store(reducer);
+    // Accounts live in users.json in the data directory, and every user gets
+    // their own todo lists in users/<username>, each with its own action log
+    let auth = match Auth::open(&config.data_dir) {
+        Ok(auth) => Arc::new(auth),
+        Err(e) => exit_with(&format!("Could not load the accounts in {}: {}", config.data_dir.display(), e)),
+    };
+    // validate() already made sure the storage setting is one we know
+    let backend = Backend::from_name(&config.storage).unwrap_or(Backend::File);
+    let stores = Arc::new( UserStores::new(config.data_dir.join("users"), backend, config.history_limit, config.trash_retention()) );
+    user_stores::sweep_trash(&stores);
 
-    // Put the store in a container that will let us
-    // safely use it in multi-threaded environment
-    let store_container = Arc::new( Mutex::new(store) );
+    if let Some(ref static_dir) = config.static_dir {
+        server.utilize(StaticFilesHandler::new(static_dir));
+    }
 
-    // Every clone() of our container is counted
-    // so that when the last clone goes out of scope
-    // the container can be deallocated
-    let store = store_container.clone();
+    // The JSON API for scripts and the mobile client lives under /api, it has
+    // to be mounted first so the catch-all HTML routes below don't match it
+    api::mount(&mut server, &auth, &stores);
 
-    // At the / path let's just render our current todo list
-    server.get("/", middleware! { |_req, res|
-        // We get our store from the container by locking it
-        // from other threads.
-        let store = store.lock().unwrap();
+    // Calendar apps sync the lists over CalDAV from /caldav/lists
+    caldav::mount(&mut server, &auth, &stores);
 
-        // Render from nickel_mustache takes the
-        // nickel Result struct, a path to a mustache
-        // template, and the data to use
-        return render(res, "./src/todos.tpl", store.get_state())
-        // And here the lock is released..
+    // The login page, with forms for logging in and registering
+    server.get("/login", middleware! { |_req, res|
+        return render_login(res, StatusCode::Ok, "")
     });
 
-    // Let's clone it again for the next closure
-    let store = store_container.clone();
+    let auth_clone = auth.clone();
+    server.post("/login", middleware! { |req, res|
+        let (username, password) = match login_form(req) {
+            Ok(fields) => fields,
+            Err(e) => return error_page(res, e),
+        };
+        return match auth_clone.login(&username, &password) {
+            Some(token) => redirect_with_session(res, &token, "/"),
+            None => render_login(res, StatusCode::Unauthorized, "Wrong username or password"),
+        }
+    });
+
+    let auth_clone = auth.clone();
+    server.post("/register", middleware! { |req, res|
+        let (username, password) = match login_form(req) {
+            Ok(fields) => fields,
+            Err(e) => return error_page(res, e),
+        };
+        if let Err(e) = auth_clone.register(&username, &password) {
+            let status = match e {
+                auth::AuthError::UsernameTaken => StatusCode::Conflict,
+                auth::AuthError::Io(_) => StatusCode::InternalServerError,
+                _ => StatusCode::UnprocessableEntity,
+            };
+            return render_login(res, status, &e.message());
+        }
+        return match auth_clone.login(&username, &password) {
+            Some(token) => redirect_with_session(res, &token, "/"),
+            None => render_login(res, StatusCode::InternalServerError, "Could not log you in"),
+        }
+    });
+
+    let auth_clone = auth.clone();
+    server.post("/logout", middleware! { |req, res|
+        if let Some(token) = session_token(req) {
+            auth_clone.logout(&token);
+        }
+        return redirect_with_session(res, "", "/login")
+    });
+
+    // Every handler below starts by looking up who is logged in and the list
+    // the URL is for, so each closure gets its own clone of both
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+
+    // Everyone has at least one list, / sends you to the first one that isn't archived
+    server.get("/", middleware! { |req, res|
+        return match check_access(req, &auth_clone, &stores_clone, false) {
+            Access::Granted(_, list, _) => redirect(res, &list.base_url()),
+            denied => deny(res, denied),
+        }
+    });
+
+    // Let's clone them again for the next closure
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+
+    // At the /lists/1 path let's just render that todo list
+    server.get("/lists/:list", middleware! { |req, res|
+        let (username, list, store) = match check_access(req, &auth_clone, &stores_clone, false) {
+            Access::Granted(username, list, store) => (username, list, store),
+            denied => return deny(res, denied),
+        };
+        let lists = match stores_clone.lists(&username) {
+            Ok(lists) => lists,
+            Err(e) => return error_page(res, e),
+        };
+
+        // The last published state is ours to keep, so we can render it
+        // without locking the store and holding up everyone else
+        let state = store.state();
+
+        // A search that doesn't parse shows the whole list with what's wrong with it
+        let (view, query_error) = match request_view(req) {
+            Ok(view) => (view, None),
+            Err(message) => (View::default(), Some(message)),
+        };
+        let mut data = page_data(&state, &username, &list, &lists, &view);
+        if let Some(message) = query_error {
+            if let Json::Object(ref mut object) = data {
+                object.insert("query_error".to_string(), message.to_json());
+            }
+        }
+
+        // Render takes the nickel Response, the name
+        // of one of the templates compiled at startup,
+        // and the data to use
+        return render(res, "todos", &data)
+    });
+
+    // Let's clone them again for the next closure
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+
+    // Everything in the list that's been deleted but not purged yet
+    server.get("/lists/:list/trash", middleware! { |req, res|
+        let (username, list, store) = match check_access(req, &auth_clone, &stores_clone, false) {
+            Access::Granted(username, list, store) => (username, list, store),
+            denied => return deny(res, denied),
+        };
+        let lists = match stores_clone.lists(&username) {
+            Ok(lists) => lists,
+            Err(e) => return error_page(res, e),
+        };
+        return render(res, "trash", &page_data(&store.state(), &username, &list, &lists, &View::default()))
+    });
+
+    // Let's clone them again for the next closure
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+
+    // Browsers keep this open to get every new State of the list pushed to them
+    server.get("/lists/:list/events", middleware! { |req, res|
+        let store = match check_access(req, &auth_clone, &stores_clone, false) {
+            Access::Granted(_, _, store) => store,
+            denied => return deny(res, denied),
+        };
+        return events::stream(res, &store)
+    });
+
+    // Let's clone them again for the next closure
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+
+    // The edit form posts the new title, due date, priority and space separated
+    // tags to /lists/1/edit/1. Only what changed is dispatched, and fields that
+    // weren't sent at all are left alone
+    server.post("/lists/:list/edit/:id", middleware! { |req, res|
+        let (list, store) = match check_access(req, &auth_clone, &stores_clone, true) {
+            Access::Granted(_, list, store) => (list, store),
+            denied => return deny(res, denied),
+        };
+        let todo_id = match req.param("id").unwrap_or("").parse::<TodoId>() {
+            Ok(todo_id) => todo_id,
+            Err(_) => return error_page(res, AppError::BadRequest("Todo ids are numbers".to_string())),
+        };
+        let fields = ["title", "due", "priority", "tags"].iter()
+            .map(|name| optional_form_field(req, name))
+            .collect::<Result<Vec<_>, _>>();
+        let (title, due, priority, tags) = match fields {
+            Ok(fields) => (fields[0].clone(), fields[1].clone(), fields[2].clone(), fields[3].clone()),
+            Err(e) => return error_page(res, e),
+        };
+        let due = match due.as_ref().map(|due| due.trim()) {
+            None => None,
+            Some("") => Some(None),
+            Some(due) => match parse_date(due) {
+                Some(due) => Some(Some(due)),
+                None => return error_page(res, AppError::Unprocessable(format!("{} is not a date like 2016-06-17", due))),
+            },
+        };
+        let priority = match priority.as_ref().map(|priority| priority.trim()) {
+            None => None,
+            Some("") => Some(None),
+            Some(priority) => match Priority::from_name(priority) {
+                Some(priority) => Some(Some(priority)),
+                None => return error_page(res, AppError::Unprocessable("Priorities are low, medium or high".to_string())),
+            },
+        };
+        let tags = match tags {
+            None => None,
+            Some(tags) => {
+                let mut set = BTreeSet::new();
+                for tag in tags.split_whitespace() {
+                    match parse_tag(tag) {
+                        Some(tag) => { set.insert(tag); },
+                        None => return error_page(res, AppError::Unprocessable(format!("{} is not a single word tag", tag))),
+                    }
+                }
+                Some(set)
+            },
+        };
+
+        let mut store = store.lock();
+        let todo = match store.get_state().todos.iter().find(|todo| todo.id == todo_id && !todo.deleted) {
+            Some(todo) => todo.clone(),
+            None => return error_page(res, AppError::NotFound("That todo doesn't exist".to_string())),
+        };
+        let mut actions = Vec::new();
+        if let Some(tags) = tags {
+            actions.extend(retag(&todo, &tags));
+        }
+        if let Some(priority) = priority {
+            if priority != todo.priority { actions.push(SetPriority(todo_id, priority)); }
+        }
+        if let Some(due) = due {
+            if due != todo.due { actions.push(SetDue(todo_id, due)); }
+        }
+        // An empty title removes the todo, the validate middleware takes care of
+        // that, so it goes last
+        if let Some(title) = title {
+            if title.trim() != todo.title { actions.push(Edit(todo_id, title)); }
+        }
+        // One edit, so one undo takes all of it back
+        store.batch(|store| {
+            for action in actions {
+                store.dispatch( Todos(action) );
+            }
+        });
+        return redirect(res, &back_to(req, &list))
+    });
+
+    // Let's clone them again for the next closure
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+
+    // Drag and drop posts the id of the todo it was dropped in front of to
+    // /lists/1/move/1, or nothing to move it to the bottom of the list
+    server.post("/lists/:list/move/:id", middleware! { |req, res|
+        let (list, store) = match check_access(req, &auth_clone, &stores_clone, true) {
+            Access::Granted(_, list, store) => (list, store),
+            denied => return deny(res, denied),
+        };
+        let todo_id = match req.param("id").unwrap_or("").parse::<TodoId>() {
+            Ok(todo_id) => todo_id,
+            Err(_) => return error_page(res, AppError::BadRequest("Todo ids are numbers".to_string())),
+        };
+        let before = match form_field(req, "before") {
+            Ok(before) => before,
+            Err(e) => return error_page(res, e),
+        };
+
+        let mut store = store.lock();
+        let position = match before.trim() {
+            // Anything past the end is the bottom of the list
+            "" => store.get_state().todos.len(),
+            before => match before.parse::<TodoId>().ok()
+                .and_then(|before| position_before(&store.get_state().todos, todo_id, before)) {
+                Some(position) => position,
+                None => return error_page(res, AppError::NotFound("The todo it was dropped on doesn't exist".to_string())),
+            },
+        };
+        store.dispatch( Todos( Move(todo_id, position) ) );
+        return redirect(res, &back_to(req, &list))
+    });
+
+    // Let's clone them again for the next closure
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+
+    // Downloads the list as /lists/1/export/csv, or json, markdown, todotxt or ical
+    server.get("/lists/:list/export/:format", middleware! { |req, res|
+        let (list, store) = match check_access(req, &auth_clone, &stores_clone, false) {
+            Access::Granted(_, list, store) => (list, store),
+            denied => return deny(res, denied),
+        };
+        let format = match Format::from_name(req.param("format").unwrap_or("")) {
+            Some(format) => format,
+            None => return error_page(res, AppError::NotFound("Lists can be exported as json, csv, markdown, todotxt or ical".to_string())),
+        };
+        let file = transfer::export(&list, &store.state().todos, format);
+        return api::send_file(res, &list, format, file)
+    });
+
+    // Let's clone them again for the next closure
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+
+    // The import page, where a file can be pasted in
+    server.get("/lists/:list/import", middleware! { |req, res|
+        let (username, list) = match check_access(req, &auth_clone, &stores_clone, false) {
+            Access::Granted(username, list, _) => (username, list),
+            denied => return deny(res, denied),
+        };
+        return render(res, "import", &import_page_data(&username, &list, "markdown", ""))
+    });
+
+    // Let's clone them again for the next closure
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+
+    // The import form has a Preview button that sends dry_run along, that
+    // shows the report of what would change with the form still filled in.
+    // Without it the import is dispatched and we go back to the list
+    server.post("/lists/:list/import", middleware! { |req, res|
+        let (username, list, store) = match check_access(req, &auth_clone, &stores_clone, true) {
+            Access::Granted(username, list, store) => (username, list, store),
+            denied => return deny(res, denied),
+        };
+        let fields = ["format", "text", "dry_run"].iter()
+            .map(|name| form_field(req, name))
+            .collect::<Result<Vec<_>, _>>();
+        let (format_name, text, dry_run) = match fields {
+            Ok(fields) => (fields[0].clone(), fields[1].clone(), fields[2].len() > 0),
+            Err(e) => return error_page(res, e),
+        };
+        let format = match Format::from_name(&format_name) {
+            Some(format) => format,
+            None => return error_page(res, AppError::BadRequest("Pick json, csv, markdown, todotxt or ical".to_string())),
+        };
+
+        let mut data = import_page_data(&username, &list, format.name(), &text);
+        let entries = match transfer::parse(&text, format) {
+            Ok(entries) => entries,
+            Err(e) => {
+                data.insert("error".to_string(), e.to_string().to_json());
+                return render_with_status(res, StatusCode::UnprocessableEntity, "import", &Json::Object(data));
+            },
+        };
+        // A preview only reads, the real thing plans against the state it changes
+        if dry_run {
+            data.insert("report".to_string(), transfer::plan(&store.state(), entries).to_json());
+            return render(res, "import", &Json::Object(data));
+        }
+        let mut store = store.lock();
+        let plan = transfer::plan(store.get_state(), entries);
+        plan.apply(&mut store);
+        return redirect(res, &list.base_url())
+    });
+
+    // Let's clone them again for the next closure
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+
+    // This time we look for POSTs like /lists/1/toggle/1, anything that changes
+    // the list has to be a POST so link prefetchers and crawlers can't trigger it
+    server.post("/lists/:list/:action/:id", middleware! { |_req, res|
+        let (list, store) = match check_access(_req, &auth_clone, &stores_clone, true) {
+            Access::Granted(_, list, store) => (list, store),
+            denied => return deny(res, denied),
+        };
 
-    // This time we look for requests like /toggle/1
-    server.get("/:action/:id", middleware! { |_req, res|
         // We will dispatch an action on our store so we
         // get a mutable reference
-        let mut store = store.lock().unwrap();
+        let mut store = store.lock();
 
         // We try to parse the id param to an int, this works for the
         // toggle and remove actions
-        if let Ok(num) = _req.param("id").unwrap().parse::<i16>() {
-            match _req.param("action").unwrap() {
+        if let Ok(num) = _req.param("id").unwrap_or("").parse::<TodoId>() {
+            match _req.param("action").unwrap_or("") {
                 "toggle" => {
                     store.dispatch( Todos( Toggle(num) ) )
                 },
 
                 "remove" => store.dispatch( Todos( Remove(num) ) ),
+                // These two come from the trash page
+                "restore" => store.dispatch( Todos( Restore(num) ) ),
+                "purge" => store.dispatch( Todos( Purge(num) ) ),
+                // Jumps to a position in the undo history
+                "jump" => { store.jump_to(num as usize); },
                 _ => (),
             }
         } else {
-        // Otherwise look for a show action
-            match _req.param("action").unwrap() {
-                "show" => {
-                    match _req.param("id").unwrap() {
-                        "all" => store.dispatch( Visibility( ShowAll ) ),
-                        "active" => store.dispatch( Visibility( ShowActive ) ),
-                        "completed" => store.dispatch( Visibility( ShowCompleted ) ),
-                        _ => (),
+        // Otherwise look for a toggle-all action
+            match _req.param("action").unwrap_or("") {
+                // /toggle-all/true marks everything as done, /toggle-all/false as not done
+                "toggle-all" => {
+                    if let Ok(completed) = _req.param("id").unwrap_or("").parse::<bool>() {
+                        store.dispatch( Todos( ToggleAll(completed) ) );
                     }
                 },
                 _ => (),
             }
         }
-        // And render the now updated todo list
-        return render(res, "./src/todos.tpl", store.get_state())
+        // Post/Redirect/Get: send the browser back to the page it came from
+        // so a reload doesn't submit the action again
+        return redirect(res, &back_to(_req, &list))
     });
 
-    // Let's clone it again for the next closure
-    let store = store_container.clone();
+    // A search form sent with GET would put spaces in the query string as + and
+    // leave colons as they are, and nickel's router doesn't match URLs like that.
+    // So the search box posts here and we send the browser on to a ?q= it can route
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+    server.post("/lists/:list/search", middleware! { |req, res|
+        let list = match check_access(req, &auth_clone, &stores_clone, false) {
+            Access::Granted(_, list, _) => list,
+            denied => return deny(res, denied),
+        };
+        let q = match form_field(req, "q") {
+            Ok(q) => q,
+            Err(e) => return error_page(res, e),
+        };
+        return match q.trim() {
+            "" => redirect(res, &list.base_url()),
+            q => redirect(res, &format!("{}?q={}", list.base_url(), encode_query_value(q))),
+        }
+    });
 
-    server.post("/*", middleware! { |req, res|
-        let mut store = store.lock().unwrap();
-        let form_body = req.form_body().ok().unwrap();
-        if let Some(new_todo) = form_body.get("todo") {
-            if new_todo.len() > 0 {
-                store.dispatch( Todos( Add(new_todo.to_string()) ) );
-            }
+    // Let's clone them again for the next closure
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+
+    // Undo and redo move through the store's history, clear-completed
+    // moves everything that's done to the trash and empty-trash empties it.
+    // Rename, archive, unarchive and delete are about the list itself
+    server.post("/lists/:list/:action", middleware! { |_req, res|
+        let (username, list, store) = match check_access(_req, &auth_clone, &stores_clone, true) {
+            Access::Granted(username, list, store) => (username, list, store),
+            denied => return deny(res, denied),
+        };
+        let action = _req.param("action").unwrap_or("").to_string();
+        let changed = match action.as_str() {
+            "rename" => match form_field(_req, "name") {
+                Ok(name) => Some(stores_clone.rename_list(&username, list.id, &name)),
+                Err(e) => Some(Err(e)),
+            },
+            "archive" => Some(stores_clone.archive_list(&username, list.id, true)),
+            "unarchive" => Some(stores_clone.archive_list(&username, list.id, false)),
+            // Back to whichever list is the default now
+            "delete" => return match stores_clone.delete_list(&username, list.id) {
+                Ok(_) => redirect(res, "/"),
+                Err(e) => error_page(res, e),
+            },
+            _ => None,
+        };
+        match changed {
+            Some(Ok(list)) => return redirect(res, &back_to(_req, &list)),
+            Some(Err(e)) => return error_page(res, e),
+            None => (),
+        }
+
+        let mut store = store.lock();
+        match _req.param("action").unwrap_or("") {
+            "undo" => { store.undo(); },
+            "redo" => { store.redo(); },
+            "clear-completed" => store.dispatch( Todos( ClearCompleted ) ),
+            "empty-trash" => store.dispatch( Todos( EmptyTrash ) ),
+            _ => (),
+        }
+        return redirect(res, &back_to(_req, &list))
+    });
+
+    // Let's clone them again for the next closure
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+
+    // The new list form posts its name here, and we go straight to the new list
+    server.post("/lists", middleware! { |req, res|
+        let username = match check_access(req, &auth_clone, &stores_clone, true) {
+            Access::Granted(username, _, _) => username,
+            denied => return deny(res, denied),
+        };
+        let name = match form_field(req, "name") {
+            Ok(name) => name,
+            Err(e) => return error_page(res, e),
+        };
+        return match stores_clone.create_list(&username, &name) {
+            Ok(list) => redirect(res, &list.base_url()),
+            Err(e) => error_page(res, e),
+        }
+    });
+
+    // Let's clone them again for the next closure
+    let (auth_clone, stores_clone) = (auth.clone(), stores.clone());
+
+    // The new todo form posts to the list itself
+    server.post("/lists/:list", middleware! { |req, res|
+        let (list, store) = match check_access(req, &auth_clone, &stores_clone, true) {
+            Access::Granted(_, list, store) => (list, store),
+            denied => return deny(res, denied),
+        };
+        let new_todo = match form_field(req, "todo") {
+            Ok(new_todo) => new_todo,
+            Err(e) => return error_page(res, e),
+        };
+        if new_todo.len() > 0 {
+            store.lock().dispatch( Todos( Add(new_todo) ) );
         }
 
-        return render(res, "./src/todos.tpl", store.get_state())
+        return redirect(res, &back_to(req, &list))
     });
 
-    server.listen("0.0.0.0:3000");
+    server.listen(config.listen.as_str());
 }
@@ -0,0 +1,81 @@
+use config;
+use store::{ State, Action };
+use store::Action::{ Todos };
+use todo::{ parse_date, parse_tag };
+use todo::TodoAction::{ Add, AddTag, Edit, Remove, RemoveTag, SetDue };
+
+// What a middleware wants to happen with the action it was given
+pub enum Next<A> {
+    // Hand the action, changed or not, to the next middleware and finally the reducer
+    Continue(A),
+    // Drop the action, the reducer never sees it
+    Stop,
+    // Drop the action and dispatch these instead, each one starts over from
+    // the top of the middleware chain
+    Dispatch(Vec<A>),
+}
+
+// In Redux a middleware is `store => next => action => ...` and calls next(action)
+// itself. Here a middleware gets the current state and the action and returns
+// what should happen next, which keeps the borrow checker happy
+pub type Middleware<S, A> = fn(&S, A) -> Next<A>;
+
+// Prints every action on its way to the reducer
+pub fn logger(state: &State, action: Action) -> Next<Action> {
+    println!("Dispatching {:?} with {} todos in the list", action, state.todos.len());
+    Next::Continue(action)
+}
+
+// Trims todo titles and refuses to add todos that are empty or too long.
+// Editing a title down to nothing removes the todo, like in TodoMVC.
+// Due dates and tags are checked and written the way they're stored
+#[allow(unused_variables)]
+pub fn validate(state: &State, action: Action) -> Next<Action> {
+    match action {
+        Todos(Add(title)) => {
+            let title = title.trim().to_string();
+            let max_title_length = config::limits().max_title_length;
+            if title.len() == 0 {
+                println!("Refused to add a todo with an empty title");
+                Next::Stop
+            } else if title.chars().count() > max_title_length {
+                println!("Refused to add a todo longer than {} characters", max_title_length);
+                Next::Stop
+            } else {
+                Next::Continue( Todos( Add(title) ) )
+            }
+        },
+        Todos(Edit(todo_id, title)) => {
+            let title = title.trim().to_string();
+            let max_title_length = config::limits().max_title_length;
+            if title.len() == 0 {
+                Next::Dispatch(vec![ Todos( Remove(todo_id) ) ])
+            } else if title.chars().count() > max_title_length {
+                println!("Refused to edit a todo to more than {} characters", max_title_length);
+                Next::Stop
+            } else {
+                Next::Continue( Todos( Edit(todo_id, title) ) )
+            }
+        },
+        Todos(SetDue(todo_id, Some(due))) => match parse_date(&due) {
+            Some(due) => Next::Continue( Todos( SetDue(todo_id, Some(due)) ) ),
+            None => {
+                println!("Refused a due date that isn't a date: {}", due);
+                Next::Stop
+            },
+        },
+        Todos(AddTag(todo_id, tag)) => match parse_tag(&tag) {
+            Some(tag) => Next::Continue( Todos( AddTag(todo_id, tag) ) ),
+            None => {
+                println!("Refused a tag that isn't a single short word: {}", tag);
+                Next::Stop
+            },
+        },
+        // A tag that could never have been added can't be removed either
+        Todos(RemoveTag(todo_id, tag)) => match parse_tag(&tag) {
+            Some(tag) => Next::Continue( Todos( RemoveTag(todo_id, tag) ) ),
+            None => Next::Stop,
+        },
+        action => Next::Continue(action),
+    }
+}
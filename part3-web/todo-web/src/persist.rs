@@ -0,0 +1,347 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use rustc_serialize::{ json, Encodable, Decodable };
+use storage::Storage;
+
+// The log and snapshot live next to each other in one data directory
+const LOG_FILE: &'static str = "actions.log";
+const SNAPSHOT_FILE: &'static str = "snapshot.json";
+
+// How many actions we let pile up in the log before writing a new snapshot
+pub const SNAPSHOT_INTERVAL: u64 = 100;
+
+// Every line in the log is one Record. The sequence number lets us tell which
+// records are already part of the snapshot, in case we crashed between writing
+// a snapshot and emptying the log
+#[derive(RustcEncodable, RustcDecodable)]
+struct Record<A> {
+    seq: u64,
+    action: A,
+}
+
+#[derive(RustcEncodable, RustcDecodable)]
+struct Snapshot<S> {
+    seq: u64,
+    state: S,
+}
+
+// What can be wrong with the tail of the log when we replay it
+#[derive(Debug)]
+pub enum Corruption {
+    // The last line was never finished, most likely we died mid-write
+    Truncated { line: usize },
+    // The line is complete but its content doesn't match its checksum
+    BadChecksum { line: usize },
+    // The checksum is fine but the record can't be decoded
+    BadRecord { line: usize, error: String },
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Corruption::Truncated { line } =>
+                write!(f, "line {} of the action log is truncated", line),
+            Corruption::BadChecksum { line } =>
+                write!(f, "line {} of the action log has a bad checksum", line),
+            Corruption::BadRecord { line, ref error } =>
+                write!(f, "line {} of the action log could not be decoded: {}", line, error),
+        }
+    }
+}
+
+// The result of replaying the log at startup
+pub struct Replay {
+    pub replayed: u64,
+    pub corruption: Option<Corruption>,
+    // How many bytes were cut off the end of the log because of the corruption
+    pub discarded_bytes: u64,
+}
+
+pub struct ActionLog {
+    dir: PathBuf,
+    log: File,
+    // Sequence number of the last action written, the last one committed
+    // and the last snapshot
+    seq: u64,
+    committed_seq: u64,
+    snapshot_seq: u64,
+}
+
+// FNV-1a, a tiny hash that is more than good enough to spot a mangled line
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut hash: u32 = 0x811c9dc5;
+    for byte in bytes {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash
+}
+
+fn invalid_data<E: fmt::Display>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+// Checks and decodes a single line (without the trailing newline)
+fn parse_line<A: Decodable>(line: &[u8], line_number: usize) -> Result<Record<A>, Corruption> {
+    let bad_record = |error: String| Corruption::BadRecord { line: line_number, error: error };
+
+    let text = try!(::std::str::from_utf8(line).map_err(|e| bad_record(e.to_string())));
+    // A line looks like `<checksum as 8 hex digits> <json record>`
+    if text.len() < 9 || !text.is_char_boundary(8) || &text[8..9] != " " {
+        return Err(bad_record("missing checksum".to_string()));
+    }
+    let expected = try!(u32::from_str_radix(&text[..8], 16).map_err(|e| bad_record(e.to_string())));
+    let body = &text[9..];
+    if checksum(body.as_bytes()) != expected {
+        return Err(Corruption::BadChecksum { line: line_number });
+    }
+    json::decode(body).map_err(|e| bad_record(e.to_string()))
+}
+
+impl ActionLog {
+    // Opens (or creates) the log in `dir`, loads the latest snapshot and replays
+    // every action logged after it through the reducer. A corrupted tail stops
+    // the replay, is reported in the returned Replay and cut off the log file so
+    // new actions aren't appended after garbage
+    pub fn open<P, S, A>(dir: P, initial_state: S, reducer: &mut dyn FnMut(&S, A) -> S)
+        -> io::Result<(ActionLog, S, Replay)>
+        where P: AsRef<Path>, S: Decodable, A: Decodable {
+        let dir = dir.as_ref().to_path_buf();
+        try!(fs::create_dir_all(&dir));
+
+        let (mut state, snapshot_seq) = match File::open(dir.join(SNAPSHOT_FILE)) {
+            Ok(mut file) => {
+                let mut contents = String::new();
+                try!(file.read_to_string(&mut contents));
+                let snapshot: Snapshot<S> = try!(json::decode(&contents).map_err(invalid_data));
+                (snapshot.state, snapshot.seq)
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (initial_state, 0),
+            Err(e) => return Err(e),
+        };
+
+        let mut log = try!(OpenOptions::new().read(true).write(true).create(true)
+            .open(dir.join(LOG_FILE)));
+        let mut contents = Vec::new();
+        try!(log.read_to_end(&mut contents));
+
+        let mut seq = snapshot_seq;
+        let mut replayed = 0;
+        let mut corruption = None;
+        let mut good_bytes = 0;
+
+        for (index, line) in contents.split(|byte| *byte == b'\n').enumerate() {
+            let line_start = good_bytes;
+            let line_end = line_start + line.len();
+            // split() gives us an empty last piece when the log ends with a newline
+            if line_end == contents.len() {
+                if !line.is_empty() {
+                    corruption = Some(Corruption::Truncated { line: index + 1 });
+                }
+                break;
+            }
+
+            match parse_line::<A>(line, index + 1) {
+                Ok(record) => {
+                    // Already part of the snapshot, skip it
+                    if record.seq > seq {
+                        state = reducer(&state, record.action);
+                        seq = record.seq;
+                        replayed += 1;
+                    }
+                    good_bytes = line_end + 1;
+                },
+                Err(e) => {
+                    corruption = Some(e);
+                    break;
+                },
+            }
+        }
+
+        let discarded_bytes = (contents.len() - good_bytes) as u64;
+        if discarded_bytes > 0 {
+            try!(log.set_len(good_bytes as u64));
+        }
+        drop(log);
+
+        // From now on we only ever append
+        let log = try!(OpenOptions::new().append(true).open(dir.join(LOG_FILE)));
+
+        let action_log = ActionLog {
+            dir: dir,
+            log: log,
+            seq: seq,
+            committed_seq: seq,
+            snapshot_seq: snapshot_seq,
+        };
+        let replay = Replay {
+            replayed: replayed,
+            corruption: corruption,
+            discarded_bytes: discarded_bytes,
+        };
+        Ok((action_log, state, replay))
+    }
+}
+
+// Whether there's a log or snapshot in `dir`
+pub fn has_journal<P: AsRef<Path>>(dir: P) -> bool {
+    [LOG_FILE, SNAPSHOT_FILE].iter().any(|name| dir.as_ref().join(name).exists())
+}
+
+// Moves the log and snapshot in `from` over to `to`, for when a store gets a
+// new data directory. Returns whether there was anything to move
+pub fn move_journal<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> io::Result<bool> {
+    let (from, to) = (from.as_ref(), to.as_ref());
+    let mut moved = false;
+    for name in &[LOG_FILE, SNAPSHOT_FILE] {
+        if from.join(name).exists() {
+            try!(fs::create_dir_all(to));
+            try!(fs::rename(from.join(name), to.join(name)));
+            moved = true;
+        }
+    }
+    Ok(moved)
+}
+
+impl<S: Encodable + Clone, A: Encodable + Clone> Storage<S, A> for ActionLog {
+    // Writes the action to disk, this has to succeed before we apply it
+    fn append(&mut self, action: &A) -> io::Result<()> {
+        let record = Record { seq: self.seq + 1, action: action.clone() };
+        let body = try!(json::encode(&record).map_err(invalid_data));
+        let line = format!("{:08x} {}\n", checksum(body.as_bytes()), body);
+        try!(self.log.write_all(line.as_bytes()));
+        try!(self.log.sync_data());
+        self.seq += 1;
+        Ok(())
+    }
+
+    // The action is already in the log, so there's only something to write
+    // every SNAPSHOT_INTERVAL actions. Undo and redo change the state without
+    // appending an action, the log can't replay those so we snapshot right away
+    fn commit(&mut self, state: &S) -> io::Result<()> {
+        let travelled = self.committed_seq == self.seq;
+        self.committed_seq = self.seq;
+        if travelled || self.seq - self.snapshot_seq >= SNAPSHOT_INTERVAL {
+            self.snapshot(state)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl ActionLog {
+    // Writes the full state to a temporary file and renames it into place, so a
+    // crash never leaves us with half a snapshot. Then the log can start over
+    fn snapshot<S: Encodable + Clone>(&mut self, state: &S) -> io::Result<()> {
+        let snapshot = Snapshot { seq: self.seq, state: state.clone() };
+        let contents = try!(json::encode(&snapshot).map_err(invalid_data));
+
+        let tmp_path = self.dir.join(format!("{}.tmp", SNAPSHOT_FILE));
+        {
+            let mut tmp = try!(File::create(&tmp_path));
+            try!(tmp.write_all(contents.as_bytes()));
+            try!(tmp.sync_all());
+        }
+        try!(fs::rename(&tmp_path, self.dir.join(SNAPSHOT_FILE)));
+
+        try!(self.log.set_len(0));
+        try!(self.log.sync_all());
+        self.snapshot_seq = self.seq;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{ self, File, OpenOptions };
+    use std::io::{ Read, Write };
+    use std::path::PathBuf;
+    use storage::Storage;
+    use store::{ Action, State, reducer };
+    use store::Action::{ Todos };
+    use todo::TodoAction::{ Add };
+    use super::{ ActionLog, Corruption, Replay, LOG_FILE };
+
+    // A fresh directory for each test, left behind to look at if it fails
+    fn log_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join("todo-web-tests").join(name);
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn open(dir: &PathBuf) -> (ActionLog, State, Replay) {
+        let mut reducer = reducer();
+        ActionLog::open(dir, State::default(), &mut *reducer).unwrap()
+    }
+
+    fn titles(state: &State) -> Vec<&str> {
+        state.todos.iter().map(|todo| todo.title.as_str()).collect()
+    }
+
+    fn add(log: &mut ActionLog, title: &str) {
+        Storage::<State, Action>::append(log, &Todos( Add(title.to_string()) )).unwrap();
+    }
+
+    // Logs an Add for each title, without ever getting to a snapshot, and
+    // returns what ended up in the log file
+    fn write_log(dir: &PathBuf, titles: &[&str]) -> Vec<u8> {
+        let (mut log, _, _) = open(dir);
+        for title in titles {
+            add(&mut log, title);
+        }
+        let mut contents = Vec::new();
+        File::open(dir.join(LOG_FILE)).unwrap().read_to_end(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn a_truncated_last_record_is_reported_and_cut_off() {
+        let dir = log_dir("truncated");
+        let contents = write_log(&dir, &["Pay rent", "Call mum", "Water the plants"]);
+        // We died halfway through writing the third line
+        let cut = contents.len() - 10;
+        OpenOptions::new().write(true).open(dir.join(LOG_FILE)).unwrap().set_len(cut as u64).unwrap();
+
+        let (mut log, state, replay) = open(&dir);
+        assert_eq!(titles(&state), vec!["Pay rent", "Call mum"]);
+        assert_eq!(replay.replayed, 2);
+        match replay.corruption {
+            Some(Corruption::Truncated { line: 3 }) => (),
+            ref corruption => panic!("expected line 3 to be truncated, got {:?}", corruption),
+        }
+        let kept = contents.iter().take(cut).rposition(|byte| *byte == b'\n').unwrap() + 1;
+        assert_eq!(replay.discarded_bytes, (cut - kept) as u64);
+        assert_eq!(fs::metadata(dir.join(LOG_FILE)).unwrap().len(), kept as u64);
+
+        // New actions go after the last good record, not after the garbage
+        add(&mut log, "Buy milk");
+        drop(log);
+        let (_, state, replay) = open(&dir);
+        assert_eq!(titles(&state), vec!["Pay rent", "Call mum", "Buy milk"]);
+        assert!(replay.corruption.is_none());
+    }
+
+    #[test]
+    fn a_flipped_byte_in_the_last_record_is_reported_and_cut_off() {
+        let dir = log_dir("flipped");
+        let mut contents = write_log(&dir, &["Pay rent", "Call mum", "Water the plants"]);
+        let last_line = contents[..contents.len() - 1].iter().rposition(|byte| *byte == b'\n').unwrap() + 1;
+        // Water the plants becomes Water the planes
+        let t = contents.len() - 1 - contents.iter().rev().position(|byte| *byte == b't').unwrap();
+        contents[t] ^= b't' ^ b'e';
+        File::create(dir.join(LOG_FILE)).unwrap().write_all(&contents).unwrap();
+
+        let (_, state, replay) = open(&dir);
+        assert_eq!(titles(&state), vec!["Pay rent", "Call mum"]);
+        assert_eq!(replay.replayed, 2);
+        match replay.corruption {
+            Some(Corruption::BadChecksum { line: 3 }) => (),
+            ref corruption => panic!("expected a bad checksum on line 3, got {:?}", corruption),
+        }
+        assert_eq!(replay.discarded_bytes, (contents.len() - last_line) as u64);
+        assert_eq!(fs::metadata(dir.join(LOG_FILE)).unwrap().len(), last_line as u64);
+    }
+}
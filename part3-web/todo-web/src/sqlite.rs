@@ -0,0 +1,305 @@
+use std::collections::{ BTreeSet, HashMap };
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use rusqlite::{ Connection, Row };
+use rustc_serialize::json;
+use persist::Replay;
+use storage::Storage;
+use store::{ Action, State };
+use todo::{ Todo, TodoId, Priority };
+
+// The whole list in one SQLite database next to where the action log would be
+const DATABASE_FILE: &'static str = "todos.sqlite";
+
+// A row for every todo, keyed by its id, and `position` keeps them in the
+// order they're listed in. Tags are one space separated string since they're
+// single words. The meta table holds the id the next todo gets, and actions
+// holds every action dispatched since the todos were last written
+const SCHEMA: &'static str = "
+    CREATE TABLE IF NOT EXISTS todos (
+        id INTEGER PRIMARY KEY,
+        position INTEGER NOT NULL,
+        title TEXT NOT NULL,
+        completed INTEGER NOT NULL,
+        deleted INTEGER NOT NULL,
+        deleted_at INTEGER,
+        due TEXT,
+        priority TEXT,
+        tags TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS meta (
+        key TEXT PRIMARY KEY,
+        value INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS actions (
+        seq INTEGER PRIMARY KEY,
+        action TEXT NOT NULL
+    );
+";
+
+// Every action is journaled in the actions table before it's applied, and the
+// state is written after every dispatch in the same transaction that empties
+// the journal. If writing the state fails the actions stay, they're replayed
+// when the list is opened and the next commit catches up on the todos. Only
+// the todos that changed since the last commit are written, which is what
+// makes this cheaper than a JSON snapshot on big lists
+pub struct SqliteStorage {
+    conn: Connection,
+    // What the database holds right now, with each todo's position
+    committed: HashMap<TodoId, (usize, Todo)>,
+    next_id: TodoId,
+    // The last action in the journal
+    seq: i64,
+}
+
+fn io_error<E: fmt::Display>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, error.to_string())
+}
+
+fn priority_name(priority: Priority) -> &'static str {
+    match priority {
+        Priority::Low => "low",
+        Priority::Medium => "medium",
+        Priority::High => "high",
+    }
+}
+
+fn read_todo(row: &Row) -> Todo {
+    let id: i64 = row.get(0);
+    let deleted_at: Option<i64> = row.get(5);
+    let priority: Option<String> = row.get(7);
+    let tags: String = row.get(8);
+    Todo {
+        id: id as TodoId,
+        title: row.get(2),
+        completed: row.get(3),
+        deleted: row.get(4),
+        deleted_at: deleted_at.map(|at| at as u64),
+        due: row.get(6),
+        priority: priority.and_then(|name| Priority::from_name(&name)),
+        tags: tags.split_whitespace().map(|tag| tag.to_string()).collect(),
+    }
+}
+
+fn write_todo(conn: &Connection, position: usize, todo: &Todo) -> io::Result<()> {
+    let tags: Vec<&str> = todo.tags.iter().map(|tag| tag.as_str()).collect();
+    let tags = tags.join(" ");
+    let priority = todo.priority.map(priority_name);
+    let deleted_at = todo.deleted_at.map(|at| at as i64);
+    try!(conn.execute(
+        "INSERT OR REPLACE INTO todos (id, position, title, completed, deleted, deleted_at, due, priority, tags)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        &[&(todo.id as i64), &(position as i64), &todo.title, &todo.completed, &todo.deleted,
+          &deleted_at, &todo.due, &priority, &tags]).map_err(io_error));
+    Ok(())
+}
+
+impl SqliteStorage {
+    // Opens (or creates) the database in `dir`, loads the list from it and
+    // replays the journaled actions through the reducer. An empty database
+    // starts out with `initial_state`
+    pub fn open<P: AsRef<Path>>(dir: P, initial_state: State, reducer: &mut dyn FnMut(&State, Action) -> State)
+        -> io::Result<(SqliteStorage, State, Replay)> {
+        let dir = dir.as_ref();
+        try!(fs::create_dir_all(dir));
+        let conn = try!(Connection::open(dir.join(DATABASE_FILE)).map_err(io_error));
+        try!(conn.execute_batch(SCHEMA).map_err(io_error));
+
+        let todos: Vec<Todo> = {
+            let mut statement = try!(conn.prepare(
+                "SELECT id, position, title, completed, deleted, deleted_at, due, priority, tags
+                 FROM todos ORDER BY position").map_err(io_error));
+            let rows = try!(statement.query_map(&[], read_todo).map_err(io_error));
+            let mut todos = Vec::new();
+            for todo in rows {
+                todos.push(try!(todo.map_err(io_error)));
+            }
+            todos
+        };
+        let next_id: Option<i64> = match conn.query_row("SELECT value FROM meta WHERE key = 'next_id'", &[], |row| row.get(0)) {
+            Ok(next_id) => Some(next_id),
+            Err(::rusqlite::Error::QueryReturnedNoRows) => None,
+            Err(e) => return Err(io_error(e)),
+        };
+
+        let actions: Vec<(i64, String)> = {
+            let mut statement = try!(conn.prepare("SELECT seq, action FROM actions ORDER BY seq").map_err(io_error));
+            let rows = try!(statement.query_map(&[], |row| (row.get(0), row.get(1))).map_err(io_error));
+            let mut actions = Vec::new();
+            for action in rows {
+                actions.push(try!(action.map_err(io_error)));
+            }
+            actions
+        };
+
+        let mut state = match next_id {
+            Some(next_id) => State { todos: todos, next_id: next_id as TodoId },
+            None => initial_state,
+        };
+        let committed = if next_id.is_some() {
+            state.todos.iter().cloned().enumerate().map(|(position, todo)| (todo.id, (position, todo))).collect()
+        } else {
+            HashMap::new()
+        };
+        let storage_next_id = if next_id.is_some() { state.next_id } else { 0 };
+
+        // The committed todos stay what the database holds, so the first
+        // commit writes what the replay changed and empties the journal
+        let mut seq = 0;
+        for &(action_seq, ref action) in &actions {
+            let action: Action = try!(json::decode(action).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("journaled action {} could not be decoded: {}", action_seq, e))
+            }));
+            state = reducer(&state, action);
+            seq = action_seq;
+        }
+
+        let storage = SqliteStorage {
+            conn: conn,
+            committed: committed,
+            // Makes sure the first commit writes next_id when it's a new database
+            next_id: storage_next_id,
+            seq: seq,
+        };
+        let replay = Replay { replayed: actions.len() as u64, corruption: None, discarded_bytes: 0 };
+        Ok((storage, state, replay))
+    }
+}
+
+// Whether there's a database in `dir`
+pub fn has_database<P: AsRef<Path>>(dir: P) -> bool {
+    dir.as_ref().join(DATABASE_FILE).exists()
+}
+
+// Moves the database in `from` over to `to`. Returns whether there was one
+pub fn move_database<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> io::Result<bool> {
+    let (from, to) = (from.as_ref(), to.as_ref());
+    if !from.join(DATABASE_FILE).exists() {
+        return Ok(false);
+    }
+    try!(fs::create_dir_all(to));
+    try!(fs::rename(from.join(DATABASE_FILE), to.join(DATABASE_FILE)));
+    Ok(true)
+}
+
+impl Storage<State, Action> for SqliteStorage {
+    // Journals the action, this has to succeed before we apply it
+    fn append(&mut self, action: &Action) -> io::Result<()> {
+        let encoded = try!(json::encode(action).map_err(io_error));
+        try!(self.conn.execute("INSERT INTO actions (seq, action) VALUES (?, ?)",
+                               &[&(self.seq + 1), &encoded]).map_err(io_error));
+        self.seq += 1;
+        Ok(())
+    }
+
+    // Writes the todos that were added, changed or moved, deletes the ones
+    // that are gone and empties the journal. Either all of it makes it to disk
+    // or none of it does, and then the journal still has every action
+    fn commit(&mut self, state: &State) -> io::Result<()> {
+        let transaction = try!(self.conn.transaction().map_err(io_error));
+        for (position, todo) in state.todos.iter().enumerate() {
+            match self.committed.get(&todo.id) {
+                Some(&(committed_position, ref committed)) if committed_position == position && committed == todo => (),
+                _ => try!(write_todo(&transaction, position, todo)),
+            }
+        }
+        let kept: BTreeSet<TodoId> = state.todos.iter().map(|todo| todo.id).collect();
+        for id in self.committed.keys().filter(|id| !kept.contains(id)) {
+            try!(transaction.execute("DELETE FROM todos WHERE id = ?", &[&(*id as i64)]).map_err(io_error));
+        }
+        if state.next_id != self.next_id {
+            try!(transaction.execute("INSERT OR REPLACE INTO meta (key, value) VALUES ('next_id', ?)",
+                                     &[&(state.next_id as i64)]).map_err(io_error));
+        }
+        try!(transaction.execute("DELETE FROM actions", &[]).map_err(io_error));
+        try!(transaction.commit().map_err(io_error));
+
+        self.committed = state.todos.iter().cloned().enumerate().map(|(position, todo)| (todo.id, (position, todo))).collect();
+        self.next_id = state.next_id;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::path::PathBuf;
+    use rusqlite::Connection;
+    use persist::Replay;
+    use storage::Storage;
+    use store::{ Action, State, reducer };
+    use store::Action::{ Todos };
+    use todo::TodoAction::{ Add, Toggle };
+    use super::{ DATABASE_FILE, SqliteStorage };
+
+    // A fresh directory for each test, left behind to look at if it fails
+    fn database_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join("todo-web-tests").join(name);
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn open(dir: &PathBuf) -> io::Result<(SqliteStorage, State, Replay)> {
+        let mut reducer = reducer();
+        SqliteStorage::open(dir, State::default(), &mut *reducer)
+    }
+
+    // Journals the action and applies it, like the store does
+    fn dispatch(storage: &mut SqliteStorage, state: State, action: Action) -> State {
+        storage.append(&action).unwrap();
+        let mut reducer = reducer();
+        reducer(&state, action)
+    }
+
+    fn titles(state: &State) -> Vec<(&str, bool)> {
+        state.todos.iter().map(|todo| (todo.title.as_str(), todo.completed)).collect()
+    }
+
+    #[test]
+    fn replays_the_actions_that_were_never_committed() {
+        let dir = database_dir("sqlite-replay");
+        {
+            let (mut storage, state, _) = open(&dir).unwrap();
+            let state = dispatch(&mut storage, state, Todos( Add("Pay rent".to_string()) ));
+            storage.commit(&state).unwrap();
+            // We die before these two get committed
+            let state = dispatch(&mut storage, state, Todos( Add("Call mum".to_string()) ));
+            dispatch(&mut storage, state, Todos( Toggle(1) ));
+        }
+
+        let (mut storage, state, replay) = open(&dir).unwrap();
+        assert_eq!(titles(&state), vec![("Pay rent", true), ("Call mum", false)]);
+        assert_eq!(state.next_id, 3);
+        assert_eq!(replay.replayed, 2);
+        assert!(replay.corruption.is_none());
+
+        // Committing catches the todos up and empties the journal
+        storage.commit(&state).unwrap();
+        drop(storage);
+        let (_, state, replay) = open(&dir).unwrap();
+        assert_eq!(titles(&state), vec![("Pay rent", true), ("Call mum", false)]);
+        assert_eq!(replay.replayed, 0);
+    }
+
+    #[test]
+    fn a_journaled_action_that_cannot_be_decoded_is_an_error() {
+        let dir = database_dir("sqlite-bad-action");
+        {
+            let (mut storage, state, _) = open(&dir).unwrap();
+            dispatch(&mut storage, state, Todos( Add("Pay rent".to_string()) ));
+        }
+        let conn = Connection::open(dir.join(DATABASE_FILE)).unwrap();
+        conn.execute("INSERT INTO actions (seq, action) VALUES (2, 'not an action')", &[]).unwrap();
+        drop(conn);
+
+        match open(&dir) {
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => (),
+            Err(e) => panic!("expected invalid data, got {}", e),
+            Ok(_) => panic!("opened a database with an action that can't be decoded"),
+        }
+    }
+}
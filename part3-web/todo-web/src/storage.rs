@@ -0,0 +1,105 @@
+use std::io;
+use std::path::Path;
+use persist::{ self, ActionLog, Replay };
+use sqlite::{ self, SqliteStorage };
+use store::{ Action, State };
+
+// Where a store keeps its state between restarts. The store is loaded from
+// it when it's opened, hands it every action before applying it and commits
+// the new state after every dispatch, undo and redo
+pub trait Storage<S, A> {
+    // If this fails the action is dropped instead of applied
+    fn append(&mut self, action: &A) -> io::Result<()>;
+    fn commit(&mut self, state: &S) -> io::Result<()>;
+}
+
+pub type TodoStorage = Box<dyn Storage<State, Action> + Send>;
+
+// The storage every list is kept in, picked with the storage setting
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    // The action log with JSON snapshots, see persist.rs
+    File,
+    // A SQLite database with a row for every todo, see sqlite.rs
+    Sqlite,
+}
+
+// When a list is migrated, the files of the backend it came from are moved
+// in here in case they're ever needed again
+const MIGRATED_DIR: &'static str = "migrated";
+
+impl Backend {
+    pub fn from_name(name: &str) -> Option<Backend> {
+        match name {
+            "file" | "json" => Some(Backend::File),
+            "sqlite" => Some(Backend::Sqlite),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Backend::File => "file",
+            Backend::Sqlite => "sqlite",
+        }
+    }
+
+    fn other(&self) -> Backend {
+        match *self {
+            Backend::File => Backend::Sqlite,
+            Backend::Sqlite => Backend::File,
+        }
+    }
+
+    fn has_data(&self, dir: &Path) -> bool {
+        match *self {
+            Backend::File => persist::has_journal(dir),
+            Backend::Sqlite => sqlite::has_database(dir),
+        }
+    }
+
+    fn move_data(&self, from: &Path, to: &Path) -> io::Result<bool> {
+        match *self {
+            Backend::File => persist::move_journal(from, to),
+            Backend::Sqlite => sqlite::move_database(from, to),
+        }
+    }
+
+    fn open(&self, dir: &Path, initial_state: State, reducer: &mut dyn FnMut(&State, Action) -> State)
+        -> io::Result<(TodoStorage, State, Replay)> {
+        match *self {
+            Backend::File => {
+                let (log, state, replay) = try!(ActionLog::open(dir, initial_state, reducer));
+                Ok((Box::new(log), state, replay))
+            },
+            Backend::Sqlite => {
+                let (database, state, replay) = try!(SqliteStorage::open(dir, initial_state, reducer));
+                Ok((Box::new(database), state, replay))
+            },
+        }
+    }
+}
+
+// Opens the list kept in `dir` with `backend` and loads its state. A list that
+// has only ever been saved with the other backend is migrated the first time
+// around, it's loaded from there, committed to `backend` and the old files are
+// moved to `dir`/migrated. Switching the storage setting back and forth
+// migrates the lists back and forth with it
+pub fn open(backend: Backend, dir: &Path, reducer: &mut dyn FnMut(&State, Action) -> State)
+    -> io::Result<(TodoStorage, State, Replay)> {
+    let other = backend.other();
+    if backend.has_data(dir) || !other.has_data(dir) {
+        return backend.open(dir, State::default(), reducer);
+    }
+
+    // The old storage is closed again before its files are moved
+    let (state, replay) = {
+        let (_, state, replay) = try!(other.open(dir, State::default(), reducer));
+        (state, replay)
+    };
+    let (mut storage, state, _) = try!(backend.open(dir, state, reducer));
+    try!(storage.commit(&state));
+    try!(other.move_data(dir, &dir.join(MIGRATED_DIR)));
+    println!("Migrated the list in {} from {} to {} storage", dir.display(), other.name(), backend.name());
+    Ok((storage, state, replay))
+}
@@ -1,18 +1,44 @@
-use store::Action::{ Visibility };
+use std::mem;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use rustc_serialize::{ Decodable, Decoder };
 use rustc_serialize::json::{self, Json, ToJson};
-use todo::{ Todo, TodoAction, todo_reducer };
+use todo::{ Todo, TodoId, TodoAction, todo_reducer, next_id_reducer, parse_tag, today };
+use query::{ self, Query, SortKey, Term };
+use storage::Storage;
+use history::{ History, DEFAULT_HISTORY_LIMIT };
+use middleware::{ Middleware, Next };
+use subscription::{ Listeners, Subscription };
+use error::{ lock, read, write };
 
-#[derive(Clone, Debug, RustcEncodable, RustcDecodable)]
+// How deep middleware can re-dispatch actions before we assume it's looping
+const MAX_DISPATCH_DEPTH: usize = 16;
+
+#[derive(Clone, Debug, RustcEncodable)]
 pub struct State {
     pub todos: Vec<Todo>,
-    pub visibility_filter: VisibilityFilter
+    // The id the next added todo will get
+    pub next_id: TodoId,
+}
+
+// Written out by hand so snapshots saved before there was a next_id still
+// load. Without one, the next id is one past the highest id in the list
+impl Decodable for State {
+    fn decode<D: Decoder>(d: &mut D) -> Result<State, D::Error> {
+        d.read_struct("State", 2, |d| {
+            let todos: Vec<Todo> = try!(d.read_struct_field("todos", 0, Decodable::decode));
+            let next_id: Option<TodoId> = try!(d.read_struct_field("next_id", 1, Decodable::decode));
+            let next_id = next_id.unwrap_or_else(|| todos.iter().map(|todo| todo.id + 1).max().unwrap_or(1));
+            Ok(State { todos: todos, next_id: next_id })
+        })
+    }
 }
 impl State {
     // This gives us a quick way to initialize a default state with State::default()
     pub fn default() -> State {
         State {
             todos: Vec::new(),
-            visibility_filter: VisibilityFilter::ShowAll,
+            next_id: 1,
         }
     }
 }
@@ -23,75 +49,523 @@ impl ToJson for State {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, RustcEncodable, RustcDecodable)]
 pub enum Action {
     Todos(TodoAction),
+    // The filter used to live in the shared State, so one user clicking "Completed"
+    // switched everyone's view. It's picked per request now and this is only
+    // here so older action logs still replay, the reducer ignores it
     Visibility(VisibilityFilter),
 }
 
-#[derive(Clone, Debug, RustcEncodable, RustcDecodable)]
+#[derive(Clone, Debug, PartialEq, RustcEncodable, RustcDecodable)]
 pub enum VisibilityFilter {
     ShowActive,
     ShowAll,
     ShowCompleted,
 }
 
-// Our main reducer, returns a new State with the results of the child-reducers
-// No combineReducers is implemented here, so it calls the child reducers
-// by function name
-pub fn reducer(state: &State, action: Action) -> State {
-    // Always return a new state
-    State {
-        todos: todo_reducer(&state.todos, &action),
-        visibility_filter: visibility_reducer(&state.visibility_filter, &action),
+impl VisibilityFilter {
+    // The filter for a `?filter=` query parameter
+    pub fn from_query(value: &str) -> Option<VisibilityFilter> {
+        match value {
+            "all" => Some(VisibilityFilter::ShowAll),
+            "active" => Some(VisibilityFilter::ShowActive),
+            "completed" => Some(VisibilityFilter::ShowCompleted),
+            _ => None,
+        }
+    }
+
+    pub fn to_query(&self) -> &'static str {
+        match *self {
+            VisibilityFilter::ShowAll => "all",
+            VisibilityFilter::ShowActive => "active",
+            VisibilityFilter::ShowCompleted => "completed",
+        }
+    }
+
+    pub fn shows(&self, todo: &Todo) -> bool {
+        match *self {
+            VisibilityFilter::ShowAll => true,
+            VisibilityFilter::ShowActive => !todo.completed,
+            VisibilityFilter::ShowCompleted => todo.completed,
+        }
     }
 }
 
-// Very simple reducer since the action will either be a VisibilityFilter, in which
-// case we will return that, otherwise just return the incoming state
-fn visibility_reducer(state: &VisibilityFilter, action: &Action) -> VisibilityFilter {
-    match *action {
-        Visibility(ref vis_action) => vis_action.clone(),
-        _ => state.clone(),
+// The orders todos can be listed in, picked with ?sort=
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortBy {
+    // The order of the list, which is the order they were added in until
+    // someone moves them around
+    Created,
+    // Soonest first, todos without a due date last
+    Due,
+    // Highest first, todos without a priority last
+    Priority,
+    Title,
+}
+
+impl SortBy {
+    pub fn from_query(value: &str) -> Option<SortBy> {
+        match value {
+            "created" => Some(SortBy::Created),
+            "due" => Some(SortBy::Due),
+            "priority" => Some(SortBy::Priority),
+            "title" => Some(SortBy::Title),
+            _ => None,
+        }
+    }
+
+    pub fn to_query(&self) -> &'static str {
+        match *self {
+            SortBy::Created => "created",
+            SortBy::Due => "due",
+            SortBy::Priority => "priority",
+            SortBy::Title => "title",
+        }
+    }
+}
+
+// Everything a request picks about which todos it sees and in what order.
+// Like the filter none of it is stored, so people sharing a list each get their own
+#[derive(Clone, Debug, PartialEq)]
+pub struct View {
+    pub filter: VisibilityFilter,
+    // Only todos with this tag, if it's set
+    pub tag: Option<String>,
+    pub sort: SortBy,
+    // What was typed into the search box, see query.rs, and what it parsed to
+    pub search_text: String,
+    pub search: Query,
+}
+
+impl View {
+    pub fn default() -> View {
+        View {
+            filter: VisibilityFilter::ShowAll,
+            tag: None,
+            sort: SortBy::Created,
+            search_text: String::new(),
+            search: Query::default(),
+        }
+    }
+
+    // The view for the ?filter=, ?tag=, ?sort= and ?q= query parameters, each
+    // one that's missing keeps its default
+    pub fn from_query(filter: Option<&str>, tag: Option<&str>, sort: Option<&str>, q: Option<&str>) -> Result<View, String> {
+        let mut view = View::default();
+        if let Some(filter) = filter {
+            view.filter = try!(VisibilityFilter::from_query(filter)
+                .ok_or("filter must be all, active or completed".to_string()));
+        }
+        if let Some(tag) = tag {
+            view.tag = Some(try!(parse_tag(tag).ok_or("tag must be a single word".to_string())));
+        }
+        if let Some(sort) = sort {
+            view.sort = try!(SortBy::from_query(sort)
+                .ok_or("sort must be created, due, priority or title".to_string()));
+        }
+        if let Some(q) = q {
+            view.search = try!(query::parse(q).map_err(|e| format!("Could not understand the search, {}", e)));
+            view.search_text = q.trim().to_string();
+        }
+        Ok(view)
+    }
+
+    // The filter, tag and order from the links plus the search as one Query.
+    // The search's own sort keys go first, the link's order breaks ties
+    pub fn query(&self) -> Query {
+        let mut query = self.search.clone();
+        query.terms.push(Term::Status(self.filter.clone()));
+        if let Some(ref tag) = self.tag {
+            query.terms.push(Term::Tag(tag.clone()));
+        }
+        match self.sort {
+            SortBy::Created => (),
+            // The link sorts by priority highest first
+            SortBy::Priority => query.sort.push(SortKey { by: SortBy::Priority, descending: true }),
+            by => query.sort.push(SortKey { by: by, descending: false }),
+        }
+        query
+    }
+
+    // The query string that asks for this view, empty for the default one.
+    // Tags are plain words so only the search needs escaping
+    pub fn to_query(&self) -> String {
+        let mut parameters = Vec::new();
+        if self.filter != VisibilityFilter::ShowAll {
+            parameters.push(format!("filter={}", self.filter.to_query()));
+        }
+        if let Some(ref tag) = self.tag {
+            parameters.push(format!("tag={}", tag));
+        }
+        if self.sort != SortBy::Created {
+            parameters.push(format!("sort={}", self.sort.to_query()));
+        }
+        if !self.search_text.is_empty() {
+            parameters.push(format!("q={}", encode_query_value(&self.search_text)));
+        }
+        if parameters.is_empty() { String::new() } else { format!("?{}", parameters.join("&")) }
     }
 }
 
-// Redux store implementation
-pub struct Store {
-    state: State,
-    listeners: Vec<fn(&State)>,
-    reducer: fn(&State, Action) -> State,
+// Percent-encodes everything but letters, digits, - and _ so any search can go
+// in a link. Nickel's router only matches query strings made of those, % , = and &,
+// so even spaces have to be %20 instead of +
+pub fn encode_query_value(value: &str) -> String {
+    value.bytes().map(|byte| match byte {
+        b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' | b'-' | b'_' => (byte as char).to_string(),
+        _ => format!("%{:02X}", byte),
+    }).collect()
 }
 
-impl Store {
-    // Takes a reducer function, we skip the initial_state and optional arguments
-    // TO keep it simple, State::default() from earlier is our initial_state implementation
-    pub fn create_store(reducer: fn(&State, Action) -> State) -> Store {
+// Selector for the todos a view shows, in the order it shows them. Deleted
+// todos never show up
+pub fn select_todos<'a>(state: &'a State, view: &View) -> Vec<&'a Todo> {
+    view.query().select(&state.todos, &today())
+}
+
+// A reducer takes the current state and an action and returns the next state.
+// Boxing a closure instead of using a plain fn pointer means it can capture things,
+// and Send lets the store live behind the Mutex in SharedStore
+pub type Reducer<S, A> = Box<dyn FnMut(&S, A) -> S + Send>;
+
+// Listeners are called with the new state after every change
+pub type Listener<S> = Box<dyn FnMut(&S) + Send>;
+
+// The store our todo app runs on
+pub type TodoStore = Store<State, Action>;
+pub type SharedTodoStore = SharedStore<State, Action>;
+
+// Where the store puts every new state for readers to pick up. The lock is only
+// held long enough to swap or clone the Arc, never while anyone reads the state
+type Published<S> = Arc<RwLock<Arc<S>>>;
+
+// Calls a field reducer from combine_reducers!. Going through a generic function
+// lets Rust work out the types of the closure arguments for us
+pub fn field_reducer<S, A, T, F: Fn(&S, &A) -> T>(reducer: F, state: &S, action: &A) -> T {
+    reducer(state, action)
+}
+
+// Our take on combineReducers from Redux: builds the reducer for the whole state
+// out of one reducer per field. Each field reducer gets the previous state and
+// the action and returns the new value for its field
+macro_rules! combine_reducers {
+    ($state:ident, $action:ty { $($field:ident: $reducer:expr),+ $(,)* }) => {
+        Box::new(|state: &$state, action: $action| {
+            // Always return a new state
+            $state {
+                $( $field: field_reducer($reducer, state, &action), )+
+            }
+        })
+    };
+}
+
+// Our main reducer, returns a new State with the results of the child-reducers
+pub fn reducer() -> Reducer<State, Action> {
+    combine_reducers!(State, Action {
+        todos: |state, action| todo_reducer(&state.todos, state.next_id, action),
+        next_id: |state, action| next_id_reducer(state.next_id, action),
+    })
+}
+
+// Redux store implementation, generic over the state and the actions so
+// it isn't tied to our todo list
+pub struct Store<S, A> {
+    // Shared with history and whoever read a snapshot, a new state gets a new Arc
+    state: Arc<S>,
+    published: Published<S>,
+    listeners: Arc<Mutex<Listeners<S>>>,
+    reducer: Reducer<S, A>,
+    // Only set for stores made with Store::with_storage
+    storage: Option<Box<dyn Storage<S, A> + Send>>,
+    // Past and undone states for undo/redo
+    history: History<Arc<S>>,
+    // Runs in order on every dispatched action before the reducer
+    middleware: Vec<Middleware<S, A>>,
+    // Set while batch() runs, history then gets one state for the whole batch
+    batching: bool,
+}
+
+impl<S: Clone + 'static, A: Clone + Debug> Store<S, A> {
+    // Takes a reducer and the initial state, we skip the optional enhancer argument
+    pub fn create_store(reducer: Reducer<S, A>, initial_state: S) -> Store<S, A> {
+        let state = Arc::new(initial_state);
         Store {
-            state: State::default(),
-            listeners: Vec::new(),
+            published: Arc::new(RwLock::new(state.clone())),
+            state: state,
+            listeners: Listeners::new(),
             reducer: reducer,
+            storage: None,
+            history: History::new(DEFAULT_HISTORY_LIMIT),
+            middleware: Vec::new(),
+            batching: false,
+        }
+    }
+
+    // Like create_store, but with a state loaded from `storage`, which gets
+    // every action before it's applied and the new state after
+    pub fn with_storage(reducer: Reducer<S, A>, state: S, storage: Box<dyn Storage<S, A> + Send>) -> Store<S, A> {
+        let mut store = Store::create_store(reducer, state);
+        store.storage = Some(storage);
+        store
+    }
+
+    // Moves the store behind a lock so request threads can share it
+    pub fn share(self) -> SharedStore<S, A> {
+        SharedStore {
+            published: self.published.clone(),
+            store: Arc::new(Mutex::new(self)),
         }
     }
 
-    // Pushes a listener that will be called for any state change
+    // Pushes a listener that will be called for any state change. The
+    // listener is removed again when the returned Subscription is dropped
+    #[allow(dead_code)]
+    pub fn subscribe(&mut self, listener: Listener<S>) -> Subscription {
+        Listeners::add(&self.listeners, listener)
+    }
+
+    // Like subscribe, but the listener only gets the part of the state picked out
+    // by the selector, and is only called when that part actually changes
     #[allow(dead_code)]
-    pub fn subscribe(&mut self, listener: fn(&State)) {
-        self.listeners.push(listener);
+    pub fn subscribe_with_selector<T>(&mut self, selector: Box<dyn Fn(&S) -> T + Send>,
+                                      listener: Box<dyn FnMut(&T) + Send>) -> Subscription
+        where T: PartialEq + Send + 'static {
+        let mut last = selector(&*self.state);
+        let mut listener = listener;
+        self.subscribe(Box::new(move |state: &S| {
+            let selected = selector(state);
+            if selected != last {
+                listener(&selected);
+                last = selected;
+            }
+        }))
     }
 
     // Simply returns the state
     #[allow(dead_code)]
-    pub fn get_state(&self) -> &State {
+    pub fn get_state(&self) -> &S {
         &self.state
     }
 
-    // Called for every new action, calls the reducer to update the state
-    // and then calls every listener
-    pub fn dispatch(&mut self, action: Action) {
-        self.state = (self.reducer)(&self.state, action);
-        for listener in &self.listeners {
-            listener(&self.state)
+    // Adds middleware to the end of the chain, every dispatched action passes
+    // through each of them in order before it reaches the reducer
+    pub fn apply_middleware(&mut self, middleware: Vec<Middleware<S, A>>) {
+        self.middleware.extend(middleware);
+    }
+
+    // Called for every new action, runs the middleware chain, calls the reducer
+    // to update the state and then calls every listener
+    pub fn dispatch(&mut self, action: A) {
+        self.run_middleware(action, 0);
+    }
+
+    // Runs `f`, which can dispatch as much as it likes. Every action still goes
+    // through the middleware and storage on its own, but one undo takes all of
+    // them back and readers and listeners only see the state they end up in.
+    // Batches inside a batch are part of the outer one
+    pub fn batch<F, T>(&mut self, f: F) -> T where F: FnOnce(&mut Store<S, A>) -> T {
+        let before = self.state.clone();
+        let outer = mem::replace(&mut self.batching, true);
+        let result = f(self);
+        self.batching = outer;
+        if !outer && !Arc::ptr_eq(&before, &self.state) {
+            self.history.record(before);
+            self.notify();
+        }
+        result
+    }
+
+    fn run_middleware(&mut self, action: A, depth: usize) {
+        if depth > MAX_DISPATCH_DEPTH {
+            eprintln!("Dropped {:?}, middleware re-dispatched more than {} times", action, MAX_DISPATCH_DEPTH);
+            return;
+        }
+
+        let mut action = action;
+        for index in 0..self.middleware.len() {
+            match (self.middleware[index])(&*self.state, action) {
+                Next::Continue(next_action) => action = next_action,
+                Next::Stop => return,
+                Next::Dispatch(actions) => {
+                    for next_action in actions {
+                        self.run_middleware(next_action, depth + 1);
+                    }
+                    return;
+                },
+            }
+        }
+
+        self.reduce(action);
+    }
+
+    // The action made it through the middleware, time to apply it
+    fn reduce(&mut self, action: A) {
+        // The reducer runs first, so an action that makes it panic never gets
+        // saved and can't take the store down again when it's replayed
+        let new_state = (self.reducer)(&*self.state, action.clone());
+
+        // Write-ahead: if the action can't be saved we don't apply it either,
+        // so what's on disk never falls behind what users have seen
+        if let Some(ref mut storage) = self.storage {
+            if let Err(e) = storage.append(&action) {
+                eprintln!("Could not save {:?}: {}", action, e);
+                return;
+            }
+        }
+
+        let previous = mem::replace(&mut self.state, Arc::new(new_state));
+        self.commit();
+        // A batch records history and notifies once, when it's done
+        if !self.batching {
+            self.history.record(previous);
+            self.notify();
+        }
+    }
+
+    // How many past states to keep for undo, the default is DEFAULT_HISTORY_LIMIT
+    pub fn set_history_limit(&mut self, limit: usize) {
+        self.history.set_limit(limit);
+    }
+
+    // Goes back to the state before the last action, returns false if there is none
+    pub fn undo(&mut self) -> bool {
+        let moved = self.history.undo(&mut self.state);
+        if moved { self.travelled(); }
+        moved
+    }
+
+    // Reapplies the last undone state, returns false if there is none
+    pub fn redo(&mut self) -> bool {
+        let moved = self.history.redo(&mut self.state);
+        if moved { self.travelled(); }
+        moved
+    }
+
+    // Jumps straight to a position in history(), returns false if it's out of range
+    pub fn jump_to(&mut self, index: usize) -> bool {
+        let moved = self.history.jump_to(&mut self.state, index);
+        if moved { self.travelled(); }
+        moved
+    }
+
+    // Every state we can undo or redo to, oldest first. The current state
+    // is at history_position()
+    #[allow(dead_code)]
+    pub fn history(&self) -> Vec<&S> {
+        self.history.states(&self.state).into_iter().map(|state| &**state).collect()
+    }
+
+    #[allow(dead_code)]
+    pub fn history_position(&self) -> usize {
+        self.history.position()
+    }
+
+    // Undo and redo change the state without an action, the new state still
+    // has to be saved
+    fn travelled(&mut self) {
+        self.commit();
+        self.notify();
+    }
+
+    // After a dispatch the storage already has the action, so a failed commit
+    // only puts off writing the state until the next one. After undo or redo
+    // there's no action, the state is lost if nothing commits before a restart
+    fn commit(&mut self) {
+        if let Some(ref mut storage) = self.storage {
+            if let Err(e) = storage.commit(&*self.state) {
+                eprintln!("Could not save the new state: {}", e);
+            }
         }
     }
+
+    // Publishes the new state to readers, then tells the listeners
+    fn notify(&self) {
+        *write(&self.published) = self.state.clone();
+        lock(&self.listeners).call_all(&*self.state);
+    }
+}
+
+// A store shared between request threads. Changing it takes the lock but
+// reading doesn't, readers get the last published state and can take as long
+// as they like rendering it without holding up a dispatch or each other
+pub struct SharedStore<S, A> {
+    store: Arc<Mutex<Store<S, A>>>,
+    published: Published<S>,
+}
+
+impl<S, A> SharedStore<S, A> {
+    // For dispatching, undo and redo, and for reads that have to see the same
+    // state as the dispatch that follows them
+    pub fn lock(&self) -> MutexGuard<Store<S, A>> {
+        lock(&self.store)
+    }
+
+    // The latest state, without waiting for a dispatch that's in progress
+    pub fn state(&self) -> Arc<S> {
+        read(&self.published).clone()
+    }
+}
+
+// Derive would want S and A to be Clone, we only clone the Arcs
+impl<S, A> Clone for SharedStore<S, A> {
+    fn clone(&self) -> SharedStore<S, A> {
+        SharedStore {
+            store: self.store.clone(),
+            published: self.published.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{ Arc, Mutex };
+    use rustc_serialize::json;
+    use todo::TodoAction::{ Add, SetDue, Toggle };
+    use super::{ State, Store, reducer };
+    use super::Action::{ Todos };
+
+    #[test]
+    fn decodes_states_saved_before_next_id() {
+        let state: State = json::decode(r#"{"todos":[
+            {"id":3,"title":"a","completed":false,"deleted":false},
+            {"id":7,"title":"b","completed":true,"deleted":false}]}"#).unwrap();
+        assert_eq!(state.next_id, 8);
+        let empty: State = json::decode(r#"{"todos":[]}"#).unwrap();
+        assert_eq!(empty.next_id, 1);
+    }
+
+    #[test]
+    fn keeps_a_saved_next_id() {
+        let state: State = json::decode(r#"{"todos":[{"id":3,"title":"a","completed":false,"deleted":false}],"next_id":12}"#).unwrap();
+        assert_eq!(state.next_id, 12);
+    }
+
+    #[test]
+    fn a_batch_is_one_undo_step_and_one_notification() {
+        let mut store = Store::create_store(reducer(), State::default());
+        store.dispatch( Todos( Add("Pay rent".to_string()) ) );
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen_by_listener = seen.clone();
+        let _subscription = store.subscribe(Box::new(move |state: &State| {
+            seen_by_listener.lock().unwrap().push(state.todos.first().map(|todo| todo.completed));
+        }));
+
+        store.batch(|store| {
+            store.dispatch( Todos( Toggle(1) ) );
+            store.dispatch( Todos( SetDue(1, Some("2026-11-01".to_string())) ) );
+            // Readers still get the state from before the batch
+            assert_eq!(store.published.read().unwrap().todos[0].due, None);
+        });
+        assert_eq!(*seen.lock().unwrap(), vec![Some(true)]);
+        assert_eq!(store.get_state().todos[0].due, Some("2026-11-01".to_string()));
+
+        assert!(store.undo());
+        assert!(!store.get_state().todos[0].completed);
+        assert_eq!(store.get_state().todos[0].due, None);
+        assert!(store.undo());
+        assert!(store.get_state().todos.is_empty());
+    }
 }
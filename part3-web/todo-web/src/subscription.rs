@@ -0,0 +1,73 @@
+use std::sync::{Arc, Mutex, Weak};
+use store::Listener;
+use error::lock;
+
+// The store's listeners, each with an id so its Subscription can find it again.
+// They sit behind their own Arc<Mutex<..>> so a Subscription can remove its
+// listener without needing the store itself
+pub struct Listeners<S> {
+    next_id: usize,
+    entries: Vec<(usize, Listener<S>)>,
+}
+
+impl<S: 'static> Listeners<S> {
+    pub fn new() -> Arc<Mutex<Listeners<S>>> {
+        Arc::new( Mutex::new(Listeners { next_id: 0, entries: Vec::new() }) )
+    }
+
+    // Adds the listener and hands back the Subscription that removes it again
+    pub fn add(listeners: &Arc<Mutex<Listeners<S>>>, listener: Listener<S>) -> Subscription {
+        let id = {
+            let mut locked = lock(&listeners);
+            let id = locked.next_id;
+            locked.next_id += 1;
+            locked.entries.push((id, listener));
+            id
+        };
+
+        // Only a weak reference, the Subscription shouldn't keep the
+        // listeners alive if the store goes away first
+        let weak: Weak<Mutex<Listeners<S>>> = Arc::downgrade(listeners);
+        Subscription {
+            remove: Some(Box::new(move || {
+                if let Some(listeners) = weak.upgrade() {
+                    lock(&listeners).entries.retain(|&(entry_id, _)| entry_id != id);
+                }
+            })),
+        }
+    }
+
+    pub fn call_all(&mut self, state: &S) {
+        for &mut (_, ref mut listener) in &mut self.entries {
+            listener(state)
+        }
+    }
+}
+
+// Returned by Store::subscribe, the listener stays subscribed for as long as this
+// lives. Call unsubscribe() or just drop it to remove the listener from the store.
+// A listener must not drop its own Subscription while it's being called, the
+// listeners are locked at that point
+#[must_use = "dropping a Subscription unsubscribes the listener right away"]
+pub struct Subscription {
+    remove: Option<Box<dyn FnMut() + Send>>,
+}
+
+impl Subscription {
+    #[allow(dead_code)]
+    pub fn unsubscribe(mut self) {
+        self.remove_listener();
+    }
+
+    fn remove_listener(&mut self) {
+        if let Some(mut remove) = self.remove.take() {
+            remove();
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.remove_listener();
+    }
+}
@@ -1,30 +1,35 @@
-use rustc_serialize::json::ToJson;
-use std::path::Path;
+use rustc_serialize::json::{Json, ToJson};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::fmt::Debug;
 use std::io::Write;
+use std::sync::RwLock;
+use std::thread;
+use std::time::{Duration, SystemTime};
 use nickel::{Response, MiddlewareResult};
+use nickel::status::StatusCode;
+use hyper::header::Location;
+use error::{ AppError, read, write };
 use handlebars::{Handlebars, Renderable, RenderError, RenderContext, Helper, Context, JsonRender};
 
-fn filter_todo(c: &Context, h: &Helper, ha: &Handlebars, rc: &mut RenderContext) -> Result<(), RenderError> {
-    let active_filter = c.navigate(".", "visibility_filter").as_string().unwrap();
-    let is_completed = c.navigate( rc.get_path(), "completed").as_boolean().unwrap();
-    let show_todo: bool = match active_filter {
-        "ShowAll" => true,
-        "ShowCompleted" => is_completed,
-        "ShowActive" => !is_completed,
-        _ => false,
-    };
+// The helpers get the page data as Json, a field that's missing or has the wrong
+// type becomes a RenderError instead of taking the request down with a panic
+fn missing(field: &str) -> RenderError {
+    RenderError::new(format!("The template data has no usable {}", field))
+}
 
-    if show_todo {
-        h.template().unwrap().render(c, ha, rc)
-    } else {
-        Ok(())
+// Renders the helper's block, if it has one
+fn render_block(c: &Context, h: &Helper, ha: &Handlebars, rc: &mut RenderContext) -> Result<(), RenderError> {
+    match h.template() {
+        Some(template) => template.render(c, ha, rc),
+        None => Ok(()),
     }
 }
 
 fn is_selected_filter(c: &Context, h: &Helper, ha: &Handlebars, rc: &mut RenderContext) -> Result<(), RenderError> {
-    let param = h.param(0).unwrap().value().as_string().unwrap();
-    let active_filter = c.navigate(".", "visibility_filter").as_string().unwrap();
+    let param = try!(h.param(0).and_then(|param| param.value().as_string()).ok_or(missing("filter parameter")));
+    let active_filter = try!(c.navigate(".", "visibility_filter").as_string().ok_or(missing("visibility_filter")));
     let is_selected: bool = match active_filter {
         "ShowAll" => if param == "ShowAll" { true } else { false },
         "ShowCompleted" => if param == "ShowCompleted" { true } else { false },
@@ -32,7 +37,41 @@ fn is_selected_filter(c: &Context, h: &Helper, ha: &Handlebars, rc: &mut RenderC
         _ => false,
     };
     if is_selected {
-        h.template().unwrap().render(c, ha, rc)
+        render_block(c, h, ha, rc)
+    } else {
+        Ok(())
+    }
+}
+
+// For the sort links, like is_selected_filter but for the ?sort= order
+fn is_selected_sort(c: &Context, h: &Helper, ha: &Handlebars, rc: &mut RenderContext) -> Result<(), RenderError> {
+    let param = try!(h.param(0).and_then(|param| param.value().as_string()).ok_or(missing("sort parameter")));
+    let sort = try!(c.navigate(".", "sort").as_string().ok_or(missing("sort")));
+    if param == sort {
+        render_block(c, h, ha, rc)
+    } else {
+        Ok(())
+    }
+}
+
+// For the format select on the import page, like is_selected_sort
+fn is_selected_format(c: &Context, h: &Helper, ha: &Handlebars, rc: &mut RenderContext) -> Result<(), RenderError> {
+    let param = try!(h.param(0).and_then(|param| param.value().as_string()).ok_or(missing("format parameter")));
+    let format = try!(c.navigate(".", "format").as_string().ok_or(missing("format")));
+    if param == format {
+        render_block(c, h, ha, rc)
+    } else {
+        Ok(())
+    }
+}
+
+// For the priority select in the edit form, renders the block if the todo
+// we're in has the priority given as a parameter
+fn is_priority(c: &Context, h: &Helper, ha: &Handlebars, rc: &mut RenderContext) -> Result<(), RenderError> {
+    let param = try!(h.param(0).and_then(|param| param.value().as_string()).ok_or(missing("priority parameter")));
+    let has_priority = c.navigate(rc.get_path(), "priority").as_string().map_or(false, |priority| priority == param);
+    if has_priority {
+        render_block(c, h, ha, rc)
     } else {
         Ok(())
     }
@@ -40,12 +79,12 @@ fn is_selected_filter(c: &Context, h: &Helper, ha: &Handlebars, rc: &mut RenderC
 
 #[allow(unused_variables)]
 fn active_count(c: &Context, h: &Helper, ha: &Handlebars, rc: &mut RenderContext) -> Result<(), RenderError> {
-    let todos = c.navigate(".", "todos").as_array().unwrap();
+    let todos = try!(c.navigate(".", "todos").as_array().ok_or(missing("todos")));
     let count = todos
         .into_iter()
         .filter(|todo| {
-            !todo.find("completed").unwrap().as_boolean().unwrap() &&
-            !todo.find("deleted").unwrap().as_boolean().unwrap()
+            let is_set = |field: &str| todo.find(field).and_then(|value| value.as_boolean()).unwrap_or(false);
+            !is_set("completed") && !is_set("deleted")
         })
         .count();
 
@@ -56,18 +95,174 @@ fn active_count(c: &Context, h: &Helper, ha: &Handlebars, rc: &mut RenderContext
         output.push_str(" items left");
     }
 
-    rc.writer.write(output.as_bytes()).unwrap();
-    Ok(())
+    rc.writer.write_all(output.as_bytes()).map_err(|e| RenderError::new(e.to_string()))
 }
 
-pub fn render<'mw, T:ToJson + Debug>(res: Response<'mw>, path: &str, data: &T) -> MiddlewareResult<'mw> {
-    let mut handlebars = Handlebars::new();
+// Renders the block if `test` holds for the todos that aren't deleted, and the
+// {{else}} part otherwise
+fn todos_block<F>(c: &Context, h: &Helper, ha: &Handlebars, rc: &mut RenderContext, test: F) -> Result<(), RenderError>
+    where F: Fn(&[&Json]) -> bool {
+    let todos = try!(c.navigate(".", "todos").as_array().ok_or(missing("todos")));
+    let todos: Vec<&Json> = todos.iter()
+        .filter(|todo| !todo.find("deleted").and_then(|deleted| deleted.as_boolean()).unwrap_or(false))
+        .collect();
+    let template = if test(&todos) { h.template() } else { h.inverse() };
+    match template {
+        Some(template) => template.render(c, ha, rc),
+        None => Ok(()),
+    }
+}
+
+fn is_completed(todo: &Json) -> bool {
+    todo.find("completed").and_then(|completed| completed.as_boolean()).unwrap_or(false)
+}
+
+// For the toggle-all checkbox, which is checked once there's nothing left to do
+fn all_completed(c: &Context, h: &Helper, ha: &Handlebars, rc: &mut RenderContext) -> Result<(), RenderError> {
+    todos_block(c, h, ha, rc, |todos| todos.len() > 0 && todos.iter().all(|todo| is_completed(todo)))
+}
+
+// For the "Clear completed" button, which only shows up if there's something to clear
+fn any_completed(c: &Context, h: &Helper, ha: &Handlebars, rc: &mut RenderContext) -> Result<(), RenderError> {
+    todos_block(c, h, ha, rc, |todos| todos.iter().any(|todo| is_completed(todo)))
+}
+
+// For the trash page, which says so when there's nothing in the trash
+fn any_deleted(c: &Context, h: &Helper, ha: &Handlebars, rc: &mut RenderContext) -> Result<(), RenderError> {
+    let todos = try!(c.navigate(".", "todos").as_array().ok_or(missing("todos")));
+    let any = todos.iter().any(|todo| todo.find("deleted").and_then(|deleted| deleted.as_boolean()).unwrap_or(false));
+    let template = if any { h.template() } else { h.inverse() };
+    match template {
+        Some(template) => template.render(c, ha, rc),
+        None => Ok(()),
+    }
+}
+
+// Every *.tpl file in the template directory is compiled once and registered
+// under its file name without the extension, so ./src/todos.tpl is "todos"
+const TEMPLATE_EXTENSION: &'static str = "tpl";
 
-    handlebars.register_helper("filter_todo", Box::new(filter_todo));
+// How often the development mode looks for changed templates
+const WATCH_INTERVAL_MILLISECONDS: u64 = 500;
+
+fn new_registry() -> Handlebars {
+    let mut handlebars = Handlebars::new();
     handlebars.register_helper("active_count", Box::new(active_count));
     handlebars.register_helper("is_selected_filter", Box::new(is_selected_filter));
-    handlebars.register_template_file("template", &Path::new(path)).ok().unwrap();
-    let result = handlebars.render("template", data).ok().unwrap();
+    handlebars.register_helper("is_selected_sort", Box::new(is_selected_sort));
+    handlebars.register_helper("is_priority", Box::new(is_priority));
+    handlebars.register_helper("is_selected_format", Box::new(is_selected_format));
+    handlebars.register_helper("all_completed", Box::new(all_completed));
+    handlebars.register_helper("any_completed", Box::new(any_completed));
+    handlebars.register_helper("any_deleted", Box::new(any_deleted));
+    handlebars
+}
+
+lazy_static! {
+    // Shared by all requests, rendering only needs a read lock
+    static ref TEMPLATES: RwLock<Handlebars> = RwLock::new(new_registry());
+}
+
+fn template_files(dir: &Path) -> ::std::io::Result<Vec<(String, PathBuf)>> {
+    let mut files = Vec::new();
+    for entry in try!(fs::read_dir(dir)) {
+        let path = try!(entry).path();
+        if path.extension().map_or(false, |extension| extension == TEMPLATE_EXTENSION) {
+            if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+                files.push((name.to_string(), path.clone()));
+            }
+        }
+    }
+    Ok(files)
+}
+
+// Compiles every template in `dir` and swaps them in, returning how many there
+// were. If any of them fails to compile the old templates are kept
+pub fn load<P: AsRef<Path>>(dir: P) -> Result<usize, String> {
+    let files = try!(template_files(dir.as_ref())
+        .map_err(|e| format!("Could not read {}: {}", dir.as_ref().display(), e)));
+
+    let mut handlebars = new_registry();
+    for &(ref name, ref path) in &files {
+        try!(handlebars.register_template_file(name, path)
+            .map_err(|e| format!("Could not compile {}: {:?}", path.display(), e)));
+    }
+
+    *write(&TEMPLATES) = handlebars;
+    Ok(files.len())
+}
+
+// Every template in `dir` with its modification time, so we notice edits as
+// well as templates being added or removed
+fn fingerprint(dir: &Path) -> Vec<(String, Option<SystemTime>)> {
+    let mut files: Vec<_> = template_files(dir).unwrap_or(Vec::new())
+        .into_iter()
+        .map(|(name, path)| (name, fs::metadata(path).and_then(|metadata| metadata.modified()).ok()))
+        .collect();
+    files.sort();
+    files
+}
+
+// Development mode: a background thread keeps an eye on the templates in `dir`
+// and recompiles them whenever one changes, so edits show up on the next reload
+pub fn watch<P: AsRef<Path>>(dir: P) {
+    let dir = dir.as_ref().to_path_buf();
+    thread::spawn(move || {
+        let mut seen = fingerprint(&dir);
+        loop {
+            thread::sleep(Duration::from_millis(WATCH_INTERVAL_MILLISECONDS));
+            let current = fingerprint(&dir);
+            if current == seen {
+                continue;
+            }
+            seen = current;
+            match load(&dir) {
+                Ok(count) => println!("Recompiled {} templates in {}", count, dir.display()),
+                // Keep serving the old templates until the mistake is fixed
+                Err(message) => println!("{}", message),
+            }
+        }
+    });
+}
+
+// Renders one of the templates compiled by load(), by name
+pub fn render_page<T: ToJson>(name: &str, data: &T) -> Result<String, AppError> {
+    read(&TEMPLATES).render(name, data)
+        .map_err(|e| AppError::Template(format!("{} template: {:?}", name, e)))
+}
+
+pub fn render<'mw, T:ToJson + Debug>(res: Response<'mw>, name: &str, data: &T) -> MiddlewareResult<'mw> {
+    match render_page(name, data) {
+        Ok(result) => res.send(result),
+        Err(e) => error_page(res, e),
+    }
+}
+
+// Sends the error with its status code, as a page rendered from the error
+// template or as plain text if even that doesn't work
+pub fn error_page<'mw>(mut res: Response<'mw>, error: AppError) -> MiddlewareResult<'mw> {
+    let status = error.status();
+    println!("{}: {}", status, error);
+
+    let mut data = BTreeMap::new();
+    data.insert("status".to_string(), status.to_string().to_json());
+    data.insert("message".to_string(), error.to_string().to_json());
+    res.set(status);
+    match render_page("error", &Json::Object(data)) {
+        Ok(result) => res.send(result),
+        Err(_) => res.send(error.to_string()),
+    }
+}
+
+// Like render, but with a status code other than 200 OK
+pub fn render_with_status<'mw, T:ToJson + Debug>(mut res: Response<'mw>, status: StatusCode, name: &str, data: &T) -> MiddlewareResult<'mw> {
+    res.set(status);
+    render(res, name, data)
+}
 
-    res.send(result)
+// Sends a 303 See Other so the browser follows up with a GET to `path`
+pub fn redirect<'mw>(mut res: Response<'mw>, path: &str) -> MiddlewareResult<'mw> {
+    res.set(StatusCode::SeeOther);
+    res.headers_mut().set(Location(path.to_string()));
+    res.send("")
 }
@@ -1,41 +1,232 @@
+use std::collections::BTreeSet;
+use std::time::{ SystemTime, UNIX_EPOCH };
+use rustc_serialize::{ Decodable, Decoder };
+use rustc_serialize::json::{self, Json, ToJson};
 use store::{ Action };
 use store::Action::{ Todos };
-use todo::TodoAction::{ Add, Toggle, Remove };
-#[derive(Clone, Debug, RustcEncodable, RustcDecodable)]
+use todo::TodoAction::{ Add, Toggle, Remove, Edit, ToggleAll, ClearCompleted, Restore, Purge, EmptyTrash, ExpireTrash,
+                       SetDue, SetPriority, AddTag, RemoveTag, Move };
+// Ids are handed out by State::next_id and never reused, u64 gives us
+// more than enough of them
+pub type TodoId = u64;
+
+// Tags are single words, this keeps them short enough to fit next to a title
+pub const MAX_TAG_LENGTH: usize = 32;
+
+#[derive(Clone, Debug, PartialEq, RustcEncodable)]
 pub struct Todo {
-    pub id: i16,
+    pub id: TodoId,
     pub title: String,
     pub completed: bool,
+    // Deleted todos sit in the trash until they're purged or restored
     pub deleted: bool,
+    // When the trash retention policy first saw the todo in the trash, in
+    // seconds since 1970. None until then, or if there's no policy
+    pub deleted_at: Option<u64>,
+    // A date like 2016-06-17, written that way they sort in date order as strings
+    pub due: Option<String>,
+    pub priority: Option<Priority>,
+    // A set keeps the tags sorted and without duplicates
+    pub tags: BTreeSet<String>,
 }
 impl Todo {
-    pub fn new(id: i16, title: String) -> Todo {
+    pub fn new(id: TodoId, title: String) -> Todo {
         Todo {
             id: id,
             title: title,
             completed: false,
             deleted: false,
+            deleted_at: None,
+            due: None,
+            priority: None,
+            tags: BTreeSet::new(),
         }
     }
 }
 
+// Written out by hand so snapshots saved before todos had tags still load.
+// Missing Option fields decode as None either way, a missing tags field
+// becomes an empty set instead of an error
+impl Decodable for Todo {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Todo, D::Error> {
+        d.read_struct("Todo", 8, |d| {
+            Ok(Todo {
+                id: try!(d.read_struct_field("id", 0, Decodable::decode)),
+                title: try!(d.read_struct_field("title", 1, Decodable::decode)),
+                completed: try!(d.read_struct_field("completed", 2, Decodable::decode)),
+                deleted: try!(d.read_struct_field("deleted", 3, Decodable::decode)),
+                deleted_at: try!(d.read_struct_field("deleted_at", 4, Decodable::decode)),
+                due: try!(d.read_struct_field("due", 5, Decodable::decode)),
+                priority: try!(d.read_struct_field("priority", 6, Decodable::decode)),
+                tags: try!(d.read_struct_field("tags", 7, |d| d.read_option(|d, present| {
+                    if present { Decodable::decode(d) } else { Ok(BTreeSet::new()) }
+                }))),
+            })
+        })
+    }
+}
+
+// Deriving PartialOrd and Ord orders the variants the way they're listed, Low < High
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, RustcEncodable, RustcDecodable)]
+pub enum Priority {
+    Low,
+    Medium,
+    High,
+}
+
+impl Priority {
+    // The priority for a form field or query parameter, in any case
+    pub fn from_name(name: &str) -> Option<Priority> {
+        match name.to_lowercase().as_str() {
+            "low" => Some(Priority::Low),
+            "medium" => Some(Priority::Medium),
+            "high" => Some(Priority::High),
+            _ => None,
+        }
+    }
+}
+
+fn days_in_month(year: u32, month: u32) -> u32 {
+    match month {
+        // Every fourth year is a leap year, except every hundredth, except every four hundredth
+        2 => match (year % 400, year % 100, year % 4) {
+            (0, _, _) => 29,
+            (_, 0, _) => 28,
+            (_, _, 0) => 29,
+            _ => 28,
+        },
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Checks a date like 2016-6-17 and returns it zero padded as 2016-06-17,
+// so comparing two dates as strings compares them as dates
+pub fn parse_date(date: &str) -> Option<String> {
+    let parts: Vec<u32> = date.trim().split('-').filter_map(|part| part.parse().ok()).collect();
+    if parts.len() != 3 || date.trim().split('-').count() != 3 {
+        return None;
+    }
+    let (year, month, day) = (parts[0], parts[1], parts[2]);
+    if year < 1000 || year > 9999 || month < 1 || month > 12 || day < 1 || day > days_in_month(year, month) {
+        return None;
+    }
+    Some(format!("{:04}-{:02}-{:02}", year, month, day))
+}
+
+// The year, month and day `days` after 1970-01-01, with Howard Hinnant's
+// civil_from_days algorithm
+pub fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// Today's date in UTC
+pub fn today() -> String {
+    let days = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs() / (24 * 60 * 60))
+        .unwrap_or(0);
+    let (year, month, day) = civil_from_days(days);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+// Done todos are never overdue
+pub fn is_overdue(todo: &Todo, today: &str) -> bool {
+    !todo.completed && todo.due.as_ref().map_or(false, |due| due.as_str() < today)
+}
+
+// Tags are single words of letters, digits, - and _, so they can go in a URL
+// as they are. The # in front is optional and they're stored in lower case
+pub fn parse_tag(tag: &str) -> Option<String> {
+    let tag = tag.trim().trim_matches('#').to_lowercase();
+    let is_word = tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if tag.len() == 0 || tag.len() > MAX_TAG_LENGTH || !is_word {
+        None
+    } else {
+        Some(tag)
+    }
+}
+
+// The actions that change the todo's tags from what they are to `tags`
+pub fn retag(todo: &Todo, tags: &BTreeSet<String>) -> Vec<TodoAction> {
+    let added = tags.difference(&todo.tags).map(|tag| AddTag(todo.id, tag.clone()));
+    let removed = todo.tags.difference(tags).map(|tag| RemoveTag(todo.id, tag.clone()));
+    added.chain(removed).collect()
+}
+
+impl ToJson for Todo {
+    fn to_json(&self) -> Json {
+        Json::from_str( &json::encode(&self).unwrap() ).unwrap()
+    }
+}
+
 // mark_done from the previous example becomes Toggle to align with the Redux example
 // otherwise functionality is the same
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, RustcEncodable, RustcDecodable)]
 pub enum TodoAction {
     Add(String),
-    Toggle(i16),
-    Remove(i16),
+    Toggle(TodoId),
+    Remove(TodoId),
+    // Changes the title but keeps the id and whether it's done
+    Edit(TodoId, String),
+    // Marks every todo as done, or every todo as not done
+    ToggleAll(bool),
+    // Moves every todo that's done to the trash
+    ClearCompleted,
+    // Takes a todo back out of the trash
+    Restore(TodoId),
+    // Removes a todo in the trash for good
+    Purge(TodoId),
+    // Removes everything in the trash for good
+    EmptyTrash,
+    // The trash retention policy, with the current time and how many seconds
+    // todos may stay in the trash. Todos it sees in the trash for the first time
+    // get their deleted_at set, the ones that have been there too long are purged.
+    // The time is part of the action so replaying the log gives the same result
+    ExpireTrash(u64, u64),
+    // A date like 2016-06-17, or None to clear it
+    SetDue(TodoId, Option<String>),
+    SetPriority(TodoId, Option<Priority>),
+    AddTag(TodoId, String),
+    RemoveTag(TodoId, String),
+    // Moves a todo to a new position in the list. Positions count the todos
+    // that aren't deleted, after the moved one has been taken out, so 0 is the
+    // top and anything past the end is the bottom
+    Move(TodoId, usize),
 }
 
 // Helper function for getting a mutable todo from a vector by todo_id
-pub fn get_mut_todo(todos: &mut Vec<Todo>, todo_id: i16) -> Option<&mut Todo> {
+pub fn get_mut_todo(todos: &mut Vec<Todo>, todo_id: TodoId) -> Option<&mut Todo> {
     todos.iter_mut().find(|todo|todo.id == todo_id)
 }
 
+// The position a todo gets if it's dropped in front of `before`, for Move.
+// None if there's no `before` todo in the list
+pub fn position_before(todos: &[Todo], todo_id: TodoId, before: TodoId) -> Option<usize> {
+    todos.iter()
+        .filter(|todo| !todo.deleted && todo.id != todo_id)
+        .position(|todo| todo.id == before)
+}
+
+// Whether the trash retention policy should purge the todo
+pub fn is_expired(todo: &Todo, now: u64, max_age: u64) -> bool {
+    match todo.deleted_at {
+        Some(deleted_at) => todo.deleted && now.saturating_sub(deleted_at) >= max_age,
+        None => false,
+    }
+}
+
 // Our todo reducer, takes in state (todo list) and returns a new/cloned version
 // after applying the action (is applicable)
-pub fn todo_reducer(state: &Vec<Todo>, action: &Action) -> Vec<Todo> {
+pub fn todo_reducer(state: &Vec<Todo>, next_id: TodoId, action: &Action) -> Vec<Todo> {
     let mut new_state: Vec<Todo> = state.clone();
 
     // First we make sure it's a `Todos` action, otherwise return clone of incoming state
@@ -45,8 +236,7 @@ pub fn todo_reducer(state: &Vec<Todo>, action: &Action) -> Vec<Todo> {
             // If Add push a new item, and if `Toggle` or `Remove` use our get_mut_todo
             // helper function and then change a property on the todo
             Add(ref title) => {
-                let new_id = new_state.len() as i16 + 1;
-                new_state.push(Todo::new(new_id, title.to_string()))
+                new_state.push(Todo::new(next_id, title.to_string()))
             },
             Toggle(todo_id) => {
                 if let Some(todo) = get_mut_todo(&mut new_state, todo_id) {
@@ -58,9 +248,85 @@ pub fn todo_reducer(state: &Vec<Todo>, action: &Action) -> Vec<Todo> {
                     todo.deleted = true;
                 }
             },
+            Edit(todo_id, ref title) => {
+                if let Some(todo) = get_mut_todo(&mut new_state, todo_id) {
+                    todo.title = title.to_string();
+                }
+            },
+            ToggleAll(completed) => {
+                for todo in new_state.iter_mut().filter(|todo| !todo.deleted) {
+                    todo.completed = completed;
+                }
+            },
+            ClearCompleted => {
+                for todo in new_state.iter_mut().filter(|todo| todo.completed) {
+                    todo.deleted = true;
+                }
+            },
+            Restore(todo_id) => {
+                if let Some(todo) = get_mut_todo(&mut new_state, todo_id) {
+                    todo.deleted = false;
+                    todo.deleted_at = None;
+                }
+            },
+            Purge(todo_id) => {
+                new_state.retain(|todo| todo.id != todo_id || !todo.deleted);
+            },
+            EmptyTrash => {
+                new_state.retain(|todo| !todo.deleted);
+            },
+            ExpireTrash(now, max_age) => {
+                for todo in new_state.iter_mut().filter(|todo| todo.deleted && todo.deleted_at.is_none()) {
+                    todo.deleted_at = Some(now);
+                }
+                new_state.retain(|todo| !is_expired(todo, now, max_age));
+            },
+            SetDue(todo_id, ref due) => {
+                if let Some(todo) = get_mut_todo(&mut new_state, todo_id) {
+                    todo.due = due.clone();
+                }
+            },
+            SetPriority(todo_id, priority) => {
+                if let Some(todo) = get_mut_todo(&mut new_state, todo_id) {
+                    todo.priority = priority;
+                }
+            },
+            AddTag(todo_id, ref tag) => {
+                if let Some(todo) = get_mut_todo(&mut new_state, todo_id) {
+                    todo.tags.insert(tag.to_string());
+                }
+            },
+            RemoveTag(todo_id, ref tag) => {
+                if let Some(todo) = get_mut_todo(&mut new_state, todo_id) {
+                    todo.tags.remove(tag);
+                }
+            },
+            Move(todo_id, position) => {
+                if let Some(from) = new_state.iter().position(|todo| todo.id == todo_id && !todo.deleted) {
+                    let todo = new_state.remove(from);
+                    // Deleted todos keep their place, so we look for where the
+                    // todo at `position` is in the whole list
+                    let to = new_state.iter()
+                        .enumerate()
+                        .filter(|&(_, todo)| !todo.deleted)
+                        .map(|(index, _)| index)
+                        .nth(position)
+                        .unwrap_or(new_state.len());
+                    new_state.insert(to, todo);
+                }
+            },
         },
         // If it's not a Todos action change nothing
         _ => (),
     }
     return new_state;
 }
+
+// Hands out the id for the next todo, it only ever moves forward so an id
+// is never reused, even if todos are removed from the list
+pub fn next_id_reducer(next_id: TodoId, action: &Action) -> TodoId {
+    match *action {
+        Todos(Add(_)) => next_id + 1,
+        _ => next_id,
+    }
+}
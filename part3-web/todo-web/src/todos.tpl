@@ -2,7 +2,7 @@
 <html>
   <head>
     <meta charset="utf-8">
-    <title>Nickel Todo</title>
+    <title>{{list.name}} - Nickel Todo</title>
     <link rel="stylesheet" href="http://todomvc.com/examples/react/node_modules/todomvc-common/base.css">
     <link rel="stylesheet" href="http://todomvc.com/examples/react/node_modules/todomvc-app-css/index.css">
     <style type="text/css">
@@ -10,37 +10,430 @@
       color: #4d4d4d;
       text-decoration: none;
     }
+    .todo-list li .edit-todo {
+      margin: 0;
+    }
+    .footer .history {
+      float: right;
+      position: relative;
+      margin-left: 10px;
+      cursor: pointer;
+    }
+    .footer .history:hover {
+      text-decoration: underline;
+    }
+    .todo-list li .details {
+      display: block;
+      font-size: 14px;
+      color: #999;
+    }
+    .todo-list li .details > * {
+      margin-right: 10px;
+    }
+    .todo-list li .details a {
+      color: inherit;
+    }
+    .todo-list li[draggable="true"] label {
+      cursor: grab;
+    }
+    .todo-list li.overdue .due {
+      color: #cf3a3a;
+      font-weight: bold;
+    }
+    .todo-list li .edit-details {
+      display: none;
+    }
+    .todo-list li.editing .edit-details {
+      display: block;
+      margin: 0 0 0 43px;
+      padding: 6px 16px;
+      font-size: 14px;
+    }
+    .header .search-todos {
+      width: 100%;
+      box-sizing: border-box;
+      padding: 8px 16px 8px 60px;
+      border: none;
+      border-top: 1px solid #e6e6e6;
+      font-family: inherit;
+      font-size: 16px;
+    }
+    .header .query-error {
+      margin: 0;
+      padding: 8px 16px 8px 60px;
+      color: #cf3a3a;
+      font-size: 14px;
+    }
+    .info .sorting a.selected {
+      font-weight: bold;
+    }
+    .info .lists a.selected {
+      font-weight: bold;
+    }
+    .info .lists form {
+      display: inline;
+    }
+    .info .lists input {
+      font-size: inherit;
+    }
+    .info .logout button,
+    .info .lists button {
+      color: inherit;
+      font-size: inherit;
+      text-decoration: underline;
+      cursor: pointer;
+    }
     </style>
     <script>
+      // With EventSource the server pushes every new state to us over /events,
+      // so actions are sent in the background and the list is patched when the
+      // new state arrives. Without it we fall back to submitting a form
+      var live = !!window.EventSource;
+
+      // Todos can be dragged around when they're shown in list order
+      var reorderable = {{reorderable}};
+
+      // Every URL of the list we're on starts with this, like /lists/1
+      var base = '{{base}}';
+
+      function post(url, body) {
+        if (live) {
+          var request = new XMLHttpRequest();
+          request.open('POST', url);
+          request.setRequestHeader('Content-Type', 'application/x-www-form-urlencoded');
+          request.send(body || '');
+        } else {
+          var form = document.createElement('form');
+          form.method = 'post';
+          // Keeps our ?filter= so we're sent back to the same view
+          form.action = url + location.search;
+          new URLSearchParams(body || '').forEach(function (value, name) {
+            var input = document.createElement('input');
+            input.type = 'hidden';
+            input.name = name;
+            input.value = value;
+            form.appendChild(input);
+          });
+          document.body.appendChild(form);
+          form.submit();
+        }
+      }
+
+      // Everything that changes the list is a POST, never a plain link
       document.addEventListener('click', function clickHandler(e) {
-        if (e.target && e.target.dataset && e.target.dataset.id) {
-          window.location.href = '/' + e.target.dataset.action + '/' + e.target.dataset.id;
+        if (e.target && e.target.dataset && e.target.dataset.action) {
+          e.preventDefault();
+          var url = base + '/' + e.target.dataset.action;
+          if (e.target.dataset.id) {
+            url += '/' + e.target.dataset.id;
+          }
+          post(url);
+        }
+      });
+
+      // Double-click a title to edit it along with the due date, priority and
+      // tags. Enter, Save or clicking outside the form saves and Escape cancels.
+      // Saving an empty title removes the todo
+      function saveEdit(form) {
+        var li = form.parentNode;
+        if (!li.classList.contains('editing')) { return; }
+        li.classList.remove('editing');
+        if (live) {
+          post(form.getAttribute('action'), new URLSearchParams(new FormData(form)).toString());
+        } else {
+          form.action = form.getAttribute('action') + location.search;
+          form.submit();
+        }
+      }
+
+      document.addEventListener('dblclick', function dblclickHandler(e) {
+        var label = e.target.closest && e.target.closest('.view label');
+        if (label) {
+          var li = label.parentNode.parentNode;
+          var input = li.querySelector('.edit');
+          li.classList.add('editing');
+          input.focus();
+          input.setSelectionRange(input.value.length, input.value.length);
+        }
+      });
+
+      document.addEventListener('keydown', function keydownHandler(e) {
+        var form = e.target.form;
+        if (e.key === 'Escape' && form && form.classList.contains('edit-todo')) {
+          form.reset();
+          form.parentNode.classList.remove('editing');
+          e.target.blur();
         }
       });
+
+      // Moving between the fields of the edit form shouldn't save it, only
+      // leaving the form does
+      document.addEventListener('focusout', function focusoutHandler(e) {
+        var form = e.target.form;
+        if (form && form.classList.contains('edit-todo') && !form.contains(e.relatedTarget)) {
+          saveEdit(form);
+        }
+      });
+
+      document.addEventListener('submit', function submitHandler(e) {
+        if (e.target.classList.contains('edit-todo')) {
+          e.preventDefault();
+          saveEdit(e.target);
+        } else if (live && e.target.classList.contains('add-todo')) {
+          e.preventDefault();
+          var input = e.target.querySelector('.new-todo');
+          post(base, 'todo=' + encodeURIComponent(input.value));
+          input.value = '';
+        } else if (e.target.classList.contains('delete-list') && !confirm('Delete this list and every todo in it?')) {
+          e.preventDefault();
+        }
+      });
+
+      // Dropping a todo on another one moves it in front of that one, or behind
+      // it if it's dropped on the lower half
+      var dragged = null;
+
+      document.addEventListener('DOMContentLoaded', function loadHandler() {
+        Array.prototype.forEach.call(document.querySelectorAll('.todo-list li'), function (li) {
+          li.draggable = reorderable;
+        });
+      });
+
+      document.addEventListener('dragstart', function dragstartHandler(e) {
+        if (e.target.tagName === 'LI' && e.target.draggable && !e.target.classList.contains('editing')) {
+          dragged = e.target;
+          e.dataTransfer.effectAllowed = 'move';
+          e.dataTransfer.setData('text/plain', dragged.dataset.id);
+        }
+      });
+
+      function dropTarget(e) {
+        var li = e.target.closest && e.target.closest('.todo-list li');
+        return dragged && li && li !== dragged ? li : null;
+      }
+
+      document.addEventListener('dragover', function dragoverHandler(e) {
+        if (dropTarget(e)) {
+          e.preventDefault();
+          e.dataTransfer.dropEffect = 'move';
+        }
+      });
+
+      document.addEventListener('drop', function dropHandler(e) {
+        var li = dropTarget(e);
+        if (!li) { return; }
+        e.preventDefault();
+        var box = li.getBoundingClientRect();
+        var before = e.clientY < box.top + box.height / 2 ? li : li.nextElementSibling;
+        if (before === dragged) { return; }
+        // Moved on the page straight away, the new state confirms it
+        li.parentNode.insertBefore(dragged, before);
+        post(base + '/move/' + dragged.dataset.id, 'before=' + (before ? before.dataset.id : ''));
+      });
+
+      document.addEventListener('dragend', function dragendHandler() {
+        dragged = null;
+      });
+
+      // The same date the server goes by, today in UTC
+      function today() {
+        return new Date().toISOString().slice(0, 10);
+      }
+
+      function element(tagName, className, text) {
+        var node = document.createElement(tagName);
+        if (className) { node.className = className; }
+        if (text) { node.textContent = text; }
+        return node;
+      }
+
+      function renderDetails(todo) {
+        var details = element('span', 'details');
+        if (todo.due) {
+          details.appendChild(element('span', 'due', 'Due ' + todo.due));
+        }
+        if (todo.priority) {
+          details.appendChild(element('span', 'priority', todo.priority + ' priority'));
+        }
+        todo.tags.forEach(function (tag) {
+          var link = element('a', 'tag', '#' + tag);
+          link.href = base + '?tag=' + tag;
+          details.appendChild(link);
+        });
+        return details;
+      }
+
+      // The edit form's fields, with their default values set so form.reset()
+      // puts them back the way they were
+      function renderEditDetails(todo) {
+        var details = element('div', 'edit-details');
+
+        var due = document.createElement('input');
+        due.type = 'date';
+        due.name = 'due';
+        due.defaultValue = todo.due || '';
+
+        var priority = document.createElement('select');
+        priority.name = 'priority';
+        [['', 'No priority'], ['low', 'Low'], ['medium', 'Medium'], ['high', 'High']].forEach(function (choice) {
+          var option = element('option', null, choice[1]);
+          option.value = choice[0];
+          option.defaultSelected = (todo.priority || '').toLowerCase() === choice[0];
+          priority.appendChild(option);
+        });
+
+        var tags = document.createElement('input');
+        tags.name = 'tags';
+        tags.placeholder = 'Tags, separated by spaces';
+        tags.defaultValue = todo.tags.join(' ');
+
+        var save = element('button', null, 'Save');
+        save.type = 'submit';
+
+        details.appendChild(due);
+        details.appendChild(priority);
+        details.appendChild(tags);
+        details.appendChild(save);
+        return details;
+      }
+
+      function renderTodo(todo) {
+        var li = document.createElement('li');
+        li.dataset.id = todo.id;
+        li.draggable = reorderable;
+        if (todo.completed) { li.classList.add('completed'); }
+        if (!todo.completed && todo.due !== null && todo.due < today()) { li.classList.add('overdue'); }
+
+        var view = document.createElement('div');
+        view.className = 'view';
+
+        var toggle = document.createElement('input');
+        toggle.className = 'toggle';
+        toggle.type = 'checkbox';
+        toggle.checked = todo.completed;
+        toggle.dataset.id = todo.id;
+        toggle.dataset.action = 'toggle';
+
+        var label = document.createElement('label');
+        label.textContent = todo.title;
+        label.appendChild(renderDetails(todo));
+
+        var destroy = document.createElement('button');
+        destroy.className = 'destroy';
+        destroy.dataset.id = todo.id;
+        destroy.dataset.action = 'remove';
+
+        var form = document.createElement('form');
+        form.className = 'edit-todo';
+        form.method = 'post';
+        form.action = base + '/edit/' + todo.id;
+
+        var edit = document.createElement('input');
+        edit.className = 'edit';
+        edit.name = 'title';
+        edit.defaultValue = todo.title;
+
+        view.appendChild(toggle);
+        view.appendChild(label);
+        view.appendChild(destroy);
+        form.appendChild(edit);
+        form.appendChild(renderEditDetails(todo));
+        li.appendChild(view);
+        li.appendChild(form);
+        return li;
+      }
+
+      function showTodos(todos) {
+        var list = document.querySelector('.todo-list');
+        list.innerHTML = '';
+        todos
+          .filter(function (todo) { return !todo.deleted; })
+          .forEach(function (todo) { list.appendChild(renderTodo(todo)); });
+      }
+
+      // Which todos to show and in what order is worked out on the server, so
+      // the list is fetched with the same ?filter=, ?tag=, ?sort= and ?q= as this
+      // page. Without any of those it's every todo in the State we were sent
+      var listRequest = null;
+      function renderList(state) {
+        if (listRequest) { listRequest.abort(); }
+        if (!location.search) {
+          showTodos(state.todos);
+          return;
+        }
+        listRequest = new XMLHttpRequest();
+        listRequest.open('GET', '/api' + base + '/todos' + location.search);
+        listRequest.onload = function () {
+          if (this.status === 200) { showTodos(JSON.parse(this.responseText).todos); }
+        };
+        listRequest.send();
+      }
+
+      function renderState(state) {
+        renderList(state);
+
+        var todos = state.todos.filter(function (todo) { return !todo.deleted; });
+        var count = todos.filter(function (todo) { return !todo.completed; }).length;
+        document.querySelector('.todo-count strong').textContent =
+          count + (count === 1 ? ' item left' : ' items left');
+
+        // Same rules as the all_completed and any_completed helpers
+        var allCompleted = todos.length > 0 && count === 0;
+        var toggleAll = document.querySelector('.toggle-all');
+        toggleAll.checked = allCompleted;
+        toggleAll.dataset.id = allCompleted ? 'false' : 'true';
+        document.querySelector('.clear-completed').hidden = count === todos.length;
+      }
+
+      if (live) {
+        new EventSource(base + '/events').onmessage = function (e) {
+          renderState(JSON.parse(e.data));
+        };
+      }
     </script>
   </head>
   <body>
     <section class="todoapp">
       <header class="header">
         <h1>todos</h1>
-        <form class="add-todo" action="/" method="post">
+        <form class="add-todo" action="{{base}}{{query}}" method="post">
           <input class="new-todo" placeholder="What needs to be done?" name="todo">
         </form>
+        <form class="search" action="{{base}}/search" method="post">
+          <input class="search-todos" name="q" value="{{search}}" placeholder="Search, like status:active tag:ops due:<2026-11-01 sort:-priority">
+        </form>
+        {{#if query_error}}<p class="query-error">{{query_error}}</p>{{/if}}
       </header>
       <section class="main">
+        <input id="toggle-all" class="toggle-all" type="checkbox" data-action="toggle-all"{{#all_completed}} checked="checked" data-id="false"{{else}} data-id="true"{{/all_completed}}>
+        <label for="toggle-all">Mark all as complete</label>
         <ul class="todo-list">
-          {{#each todos}}
-          {{#unless deleted}}
-          {{#filter_todo}}
-          <li{{#if completed}} class="completed"{{/if}} data-id={{id}}>
+          {{#each visible}}
+          <li class="{{#if completed}}completed{{/if}}{{#if overdue}} overdue{{/if}}" data-id={{id}}>
             <div class="view">
               <input class="toggle" type="checkbox"{{#if completed}} checked="checked"{{/if}} data-id={{id}} data-action="toggle">
-              <label><a href="/toggle/{{id}}">{{title}}</a></label>
+              <label>{{title}}<span class="details">
+                {{#if due}}<span class="due">Due {{due}}</span>{{/if}}
+                {{#if priority}}<span class="priority">{{priority}} priority</span>{{/if}}
+                {{#each tags}}<a class="tag" href="?tag={{this}}">#{{this}}</a>{{/each}}
+              </span></label>
               <button class="destroy" data-id={{id}} data-action="remove"></button>
             </div>
+            <form class="edit-todo" action="{{edit_url}}" method="post">
+              <input class="edit" name="title" value="{{title}}">
+              <div class="edit-details">
+                <input type="date" name="due" value="{{due}}">
+                <select name="priority">
+                  <option value="">No priority</option>
+                  <option value="low"{{#is_priority "Low"}} selected{{/is_priority}}>Low</option>
+                  <option value="medium"{{#is_priority "Medium"}} selected{{/is_priority}}>Medium</option>
+                  <option value="high"{{#is_priority "High"}} selected{{/is_priority}}>High</option>
+                </select>
+                <input name="tags" value="{{tag_list}}" placeholder="Tags, separated by spaces">
+                <button type="submit">Save</button>
+              </div>
+            </form>
           </li>
-          {{/filter_todo}}
-          {{/unless}}
           {{/each}}
         </ul>
       </section>
@@ -49,22 +442,61 @@
         <span class="todo-count">
           <strong>{{#active_count}}{{/active_count}}</strong>
         </span>
+        <button class="clear-completed" data-action="clear-completed"{{#any_completed}}{{else}} hidden{{/any_completed}}>Clear completed</button>
+        <button class="history" data-action="redo">Redo</button>
+        <button class="history" data-action="undo">Undo</button>
         <ul class="filters">
           <li>
-            <a href="/show/all"{{#is_selected_filter "ShowAll"}} class="selected"{{/is_selected_filter}}>All</a>
+            <a href="{{filter_links.all}}"{{#is_selected_filter "ShowAll"}} class="selected"{{/is_selected_filter}}>All</a>
           </li>
           <span> </span>
           <li>
-            <a href="/show/active"{{#is_selected_filter "ShowActive"}} class="selected"{{/is_selected_filter}}>Active</a>
+            <a href="{{filter_links.active}}"{{#is_selected_filter "ShowActive"}} class="selected"{{/is_selected_filter}}>Active</a>
           </li>
           <span> </span>
           <li>
-            <a href="/show/completed"{{#is_selected_filter "ShowCompleted"}} class="selected"{{/is_selected_filter}}>Completed</a>
+            <a href="{{filter_links.completed}}"{{#is_selected_filter "ShowCompleted"}} class="selected"{{/is_selected_filter}}>Completed</a>
           </li>
 
         </ul>
       </footer>
     </section>
+    <footer class="info">
+      <div class="lists">
+        <p>
+          Lists:
+          {{#each open_lists}}<a href="{{url}}"{{#if current}} class="selected"{{/if}}>{{name}}</a> {{/each}}
+        </p>
+        {{#if archived_lists}}<p>Archived: {{#each archived_lists}}<a href="{{url}}"{{#if current}} class="selected"{{/if}}>{{name}}</a> {{/each}}</p>{{/if}}
+        <form action="/lists" method="post"><input name="name" placeholder="New list"></form>
+        <form action="{{base}}/rename" method="post"><input name="name" value="{{list.name}}"> <button type="submit">Rename</button></form>
+        {{#if list.archived}}
+        <form action="{{base}}/unarchive" method="post"><button type="submit">Unarchive</button></form>
+        {{else}}
+        <form action="{{base}}/archive" method="post"><button type="submit">Archive</button></form>
+        {{/if}}
+        {{#if can_delete_list}}<form class="delete-list" action="{{base}}/delete" method="post"><button type="submit">Delete</button></form>{{/if}}
+      </div>
+      <p class="sorting">
+        Sort by
+        <a href="{{sort_links.created}}"{{#is_selected_sort "created"}} class="selected"{{/is_selected_sort}}>list order</a>,
+        <a href="{{sort_links.due}}"{{#is_selected_sort "due"}} class="selected"{{/is_selected_sort}}>due date</a>,
+        <a href="{{sort_links.priority}}"{{#is_selected_sort "priority"}} class="selected"{{/is_selected_sort}}>priority</a> or
+        <a href="{{sort_links.title}}"{{#is_selected_sort "title"}} class="selected"{{/is_selected_sort}}>title</a>
+      </p>
+      {{#if tag}}<p>Only showing #{{tag}}, <a href="{{untagged_link}}">show every tag</a></p>{{/if}}
+      <p>
+        Export as <a href="{{base}}/export/json">JSON</a>, <a href="{{base}}/export/csv">CSV</a>,
+        <a href="{{base}}/export/markdown">Markdown</a>, <a href="{{base}}/export/todotxt">todo.txt</a>
+        or <a href="{{base}}/export/ical">iCalendar</a>,
+        or <a href="{{base}}/import">import</a> a file
+      </p>
+      <p>Calendar apps can sync this list over CalDAV from /caldav{{base}}/</p>
+      <p><a href="{{base}}/trash">Trash</a></p>
+      <form class="logout" action="/logout" method="post">
+        <p>Signed in as {{username}} <button type="submit">Log out</button></p>
+      </form>
+    </footer>
 
   </body>
 </html>
@@ -0,0 +1,890 @@
+use std::collections::{ BTreeMap, BTreeSet };
+use std::fmt;
+use std::mem;
+use rustc_serialize::json::{ Json, ToJson };
+use config;
+use ical;
+use lists::TodoList;
+use store::{ State, TodoStore };
+use store::Action::{ Todos };
+use todo::{ Todo, TodoId, Priority, parse_date, parse_tag };
+use todo::TodoAction::{ Add, AddTag, SetDue, SetPriority, Toggle };
+
+// Getting todos in and out of a list. Every format can be exported and imported
+//
+//     json      an array of todos, like GET /api/todos has them
+//     csv       a header line and then id,title,completed,due,priority,tags
+//     markdown  a GitHub checklist, - [ ] for open todos and - [x] for done ones
+//     todotxt   todo.txt lines, x for done, (A) to (C) for the priority,
+//               due:2016-06-17 for the due date and +tags
+//     ical      an iCalendar file with a VTODO for every todo, see ical.rs
+//
+// Importing goes by title. A todo that's already in the list only gets its
+// completed state brought in line with the file, the rest are added
+//
+// part2-borrowing/redux-light has the same formats in its transfer.rs, less
+// iCalendar and with its own small JSON reader. Apart from that the two are
+// meant to mirror each other, so a fix to one goes in the other too
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    Csv,
+    Markdown,
+    TodoTxt,
+    ICalendar,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name.to_lowercase().as_str() {
+            "json" => Some(Format::Json),
+            "csv" => Some(Format::Csv),
+            "markdown" | "md" => Some(Format::Markdown),
+            "todotxt" | "todo.txt" => Some(Format::TodoTxt),
+            "ical" | "ics" | "icalendar" => Some(Format::ICalendar),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Format::Json => "json",
+            Format::Csv => "csv",
+            Format::Markdown => "markdown",
+            Format::TodoTxt => "todotxt",
+            Format::ICalendar => "ical",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            Format::Json => "json",
+            Format::Csv => "csv",
+            Format::Markdown => "md",
+            Format::TodoTxt => "txt",
+            Format::ICalendar => "ics",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match *self {
+            Format::Json => "application/json; charset=utf-8",
+            Format::Csv => "text/csv; charset=utf-8",
+            Format::Markdown => "text/markdown; charset=utf-8",
+            Format::TodoTxt => "text/plain; charset=utf-8",
+            Format::ICalendar => "text/calendar; charset=utf-8",
+        }
+    }
+}
+
+// One todo as a file describes it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    // The line it's on, or for JSON which todo in the array it is, from 1
+    pub line: usize,
+    pub title: String,
+    pub completed: bool,
+    pub due: Option<String>,
+    pub priority: Option<Priority>,
+    pub tags: BTreeSet<String>,
+}
+
+impl Entry {
+    pub fn new(line: usize, title: &str, completed: bool) -> Entry {
+        Entry {
+            line: line,
+            title: title.trim().to_string(),
+            completed: completed,
+            due: None,
+            priority: None,
+            tags: BTreeSet::new(),
+        }
+    }
+}
+
+// Where a file stopped making sense
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+fn error<T>(line: usize, message: String) -> Result<T, ParseError> {
+    Err(ParseError { line: line, message: message })
+}
+
+// Titles can't span lines in the line based formats
+fn one_line(title: &str) -> String {
+    title.lines().map(|line| line.trim()).collect::<Vec<_>>().join(" ")
+}
+
+fn priority_name(priority: Option<Priority>) -> &'static str {
+    match priority {
+        Some(Priority::Low) => "low",
+        Some(Priority::Medium) => "medium",
+        Some(Priority::High) => "high",
+        None => "",
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", field.replace("\"", "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn todo_txt_priority(priority: Priority) -> char {
+    match priority {
+        Priority::High => 'A',
+        Priority::Medium => 'B',
+        Priority::Low => 'C',
+    }
+}
+
+// (A) is high, (B) medium and everything from (C) on is low
+fn from_todo_txt_priority(letter: char) -> Option<Priority> {
+    match letter {
+        'A' => Some(Priority::High),
+        'B' => Some(Priority::Medium),
+        letter if letter >= 'C' && letter <= 'Z' => Some(Priority::Low),
+        _ => None,
+    }
+}
+
+// Writes the todos in `list` out in `format`, deleted todos are left out
+pub fn export(list: &TodoList, todos: &[Todo], format: Format) -> String {
+    let todos: Vec<&Todo> = todos.iter().filter(|todo| !todo.deleted).collect();
+    let mut output = String::new();
+    match format {
+        Format::Json => {
+            let todos: Vec<Json> = todos.iter().map(|todo| todo.to_json()).collect();
+            output.push_str(&Json::Array(todos).pretty().to_string());
+            output.push('\n');
+        },
+        Format::Csv => {
+            output.push_str("id,title,completed,due,priority,tags\r\n");
+            for todo in todos {
+                let tags: Vec<&str> = todo.tags.iter().map(|tag| tag.as_str()).collect();
+                let fields = [
+                    todo.id.to_string(),
+                    csv_field(&todo.title),
+                    todo.completed.to_string(),
+                    todo.due.clone().unwrap_or(String::new()),
+                    priority_name(todo.priority).to_string(),
+                    tags.join(" "),
+                ];
+                output.push_str(&fields.join(","));
+                output.push_str("\r\n");
+            }
+        },
+        Format::Markdown => {
+            for todo in todos {
+                let check = if todo.completed { "x" } else { " " };
+                output.push_str(&format!("- [{}] {}\n", check, one_line(&todo.title)));
+            }
+        },
+        Format::TodoTxt => {
+            for todo in todos {
+                let mut parts = Vec::new();
+                // todo.txt wants done todos to start with x, and the priority
+                // goes in a pri: tag for those
+                match (todo.completed, todo.priority) {
+                    (true, priority) => {
+                        parts.push("x".to_string());
+                        parts.push(one_line(&todo.title));
+                        if let Some(priority) = priority {
+                            parts.push(format!("pri:{}", todo_txt_priority(priority)));
+                        }
+                    },
+                    (false, Some(priority)) => {
+                        parts.push(format!("({})", todo_txt_priority(priority)));
+                        parts.push(one_line(&todo.title));
+                    },
+                    (false, None) => parts.push(one_line(&todo.title)),
+                }
+                if let Some(ref due) = todo.due {
+                    parts.push(format!("due:{}", due));
+                }
+                for tag in &todo.tags {
+                    parts.push(format!("+{}", tag));
+                }
+                output.push_str(&parts.join(" "));
+                output.push('\n');
+            }
+        },
+        Format::ICalendar => output.push_str(&ical::calendar(list, &todos)),
+    }
+    output
+}
+
+// Reads the todos in a file. Lines that aren't todos, like the headings in a
+// Markdown file, are skipped, but a todo we can't make sense of is an error
+pub fn parse(text: &str, format: Format) -> Result<Vec<Entry>, ParseError> {
+    match format {
+        Format::Json => parse_json(text),
+        Format::Csv => parse_csv(text),
+        Format::Markdown => Ok(parse_markdown(text)),
+        Format::TodoTxt => parse_todo_txt(text),
+        Format::ICalendar => ical::parse(text),
+    }
+}
+
+// Takes an array of todos, or anything with the array in a todos field like
+// the State from GET /api/todos
+fn parse_json(text: &str) -> Result<Vec<Entry>, ParseError> {
+    let json = try!(Json::from_str(text).or_else(|e| error(1, format!("invalid JSON: {}", e))));
+    let todos = match json {
+        Json::Array(todos) => todos,
+        Json::Object(mut object) => match object.remove("todos") {
+            Some(Json::Array(todos)) => todos,
+            _ => return error(1, "expected an array of todos, or an object with one in `todos`".to_string()),
+        },
+        _ => return error(1, "expected an array of todos".to_string()),
+    };
+
+    let mut entries = Vec::new();
+    for (index, todo) in todos.iter().enumerate() {
+        let line = index + 1;
+        let field = |name: &str| todo.find(name).and_then(|value| if value.is_null() { None } else { Some(value) });
+        if field("deleted").and_then(|deleted| deleted.as_boolean()).unwrap_or(false) {
+            continue;
+        }
+        let title = match field("title").and_then(|title| title.as_string()) {
+            Some(title) => title,
+            None => return error(line, "every todo needs a title".to_string()),
+        };
+        let completed = match field("completed") {
+            Some(completed) => try!(completed.as_boolean()
+                .ok_or(ParseError { line: line, message: "completed must be true or false".to_string() })),
+            None => false,
+        };
+        let mut entry = Entry::new(line, title, completed);
+        if let Some(due) = field("due") {
+            entry.due = Some(try!(due.as_string().and_then(parse_date)
+                .ok_or(ParseError { line: line, message: "due must be a date like 2016-06-17".to_string() })));
+        }
+        if let Some(priority) = field("priority") {
+            entry.priority = Some(try!(priority.as_string().and_then(Priority::from_name)
+                .ok_or(ParseError { line: line, message: "priority must be low, medium or high".to_string() })));
+        }
+        if let Some(tags) = field("tags") {
+            entry.tags = try!(tags.as_array()
+                .and_then(|tags| tags.iter().map(|tag| tag.as_string().and_then(parse_tag)).collect::<Option<BTreeSet<_>>>())
+                .ok_or(ParseError { line: line, message: "tags must be an array of single words".to_string() }));
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+// Splits CSV into records of fields, with the line each record starts on.
+// Quoted fields can have commas, "" for a quote and line breaks in them
+fn csv_records(text: &str) -> Result<Vec<(usize, Vec<String>)>, ParseError> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut line = 1;
+    let mut record_line = 1;
+    let mut in_quotes = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\n' { line += 1; }
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => { chars.next(); field.push('"'); },
+                '"' => in_quotes = false,
+                c => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => in_quotes = true,
+            ',' => record.push(mem::replace(&mut field, String::new())),
+            '\r' => (),
+            '\n' => {
+                record.push(mem::replace(&mut field, String::new()));
+                records.push((record_line, mem::replace(&mut record, Vec::new())));
+                record_line = line;
+            },
+            c => field.push(c),
+        }
+    }
+    if in_quotes {
+        return error(record_line, "a quoted field is never closed".to_string());
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push((record_line, record));
+    }
+    // Blank lines aren't todos
+    records.retain(|&(_, ref record)| !(record.len() == 1 && record[0].trim().is_empty()));
+    Ok(records)
+}
+
+fn parse_completed(value: &str) -> Option<bool> {
+    match value.trim().to_lowercase().as_str() {
+        "true" | "yes" | "1" | "x" | "done" => Some(true),
+        "false" | "no" | "0" | "" => Some(false),
+        _ => None,
+    }
+}
+
+// The first line names the columns, only title is required and columns we
+// don't know are ignored. Tags are separated by spaces
+fn parse_csv(text: &str) -> Result<Vec<Entry>, ParseError> {
+    let mut records = try!(csv_records(text)).into_iter();
+    let header = match records.next() {
+        Some((_, header)) => header,
+        None => return Ok(Vec::new()),
+    };
+    let column = |name: &str| header.iter().position(|column| column.trim().to_lowercase() == name);
+    let title_column = try!(column("title")
+        .ok_or(ParseError { line: 1, message: "the first line needs a title column".to_string() }));
+    let (completed_column, due_column, priority_column, tags_column) =
+        (column("completed"), column("due"), column("priority"), column("tags"));
+
+    let mut entries = Vec::new();
+    for (line, record) in records {
+        let value = |column: Option<usize>| column.and_then(|column| record.get(column))
+            .map(|value| value.trim())
+            .unwrap_or("");
+        let completed = try!(parse_completed(value(completed_column))
+            .ok_or(ParseError { line: line, message: format!("completed can't be `{}`", value(completed_column)) }));
+        let mut entry = Entry::new(line, value(Some(title_column)), completed);
+        match value(due_column) {
+            "" => (),
+            due => entry.due = Some(try!(parse_date(due)
+                .ok_or(ParseError { line: line, message: format!("`{}` is not a date like 2016-06-17", due) }))),
+        }
+        match value(priority_column) {
+            "" => (),
+            priority => entry.priority = Some(try!(Priority::from_name(priority)
+                .ok_or(ParseError { line: line, message: format!("`{}` is not low, medium or high", priority) }))),
+        }
+        for tag in value(tags_column).split_whitespace() {
+            entry.tags.insert(try!(parse_tag(tag)
+                .ok_or(ParseError { line: line, message: format!("`{}` is not a single word tag", tag) })));
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+// Every `- [ ] title` or `- [x] title` line, * and + work as bullets too
+fn parse_markdown(text: &str) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line_text = line.trim();
+        let item = match line_text.chars().next() {
+            Some('-') | Some('*') | Some('+') => line_text[1..].trim(),
+            _ => continue,
+        };
+        let completed = if item.starts_with("[ ]") {
+            false
+        } else if item.starts_with("[x]") || item.starts_with("[X]") {
+            true
+        } else {
+            continue;
+        };
+        entries.push(Entry::new(index + 1, &item[3..], completed));
+    }
+    entries
+}
+
+fn is_date(word: &str) -> bool {
+    word.len() == 10 && parse_date(word).is_some()
+}
+
+// A todo.txt line is `x` for done, then the dates it was done and created,
+// then (A) for the priority. due:, pri:, +project and @context tags can be
+// anywhere after that, and both projects and contexts become tags
+fn parse_todo_txt(text: &str) -> Result<Vec<Entry>, ParseError> {
+    let mut entries = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let mut words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+        let completed = words[0] == "x";
+        if completed {
+            words.remove(0);
+        }
+        let mut priority = None;
+        if let Some(first) = words.first().cloned() {
+            let letters: Vec<char> = first.chars().collect();
+            if letters.len() == 3 && letters[0] == '(' && letters[2] == ')' {
+                priority = from_todo_txt_priority(letters[1]);
+                if priority.is_some() { words.remove(0); }
+            }
+        }
+        // Up to two dates, when it was done and when it was created
+        for _ in 0..2 {
+            if words.first().map_or(false, |word| is_date(word)) {
+                words.remove(0);
+            }
+        }
+
+        let mut title = Vec::new();
+        let mut entry = Entry::new(line_number, "", completed);
+        entry.priority = priority;
+        for word in words {
+            let tag = if word.len() > 1 && (word.starts_with('+') || word.starts_with('@')) {
+                parse_tag(&word[1..])
+            } else {
+                None
+            };
+            if word.starts_with("due:") {
+                entry.due = Some(try!(parse_date(&word[4..]).ok_or(ParseError {
+                    line: line_number,
+                    message: format!("`{}` is not a date like due:2016-06-17", word),
+                })));
+            } else if word.starts_with("pri:") && word.len() == 5 {
+                entry.priority = word[4..].chars().next().and_then(from_todo_txt_priority);
+            } else if let Some(tag) = tag {
+                entry.tags.insert(tag);
+            } else {
+                title.push(word);
+            }
+        }
+        entry.title = title.join(" ");
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+// What importing one entry would do
+#[derive(Clone, Debug)]
+pub enum Change {
+    Add(Entry),
+    // A todo that's in the list already, marked as done
+    Complete(TodoId, Entry),
+    // Or marked as not done any more
+    Reopen(TodoId, Entry),
+    Unchanged(TodoId, Entry),
+    // The entry can't be a todo, with why not
+    Skip(Entry, String),
+}
+
+impl Change {
+    fn name(&self) -> &'static str {
+        match *self {
+            Change::Add(_) => "add",
+            Change::Complete(..) => "complete",
+            Change::Reopen(..) => "reopen",
+            Change::Unchanged(..) => "unchanged",
+            Change::Skip(..) => "skip",
+        }
+    }
+
+    fn entry(&self) -> &Entry {
+        match *self {
+            Change::Add(ref entry) |
+            Change::Complete(_, ref entry) |
+            Change::Reopen(_, ref entry) |
+            Change::Unchanged(_, ref entry) |
+            Change::Skip(ref entry, _) => entry,
+        }
+    }
+}
+
+impl ToJson for Change {
+    fn to_json(&self) -> Json {
+        let entry = self.entry();
+        let mut object = BTreeMap::new();
+        object.insert("change".to_string(), self.name().to_json());
+        object.insert("line".to_string(), entry.line.to_json());
+        object.insert("title".to_string(), entry.title.to_json());
+        object.insert("completed".to_string(), entry.completed.to_json());
+        match *self {
+            Change::Complete(todo_id, _) | Change::Reopen(todo_id, _) | Change::Unchanged(todo_id, _) => {
+                object.insert("id".to_string(), todo_id.to_json());
+            },
+            Change::Skip(_, ref reason) => {
+                object.insert("reason".to_string(), reason.to_json());
+            },
+            Change::Add(_) => (),
+        }
+        Json::Object(object)
+    }
+}
+
+// Everything an import would change, worked out before anything is
+// dispatched so it can be shown as a dry run first
+#[derive(Clone, Debug)]
+pub struct ImportPlan {
+    pub changes: Vec<Change>,
+}
+
+// Matches the entries against the todos in `state` by title. Each todo is only
+// matched once, so a file with the same title twice adds the second one
+pub fn plan(state: &State, entries: Vec<Entry>) -> ImportPlan {
+    let max_title_length = config::limits().max_title_length;
+    let mut matched = BTreeSet::new();
+    let mut changes = Vec::new();
+    for entry in entries {
+        if entry.title.is_empty() {
+            changes.push(Change::Skip(entry, "it has no title".to_string()));
+            continue;
+        }
+        if entry.title.chars().count() > max_title_length {
+            changes.push(Change::Skip(entry, format!("the title is longer than {} characters", max_title_length)));
+            continue;
+        }
+        let existing = state.todos.iter()
+            .find(|todo| !todo.deleted && !matched.contains(&todo.id) && todo.title == entry.title)
+            .map(|todo| (todo.id, todo.completed));
+        changes.push(match existing {
+            Some((todo_id, completed)) => {
+                matched.insert(todo_id);
+                match (completed, entry.completed) {
+                    (false, true) => Change::Complete(todo_id, entry),
+                    (true, false) => Change::Reopen(todo_id, entry),
+                    _ => Change::Unchanged(todo_id, entry),
+                }
+            },
+            None => Change::Add(entry),
+        });
+    }
+    ImportPlan { changes: changes }
+}
+
+impl ImportPlan {
+    fn count(&self, name: &str) -> usize {
+        self.changes.iter().filter(|change| change.name() == name).count()
+    }
+
+    // Dispatches the plan on the store it was made for, in one batch so one
+    // undo takes the whole import back
+    pub fn apply(&self, store: &mut TodoStore) {
+        store.batch(|store| {
+            for change in &self.changes {
+                match *change {
+                    Change::Add(ref entry) => {
+                        add(store, entry);
+                    },
+                    Change::Complete(todo_id, _) | Change::Reopen(todo_id, _) => {
+                        store.dispatch( Todos( Toggle(todo_id) ) );
+                    },
+                    Change::Unchanged(..) | Change::Skip(..) => (),
+                }
+            }
+        })
+    }
+}
+
+// Adds the entry as a new todo, an Add followed by a Toggle if it's done and
+// its due date, priority and tags, all in one batch. Returns the new todo's
+// id, unless the validate middleware refused it
+pub fn add(store: &mut TodoStore, entry: &Entry) -> Option<TodoId> {
+    store.batch(|store| {
+        let todo_id = store.get_state().next_id;
+        store.dispatch( Todos( Add(entry.title.clone()) ) );
+        if !store.get_state().todos.iter().any(|todo| todo.id == todo_id) {
+            return None;
+        }
+        if entry.completed {
+            store.dispatch( Todos( Toggle(todo_id) ) );
+        }
+        if entry.due.is_some() {
+            store.dispatch( Todos( SetDue(todo_id, entry.due.clone()) ) );
+        }
+        if entry.priority.is_some() {
+            store.dispatch( Todos( SetPriority(todo_id, entry.priority) ) );
+        }
+        for tag in &entry.tags {
+            store.dispatch( Todos( AddTag(todo_id, tag.clone()) ) );
+        }
+        Some(todo_id)
+    })
+}
+
+// The report, how many todos each kind of change has and then every change
+impl ToJson for ImportPlan {
+    fn to_json(&self) -> Json {
+        let mut object = BTreeMap::new();
+        for &(key, name) in &[("added", "add"), ("completed", "complete"), ("reopened", "reopen"),
+                              ("unchanged", "unchanged"), ("skipped", "skip")] {
+            object.insert(key.to_string(), self.count(name).to_json());
+        }
+        object.insert("changes".to_string(), self.changes.to_json());
+        Json::Object(object)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use lists::TodoList;
+    use store::{ Store, State, reducer };
+    use store::Action::{ Todos };
+    use todo::{ Todo, Priority };
+    use todo::TodoAction::{ Add };
+    use super::{ Entry, Format, export, parse, plan };
+
+    fn list() -> TodoList {
+        TodoList { id: 1, name: "Home".to_string(), archived: false }
+    }
+
+    fn tags(tags: &[&str]) -> BTreeSet<String> {
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    fn todos() -> Vec<Todo> {
+        let mut todos = vec![Todo::new(1, "Pay rent, \"now\"".to_string()), Todo::new(2, "Call mum".to_string()),
+                             Todo::new(3, "Thrown away".to_string()), Todo::new(4, "Plain".to_string())];
+        todos[0].completed = true;
+        todos[0].due = Some("2026-11-01".to_string());
+        todos[0].priority = Some(Priority::High);
+        todos[0].tags = tags(&["bills", "home"]);
+        todos[1].priority = Some(Priority::Low);
+        todos[1].due = Some("2026-10-18".to_string());
+        todos[1].tags = tags(&["phone"]);
+        todos[2].deleted = true;
+        todos
+    }
+
+    // Everything but the line, which depends on the format
+    fn summary(entries: &[Entry]) -> Vec<(String, bool, Option<String>, Option<Priority>, BTreeSet<String>)> {
+        entries.iter().map(|entry| {
+            (entry.title.clone(), entry.completed, entry.due.clone(), entry.priority, entry.tags.clone())
+        }).collect()
+    }
+
+    // Files as other tools write them, rather than as export does
+
+    // A release checklist from a GitHub issue
+    const GITHUB_CHECKLIST: &'static str = "\
+## Release 0.3
+
+Left to do before we tag it, see #42.
+
+- [x] Bump the version in `Cargo.toml`
+- [ ] Write the changelog
+  - [ ] Mention the new import formats
+  - [X] Thank @jdoe and @mkim
+* [ ] Check the [docs](https://example.com/docs) build
+- Not a task, just a note
++ [x] Tag the release
+";
+
+    // From a phone app that syncs todo.txt. Done todos have the date they
+    // were done and created, t: is a threshold date we don't know about
+    const TODO_TXT: &'static str = "\
+(A) 2026-10-01 Call the landlord about the boiler @Phone +Flat
+x 2026-10-12 2026-10-02 Renew passport +Travel due:2026-10-15
+2026-10-05 Pick up dry cleaning @errands t:2026-10-20
+(C) Read \"Designing Data-Intensive Applications\" +books
+";
+
+    // Saved from a spreadsheet, which ends rows with CRLF but keeps a bare LF
+    // for the line breaks in a cell
+    const SPREADSHEET_CSV: &'static str = "Title,Completed,Due,Priority,Tags,Notes\r\n\
+Pay rent,TRUE,2026-11-01,High,bills home,\r\n\
+\"Call mum, then dad\",FALSE,,low,phone,\"Ask about\nthe weekend\"\r\n\
+Plain,,,,,\r\n";
+
+    // Another app's backup, with fields we don't read and escapes
+    const APP_JSON: &'static str = r##"{
+  "exported": "2026-10-18T09:30:00Z",
+  "todos": [
+    {"id": 17, "title": "Water the plants \u2618", "completed": false, "due": "2026-10-20",
+     "priority": "medium", "tags": ["home"], "notes": {"text": "the balcony ones", "pinned": false}},
+    {"id": 18, "title": "Send the \"final\" report", "completed": true, "due": null, "priority": null, "tags": []},
+    {"id": 19, "title": "Old idea", "deleted": true},
+    {"id": 20, "title": "Plan trip \ud83c\udfd4", "tags": ["Travel", "#Summer"], "estimate": 1.5e1}
+  ]
+}"##;
+
+    // A calendar app's export, folded lines, a VEVENT to skip, a VALARM in a
+    // VTODO and a due date with a time zone
+    const CALENDAR_ICS: &'static str = "BEGIN:VCALENDAR\r\n\
+VERSION:2.0\r\n\
+PRODID:-//Example Corp.//Calendar 12.0//EN\r\n\
+BEGIN:VEVENT\r\n\
+UID:event-1@example.com\r\n\
+SUMMARY:Team lunch\r\n\
+DTSTART:20261020T120000Z\r\n\
+END:VEVENT\r\n\
+BEGIN:VTODO\r\n\
+UID:0F3A6C2E-1D6B-4C55-9E0F-2F9C1B7A4D10\r\n\
+DTSTAMP:20261018T093000Z\r\n\
+SUMMARY:Renew the car insurance\\, compare at least three quotes before \r\n deciding\r\n\
+DUE;TZID=Europe/Stockholm:20261101T170000\r\n\
+PRIORITY:1\r\n\
+CATEGORIES:car,money,Paperwork\r\n\
+BEGIN:VALARM\r\n\
+ACTION:DISPLAY\r\n\
+DESCRIPTION:Reminder\r\n\
+TRIGGER:-P1D\r\n\
+END:VALARM\r\n\
+END:VTODO\r\n\
+BEGIN:VTODO\r\n\
+UID:7C1E9B44-0A2D-4F7B-8E3C-5D6A9F0B1C22\r\n\
+SUMMARY:Return library books\r\n\
+STATUS:COMPLETED\r\n\
+COMPLETED:20261015T081500Z\r\n\
+PRIORITY:9\r\n\
+END:VTODO\r\n\
+END:VCALENDAR\r\n";
+
+    #[test]
+    fn reads_a_github_checklist() {
+        let entries = parse(GITHUB_CHECKLIST, Format::Markdown).unwrap();
+        let titles: Vec<(usize, &str, bool)> = entries.iter()
+            .map(|entry| (entry.line, entry.title.as_str(), entry.completed))
+            .collect();
+        assert_eq!(titles, vec![
+            (5, "Bump the version in `Cargo.toml`", true),
+            (6, "Write the changelog", false),
+            (7, "Mention the new import formats", false),
+            (8, "Thank @jdoe and @mkim", true),
+            (9, "Check the [docs](https://example.com/docs) build", false),
+            (11, "Tag the release", true),
+        ]);
+    }
+
+    #[test]
+    fn reads_todo_txt_from_another_app() {
+        let entries = parse(TODO_TXT, Format::TodoTxt).unwrap();
+        assert_eq!(summary(&entries), vec![
+            ("Call the landlord about the boiler".to_string(), false, None, Some(Priority::High), tags(&["flat", "phone"])),
+            ("Renew passport".to_string(), true, Some("2026-10-15".to_string()), None, tags(&["travel"])),
+            ("Pick up dry cleaning t:2026-10-20".to_string(), false, None, None, tags(&["errands"])),
+            ("Read \"Designing Data-Intensive Applications\"".to_string(), false, None, Some(Priority::Low), tags(&["books"])),
+        ]);
+    }
+
+    #[test]
+    fn reads_csv_from_a_spreadsheet() {
+        let entries = parse(SPREADSHEET_CSV, Format::Csv).unwrap();
+        assert_eq!(summary(&entries), vec![
+            ("Pay rent".to_string(), true, Some("2026-11-01".to_string()), Some(Priority::High), tags(&["bills", "home"])),
+            ("Call mum, then dad".to_string(), false, None, Some(Priority::Low), tags(&["phone"])),
+            ("Plain".to_string(), false, None, None, tags(&[])),
+        ]);
+        assert_eq!(entries.iter().map(|entry| entry.line).collect::<Vec<_>>(), vec![2, 3, 5]);
+    }
+
+    #[test]
+    fn reads_json_from_another_app() {
+        let entries = parse(APP_JSON, Format::Json).unwrap();
+        assert_eq!(summary(&entries), vec![
+            ("Water the plants \u{2618}".to_string(), false, Some("2026-10-20".to_string()), Some(Priority::Medium), tags(&["home"])),
+            ("Send the \"final\" report".to_string(), true, None, None, tags(&[])),
+            ("Plan trip \u{1f3d4}".to_string(), false, None, None, tags(&["summer", "travel"])),
+        ]);
+        assert_eq!(entries.iter().map(|entry| entry.line).collect::<Vec<_>>(), vec![1, 2, 4]);
+    }
+
+    #[test]
+    fn reads_icalendar_from_a_calendar_app() {
+        let entries = parse(CALENDAR_ICS, Format::ICalendar).unwrap();
+        assert_eq!(summary(&entries), vec![
+            ("Renew the car insurance, compare at least three quotes before deciding".to_string(), false,
+             Some("2026-11-01".to_string()), Some(Priority::High), tags(&["car", "money", "paperwork"])),
+            ("Return library books".to_string(), true, None, Some(Priority::Low), tags(&[])),
+        ]);
+        assert_eq!(entries.iter().map(|entry| entry.line).collect::<Vec<_>>(), vec![9, 23]);
+    }
+
+    #[test]
+    fn every_format_reads_back_what_it_wrote() {
+        let todos = todos();
+        let expected: Vec<_> = todos.iter().filter(|todo| !todo.deleted)
+            .map(|todo| (todo.title.clone(), todo.completed, todo.due.clone(), todo.priority, todo.tags.clone()))
+            .collect();
+        for &format in &[Format::Json, Format::Csv, Format::TodoTxt, Format::ICalendar] {
+            let entries = parse(&export(&list(), &todos, format), format).unwrap();
+            assert_eq!(summary(&entries), expected, "{}", format.name());
+        }
+
+        // Markdown only has room for the title and whether it's done
+        let entries = parse(&export(&list(), &todos, Format::Markdown), Format::Markdown).unwrap();
+        let titles: Vec<(&str, bool)> = entries.iter().map(|entry| (entry.title.as_str(), entry.completed)).collect();
+        assert_eq!(titles, vec![("Pay rent, \"now\"", true), ("Call mum", false), ("Plain", false)]);
+    }
+
+    #[test]
+    fn csv_keeps_quotes_commas_and_line_breaks_in_titles() {
+        let todos = vec![Todo::new(1, "First line\nsecond, \"quoted\" line".to_string())];
+        let csv = export(&list(), &todos, Format::Csv);
+        assert!(csv.contains("\"First line\nsecond, \"\"quoted\"\" line\""));
+        let entries = parse(&csv, Format::Csv).unwrap();
+        assert_eq!(entries[0].title, "First line\nsecond, \"quoted\" line");
+
+        // Line based formats put it on one line instead
+        let todo_txt = export(&list(), &todos, Format::TodoTxt);
+        assert_eq!(todo_txt, "First line second, \"quoted\" line\n");
+    }
+
+    #[test]
+    fn csv_records_start_on_the_line_their_first_field_is_on() {
+        let csv = "Title,Completed,Due,Color\r\n\"Line one\nline \"\"two\"\"\",yes,,red\r\n\r\n\"a, b\",no,2016-6-7\r\n";
+        let entries = parse(csv, Format::Csv).unwrap();
+        assert_eq!(entries.iter().map(|entry| entry.line).collect::<Vec<_>>(), vec![2, 5]);
+        assert_eq!(entries[0].title, "Line one\nline \"two\"");
+        assert!(entries[0].completed);
+        assert_eq!(entries[1].title, "a, b");
+        assert_eq!(entries[1].due, Some("2016-06-07".to_string()));
+
+        let bad_date = format!("{}\"unfinished\",no,someday\r\n", csv);
+        assert_eq!(parse(&bad_date, Format::Csv).unwrap_err().line, 6);
+        let unclosed = "title\r\nfine\r\n\"never\nclosed\r\n";
+        assert_eq!(parse(unclosed, Format::Csv).unwrap_err().line, 3);
+        assert_eq!(parse("name,done\r\nx,yes\r\n", Format::Csv).unwrap_err().line, 1);
+    }
+
+    #[test]
+    fn todo_txt_reads_priorities_dates_and_tags() {
+        let text = "x 2026-10-18 2026-10-01 Pay rent pri:A due:2026-11-01 +bills @Home\n\
+                    (B) Call mum +phone\n\
+                    \n\
+                    (a) lower case is no priority +\n";
+        let entries = parse(text, Format::TodoTxt).unwrap();
+        assert_eq!(summary(&entries), vec![
+            ("Pay rent".to_string(), true, Some("2026-11-01".to_string()), Some(Priority::High), tags(&["bills", "home"])),
+            ("Call mum".to_string(), false, None, Some(Priority::Medium), tags(&["phone"])),
+            ("(a) lower case is no priority +".to_string(), false, None, None, tags(&[])),
+        ]);
+        assert_eq!(entries.iter().map(|entry| entry.line).collect::<Vec<_>>(), vec![1, 2, 4]);
+
+        let error = parse("Buy milk\n(C) Bake due:tomorrow\n", Format::TodoTxt).unwrap_err();
+        assert_eq!(error.line, 2);
+    }
+
+    #[test]
+    fn json_errors_name_the_todo_they_are_in() {
+        let entries = parse(r#"{"todos":[{"title":"a"},{"title":"b","deleted":true}],"next_id":3}"#, Format::Json).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(parse(r#"[{"title":"a"},{"completed":true}]"#, Format::Json).unwrap_err().line, 2);
+        assert_eq!(parse(r#"[{"title":"a"},{"title":"b"},{"title":"c","due":"soon"}]"#, Format::Json).unwrap_err().line, 3);
+        assert_eq!(parse("[{", Format::Json).unwrap_err().line, 1);
+    }
+
+    #[test]
+    fn an_import_is_undone_in_one_go() {
+        let mut store = Store::create_store(reducer(), State::default());
+        store.dispatch( Todos( Add("Call mum".to_string()) ) );
+        let entries = parse(&export(&list(), &todos(), Format::Json), Format::Json).unwrap();
+        let import = plan(store.get_state(), entries);
+        import.apply(&mut store);
+        assert_eq!(store.get_state().todos.len(), 3);
+        assert_eq!(store.get_state().todos[0].priority, None);
+        assert_eq!(store.get_state().todos[1].tags, tags(&["bills", "home"]));
+
+        assert!(store.undo());
+        assert_eq!(store.get_state().todos.len(), 1);
+        assert!(store.redo());
+        assert_eq!(store.get_state().todos.len(), 3);
+    }
+}
@@ -0,0 +1,89 @@
+<!DOCTYPE html>
+<html>
+  <head>
+    <meta charset="utf-8">
+    <title>{{list.name}} - Nickel Todo - Trash</title>
+    <link rel="stylesheet" href="http://todomvc.com/examples/react/node_modules/todomvc-common/base.css">
+    <link rel="stylesheet" href="http://todomvc.com/examples/react/node_modules/todomvc-app-css/index.css">
+    <style type="text/css">
+    .todo-list li label {
+      padding-left: 15px;
+      color: #d9d9d9;
+      text-decoration: line-through;
+    }
+    .todo-list li .restore {
+      position: absolute;
+      top: 0;
+      right: 50px;
+      bottom: 0;
+      margin: auto 0;
+      height: 30px;
+      color: #4d4d4d;
+      cursor: pointer;
+    }
+    .todo-list li .restore:hover {
+      text-decoration: underline;
+    }
+    .todo-list li .destroy {
+      display: block;
+    }
+    .empty {
+      padding: 16px;
+      color: #777;
+    }
+    .footer .clear-completed {
+      display: block;
+    }
+    .info .back {
+      color: inherit;
+    }
+    </style>
+    <script>
+      // Same as on the todo page without the live updates, every button
+      // posts to /lists/1/action/id and we get sent back here
+      document.addEventListener('click', function clickHandler(e) {
+        if (e.target && e.target.dataset && e.target.dataset.action) {
+          e.preventDefault();
+          var form = document.createElement('form');
+          form.method = 'post';
+          form.action = '{{base}}/' + e.target.dataset.action + (e.target.dataset.id ? '/' + e.target.dataset.id : '');
+          document.body.appendChild(form);
+          form.submit();
+        }
+      });
+    </script>
+  </head>
+  <body>
+    <section class="todoapp">
+      <header class="header">
+        <h1>trash</h1>
+      </header>
+      {{#any_deleted}}
+      <section class="main">
+        <ul class="todo-list">
+          {{#each todos}}
+          {{#if deleted}}
+          <li data-id={{id}}>
+            <div class="view">
+              <label>{{title}}</label>
+              <button class="restore" data-id={{id}} data-action="restore">Restore</button>
+              <button class="destroy" data-id={{id}} data-action="purge" title="Delete forever"></button>
+            </div>
+          </li>
+          {{/if}}
+          {{/each}}
+        </ul>
+      </section>
+      <footer class="footer">
+        <button class="clear-completed" data-action="empty-trash">Empty trash</button>
+      </footer>
+      {{else}}
+      <p class="empty">The trash is empty.</p>
+      {{/any_deleted}}
+    </section>
+    <footer class="info">
+      <p><a class="back" href="{{base}}">Back to {{list.name}}</a></p>
+      <p>Signed in as {{username}}</p>
+    </footer>
+  </body>
+</html>
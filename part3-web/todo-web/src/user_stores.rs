@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use store::{ Store, SharedTodoStore, TodoStore, reducer };
+use store::Action::{ Todos };
+use todo::is_expired;
+use todo::TodoAction::{ ExpireTrash };
+use lists::{ ListId, ListIndex, TodoList, list_dir, list_name_rules, parse_list_name };
+use middleware;
+use storage::{ self, Backend };
+use error::{ AppError, lock };
+
+// Every user has their own lists, kept in `dir`/<username>, and every list
+// gets its own store saved with `backend`. Stores are opened the first time
+// they're needed and then kept around.
+//
+// The locks go from the map of users to one user's lists to one list's slot,
+// and each one is let go before the next is taken. Only the slot is held while
+// a store is opened, so a big list replaying its log holds up nobody but the
+// requests for that same list
+pub struct UserStores {
+    dir: PathBuf,
+    backend: Backend,
+    history_limit: usize,
+    // How long deleted todos stay in the trash, None keeps them forever
+    trash_retention: Option<Duration>,
+    users: Mutex<HashMap<String, Arc<Mutex<UserLists>>>>,
+}
+
+// Where a list's store goes once it's open. Whoever opens it holds the slot
+// meanwhile, so everyone else asking for the list waits for that store
+// instead of opening the same files a second time
+type StoreSlot = Arc<Mutex<Option<SharedTodoStore>>>;
+
+// One user's lists and a slot for each one that's been asked for so far
+struct UserLists {
+    dir: PathBuf,
+    index: ListIndex,
+    stores: HashMap<ListId, StoreSlot>,
+}
+
+impl UserLists {
+    // Makes a change to the lists and saves it, if saving fails nothing changed
+    fn update<F, T>(&mut self, change: F) -> Result<T, AppError>
+        where F: FnOnce(&mut ListIndex) -> Result<T, AppError> {
+        let mut index = self.index.clone();
+        let result = try!(change(&mut index));
+        try!(index.save(&self.dir));
+        self.index = index;
+        Ok(result)
+    }
+}
+
+fn list_not_found() -> AppError {
+    AppError::NotFound("That list doesn't exist".to_string())
+}
+
+impl UserStores {
+    pub fn new<P: AsRef<Path>>(dir: P, backend: Backend, history_limit: usize, trash_retention: Option<Duration>) -> UserStores {
+        UserStores {
+            dir: dir.as_ref().to_path_buf(),
+            backend: backend,
+            history_limit: history_limit,
+            trash_retention: trash_retention,
+            users: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // The user's lists, loading them first if we haven't yet. The index is
+    // read without holding the map, if someone else loaded it meanwhile
+    // theirs is kept. Only call this with a username that passed
+    // auth::valid_username, it's used as a directory name
+    fn user(&self, username: &str) -> Result<Arc<Mutex<UserLists>>, AppError> {
+        if let Some(user) = lock(&self.users).get(username) {
+            return Ok(user.clone());
+        }
+        let dir = self.dir.join(username);
+        let index = try!(ListIndex::open(&dir));
+        let user = Arc::new(Mutex::new(UserLists { dir: dir, index: index, stores: HashMap::new() }));
+        Ok(lock(&self.users).entry(username.to_string()).or_insert(user).clone())
+    }
+
+    // Runs `f` with the user's lists, `f` should be quick since every other
+    // request of the user waits for it
+    fn with_user<F, T>(&self, username: &str, f: F) -> Result<T, AppError>
+        where F: FnOnce(&mut UserLists) -> Result<T, AppError> {
+        let user = try!(self.user(username));
+        let mut user = lock(&*user);
+        f(&mut user)
+    }
+
+    // Every list the user has, archived or not
+    pub fn lists(&self, username: &str) -> Result<Vec<TodoList>, AppError> {
+        self.with_user(username, |user| Ok(user.index.lists.clone()))
+    }
+
+    // The list with `list_id` and its store, or the default list without one.
+    // Err(AppError::NotFound) if there's no such list
+    pub fn get(&self, username: &str, list_id: Option<ListId>) -> Result<(TodoList, SharedTodoStore), AppError> {
+        let (list, dir, slot) = try!(self.with_user(username, |user| {
+            let list = match list_id {
+                Some(list_id) => user.index.find(list_id),
+                None => user.index.default_list(),
+            };
+            let list = try!(list.cloned().ok_or_else(list_not_found));
+            let slot = user.stores.entry(list.id).or_insert_with(|| Arc::new(Mutex::new(None))).clone();
+            Ok((list.clone(), list_dir(&user.dir, list.id), slot))
+        }));
+
+        let mut slot = lock(&*slot);
+        if let Some(ref store) = *slot {
+            return Ok((list, store.clone()));
+        }
+        // The list could have been deleted while we waited for the slot, then
+        // opening it would only bring its directory back
+        if try!(self.with_user(username, |user| Ok(user.index.find(list.id).is_none()))) {
+            return Err(list_not_found());
+        }
+        let store = try!(self.open_store(&dir, username, &list));
+        *slot = Some(store.clone());
+        Ok((list, store))
+    }
+
+    fn open_store(&self, dir: &Path, username: &str, list: &TodoList) -> io::Result<SharedTodoStore> {
+        // Load whatever was saved for this list so a restart doesn't wipe it
+        let mut reducer = reducer();
+        let (storage, state, replay) = try!(storage::open(self.backend, dir, &mut *reducer));
+        let mut store = Store::with_storage(reducer, state, storage);
+        println!("Loaded {}'s list {} from {} storage, replayed {} actions from the log",
+                 username, list.name, self.backend.name(), replay.replayed);
+        if let Some(corruption) = replay.corruption {
+            println!("Warning: {}, discarded the last {} bytes of the log of {}'s list {}",
+                     corruption, replay.discarded_bytes, username, list.name);
+        }
+
+        // Log every action and keep empty or overly long todos out of the list
+        store.apply_middleware(vec![ middleware::logger, middleware::validate ]);
+        store.set_history_limit(self.history_limit);
+        if let Some(retention) = self.trash_retention {
+            expire_trash(&mut store, retention);
+        }
+        Ok(store.share())
+    }
+
+    pub fn create_list(&self, username: &str, name: &str) -> Result<TodoList, AppError> {
+        let name = try!(parse_list_name(name).ok_or_else(|| AppError::Unprocessable(list_name_rules())));
+        self.with_user(username, |user| user.update(|index| Ok(index.add(name))))
+    }
+
+    pub fn rename_list(&self, username: &str, list_id: ListId, name: &str) -> Result<TodoList, AppError> {
+        let name = try!(parse_list_name(name).ok_or_else(|| AppError::Unprocessable(list_name_rules())));
+        self.with_user(username, |user| user.update(|index| {
+            let list = try!(index.find_mut(list_id).ok_or_else(list_not_found));
+            list.name = name;
+            Ok(list.clone())
+        }))
+    }
+
+    pub fn archive_list(&self, username: &str, list_id: ListId, archived: bool) -> Result<TodoList, AppError> {
+        self.with_user(username, |user| user.update(|index| {
+            let list = try!(index.find_mut(list_id).ok_or_else(list_not_found));
+            list.archived = archived;
+            Ok(list.clone())
+        }))
+    }
+
+    // Deletes the list along with everything saved for it, for good. There's always at
+    // least one list left, so the last one can't be deleted
+    pub fn delete_list(&self, username: &str, list_id: ListId) -> Result<TodoList, AppError> {
+        let (list, dir, slot) = try!(self.with_user(username, |user| {
+            let list = try!(user.update(|index| {
+                if index.lists.len() == 1 {
+                    return Err(AppError::Unprocessable("Your last list can't be deleted".to_string()));
+                }
+                let position = try!(index.lists.iter().position(|list| list.id == list_id).ok_or_else(list_not_found));
+                Ok(index.lists.remove(position))
+            }));
+            Ok((list, list_dir(&user.dir, list_id), user.stores.remove(&list_id)))
+        }));
+
+        // Waits for the store if it's being opened right now, so its files
+        // aren't written to while they're removed. Anyone still streaming the
+        // list keeps the store until they go, it just isn't saved anywhere any more
+        let _opening = slot.as_ref().map(|slot| lock(&**slot));
+        try!(fs::remove_dir_all(dir).or_else(|e| {
+            if e.kind() == io::ErrorKind::NotFound { Ok(()) } else { Err(e) }
+        }));
+        Ok(list)
+    }
+}
+
+// How often the retention policy goes through the trash of every open store.
+// Todos are purged at most this long after their time is up
+const TRASH_SWEEP_INTERVAL_SECONDS: u64 = 60 * 60;
+
+fn seconds_since_epoch() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0)
+}
+
+// Only dispatches ExpireTrash if it would change something, so an idle list
+// doesn't fill its storage and undo history with sweeps
+fn expire_trash(store: &mut TodoStore, retention: Duration) {
+    let now = seconds_since_epoch();
+    let max_age = retention.as_secs();
+    let has_work = store.get_state().todos.iter()
+        .any(|todo| (todo.deleted && todo.deleted_at.is_none()) || is_expired(todo, now, max_age));
+    if has_work {
+        store.dispatch( Todos( ExpireTrash(now, max_age) ) );
+    }
+}
+
+// Starts a background thread that applies the trash retention policy to every
+// open store now and then, if there is a policy
+pub fn sweep_trash(stores: &Arc<UserStores>) {
+    let retention = match stores.trash_retention {
+        Some(retention) => retention,
+        None => return,
+    };
+    let stores = stores.clone();
+    thread::spawn(move || {
+        loop {
+            thread::sleep(Duration::from_secs(TRASH_SWEEP_INTERVAL_SECONDS));
+            // Collect them first so we don't hold on to any of the maps while dispatching
+            let users: Vec<Arc<Mutex<UserLists>>> = lock(&stores.users).values().cloned().collect();
+            let slots: Vec<StoreSlot> = users.iter()
+                .flat_map(|user| lock(&**user).stores.values().cloned().collect::<Vec<_>>())
+                .collect();
+            let open: Vec<SharedTodoStore> = slots.iter().filter_map(|slot| lock(&**slot).clone()).collect();
+            for store in open {
+                expire_trash(&mut store.lock(), retention);
+            }
+        }
+    });
+}